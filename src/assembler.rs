@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::memory::{MEM_REGION_RAM, MEM_SIZE};
+use crate::opcode::{OpCode, INSTRUCTION_SIZE};
+
+// Two pass assembler for the mnemonics in vm.rs (and the c8dis output)
+//
+//   ; comments run to the end of the line
+//   SPEED EQU 4           ; constants, may use labels and constants defined above them
+//   loop:                 ; labels, optionally followed by a statement on the same line
+//       ADD V0, SPEED + 1 ; numbers are decimal, 0x hex or 0b binary, with + and -
+//       JP loop
+//   sprite:
+//       DB 0xF0, 0x90     ; bytes
+//       DW 0x1234         ; big endian words
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+const MNEMONICS: [&str; 33] = [
+    "SYS", "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE",
+    "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND", "DRW", "SKP",
+    "SKNP", "PLANE", "AUDIO", "DB", "DW", "EQU", "LONG",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand {
+    Register(u8),
+    RegisterRange(u8, u8),
+    I,
+    IndirectI,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    HighFont,
+    Bcd,
+    Rpl,
+    Pitch,
+    Long(i64),
+    Value(i64),
+}
+
+struct Statement<'a> {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, Vec<AssembleError>> {
+    let mut errors = Vec::new();
+    let mut symbols: HashMap<String, i64> = HashMap::new();
+    let mut statements = Vec::new();
+
+    // Pass 1: addresses of labels, values of constants
+    let mut address = MEM_REGION_RAM as usize;
+    for (line_idx, line) in source.lines().enumerate() {
+        let line_num = line_idx + 1;
+        let mut error = |message: String| {
+            errors.push(AssembleError {
+                line: line_num,
+                message,
+            })
+        };
+
+        let mut text = line.split(';').next().unwrap_or_default().trim();
+        let first = text.split_whitespace().next().unwrap_or_default();
+        if let Some(label) = first.strip_suffix(':') {
+            if !is_identifier(label) {
+                error(format!("Invalid label '{}'", label));
+            } else if symbols.insert(label.to_string(), address as i64).is_some() {
+                error(format!("Duplicate symbol '{}'", label));
+            }
+            text = text[first.len()..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, rest) = split_first_word(text);
+        let (second, value) = split_first_word(rest);
+        if second.eq_ignore_ascii_case("EQU") {
+            if !is_identifier(mnemonic) {
+                error(format!("Invalid constant name '{}'", mnemonic));
+                continue;
+            }
+            match evaluate(value, &symbols) {
+                Ok(value) => {
+                    if symbols.insert(mnemonic.to_string(), value).is_some() {
+                        error(format!("Duplicate symbol '{}'", mnemonic));
+                    }
+                }
+                Err(message) => error(message),
+            }
+            continue;
+        }
+
+        let statement = Statement {
+            line: line_num,
+            mnemonic: mnemonic.to_ascii_uppercase(),
+            operands: if rest.is_empty() {
+                Vec::new()
+            } else {
+                rest.split(',').map(str::trim).collect()
+            },
+        };
+        address += statement_size(&statement);
+        if address > MEM_SIZE {
+            error("Program doesn't fit in memory".to_string());
+            break;
+        }
+        statements.push(statement);
+    }
+
+    // Pass 2: encoding, now that every label is known
+    let mut rom = Vec::new();
+    for statement in statements.iter() {
+        match encode_statement(statement, &symbols) {
+            Ok(bytes) => rom.extend(bytes),
+            Err(message) => errors.push(AssembleError {
+                line: statement.line,
+                message,
+            }),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(rom)
+    } else {
+        errors.sort_by_key(|error| error.line);
+        Err(errors)
+    }
+}
+
+// Known without evaluating anything, so labels can be placed in the first pass
+fn statement_size(statement: &Statement) -> usize {
+    match statement.mnemonic.as_str() {
+        "DB" => statement.operands.len(),
+        "DW" => statement.operands.len() * 2,
+        "LD" if statement.operands.get(1).copied().map(is_long) == Some(true) => {
+            OpCode::LDIL().size() as usize
+        }
+        _ => INSTRUCTION_SIZE as usize,
+    }
+}
+
+fn encode_statement(
+    statement: &Statement,
+    symbols: &HashMap<String, i64>,
+) -> Result<Vec<u8>, String> {
+    let mnemonic = statement.mnemonic.as_str();
+    if !MNEMONICS.contains(&mnemonic) || mnemonic == "EQU" || mnemonic == "LONG" {
+        return Err(format!("Unknown instruction '{}'", mnemonic));
+    }
+
+    match mnemonic {
+        "DB" => statement
+            .operands
+            .iter()
+            .map(|operand| evaluate(operand, symbols).and_then(to_byte))
+            .collect(),
+        "DW" => statement
+            .operands
+            .iter()
+            .map(|operand| evaluate(operand, symbols).and_then(to_word))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|words| words.iter().flat_map(|word| word.to_be_bytes()).collect()),
+        _ => {
+            let operands = statement
+                .operands
+                .iter()
+                .map(|operand| parse_operand(operand, symbols))
+                .collect::<Result<Vec<_>, _>>()?;
+            encode_instruction(mnemonic, &operands)
+        }
+    }
+}
+
+fn encode_instruction(mnemonic: &str, operands: &[Operand]) -> Result<Vec<u8>, String> {
+    use Operand::*;
+
+    let opcode = match (mnemonic, operands) {
+        ("SYS", [Value(0)]) => OpCode::NOP(),
+        ("SYS", [Value(_)]) => return Err("Only SYS 0x000 is supported".to_string()),
+        ("CLS", []) => OpCode::CLS(),
+        ("RET", []) => OpCode::RET(),
+        ("SCD", [Value(n)]) => OpCode::SCDN(to_nibble(*n)?),
+        ("SCU", [Value(n)]) => OpCode::SCUN(to_nibble(*n)?),
+        ("SCR", []) => OpCode::SCR(),
+        ("SCL", []) => OpCode::SCL(),
+        ("EXIT", []) => OpCode::EXIT(),
+        ("LOW", []) => OpCode::LOW(),
+        ("HIGH", []) => OpCode::HIGH(),
+        ("JP", [Value(addr)]) => OpCode::JP(to_addr(*addr)?),
+        ("JP", [Register(0), Value(addr)]) => OpCode::JPVA(to_addr(*addr)?),
+        ("CALL", [Value(addr)]) => OpCode::CALL(to_addr(*addr)?),
+        ("SE", [Register(x), Value(byte)]) => OpCode::SEVB(*x, to_byte(*byte)?),
+        ("SE", [Register(x), Register(y)]) => OpCode::SEVV(*x, *y),
+        ("SNE", [Register(x), Value(byte)]) => OpCode::SNEVB(*x, to_byte(*byte)?),
+        ("SNE", [Register(x), Register(y)]) => OpCode::SNEVV(*x, *y),
+        ("LD", [Register(x), Value(byte)]) => OpCode::LDVB(*x, to_byte(*byte)?),
+        ("LD", [Register(x), Register(y)]) => OpCode::LDVV(*x, *y),
+        ("LD", [I, Value(addr)]) => OpCode::LDIA(to_addr(*addr)?),
+        ("LD", [I, Long(addr)]) => {
+            let mut bytes = OpCode::LDIL().to_bytes().to_be_bytes().to_vec();
+            bytes.extend_from_slice(&to_word(*addr)?.to_be_bytes());
+            return Ok(bytes);
+        }
+        ("LD", [Register(x), DelayTimer]) => OpCode::LDVDT(*x),
+        ("LD", [Register(x), Key]) => OpCode::LDVK(*x),
+        ("LD", [DelayTimer, Register(x)]) => OpCode::LDDTV(*x),
+        ("LD", [SoundTimer, Register(x)]) => OpCode::LDSTV(*x),
+        ("LD", [Font, Register(x)]) => OpCode::LDFV(*x),
+        ("LD", [HighFont, Register(x)]) => OpCode::LDHFV(*x),
+        ("LD", [Bcd, Register(x)]) => OpCode::LDBV(*x),
+        ("LD", [Pitch, Register(x)]) => OpCode::LDPV(*x),
+        ("LD", [IndirectI, Register(x)]) => OpCode::LDIV(*x),
+        ("LD", [Register(x), IndirectI]) => OpCode::LDVI(*x),
+        ("LD", [Rpl, Register(x)]) => OpCode::LDRV(*x),
+        ("LD", [Register(x), Rpl]) => OpCode::LDVR(*x),
+        ("LD", [IndirectI, RegisterRange(x, y)]) => OpCode::LDIVV(*x, *y),
+        ("LD", [RegisterRange(x, y), IndirectI]) => OpCode::LDVVI(*x, *y),
+        ("ADD", [Register(x), Value(byte)]) => OpCode::ADDVB(*x, to_byte(*byte)?),
+        ("ADD", [Register(x), Register(y)]) => OpCode::ADDVV(*x, *y),
+        ("ADD", [I, Register(x)]) => OpCode::ADDIV(*x),
+        ("OR", [Register(x), Register(y)]) => OpCode::ORVV(*x, *y),
+        ("AND", [Register(x), Register(y)]) => OpCode::ANDVV(*x, *y),
+        ("XOR", [Register(x), Register(y)]) => OpCode::XORVV(*x, *y),
+        ("SUB", [Register(x), Register(y)]) => OpCode::SUBVV(*x, *y),
+        ("SUBN", [Register(x), Register(y)]) => OpCode::SUBNVV(*x, *y),
+        // SHR Vx {, Vy}, shifting Vx in place when Vy is left out
+        ("SHR", [Register(x)]) => OpCode::SHRVV(*x, *x),
+        ("SHR", [Register(x), Register(y)]) => OpCode::SHRVV(*x, *y),
+        ("SHL", [Register(x)]) => OpCode::SHLVV(*x, *x),
+        ("SHL", [Register(x), Register(y)]) => OpCode::SHLVV(*x, *y),
+        ("RND", [Register(x), Value(byte)]) => OpCode::RNDVB(*x, to_byte(*byte)?),
+        ("DRW", [Register(x), Register(y), Value(n)]) => OpCode::DRWVVN(*x, *y, to_nibble(*n)?),
+        ("SKP", [Register(x)]) => OpCode::SKPV(*x),
+        ("SKNP", [Register(x)]) => OpCode::SKNPV(*x),
+        ("PLANE", [Value(n)]) => OpCode::PLANEN(to_nibble(*n)?),
+        ("AUDIO", []) => OpCode::AUDIO(),
+        _ => return Err(format!("Invalid operands for {}", mnemonic)),
+    };
+
+    Ok(opcode.to_bytes().to_be_bytes().to_vec())
+}
+
+fn parse_operand(operand: &str, symbols: &HashMap<String, i64>) -> Result<Operand, String> {
+    let upper = operand.to_ascii_uppercase();
+    let operand = match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::DelayTimer,
+        "ST" => Operand::SoundTimer,
+        "K" => Operand::Key,
+        "F" => Operand::Font,
+        "HF" => Operand::HighFont,
+        "B" => Operand::Bcd,
+        "R" => Operand::Rpl,
+        "PITCH" => Operand::Pitch,
+        _ => {
+            if let Some(register) = parse_register(&upper) {
+                Operand::Register(register)
+            } else if let Some((from, to)) = upper.split_once('-').and_then(|(from, to)| {
+                Some((parse_register(from.trim())?, parse_register(to.trim())?))
+            }) {
+                Operand::RegisterRange(from, to)
+            } else if is_long(operand) {
+                Operand::Long(evaluate(split_first_word(operand).1, symbols)?)
+            } else {
+                Operand::Value(evaluate(operand, symbols)?)
+            }
+        }
+    };
+    Ok(operand)
+}
+
+fn parse_register(operand: &str) -> Option<u8> {
+    let index = operand.strip_prefix('V')?;
+    if index.len() == 1 {
+        u8::from_str_radix(index, 16).ok()
+    } else {
+        None
+    }
+}
+
+// Terms joined by + and -, a term is a number or a symbol
+fn evaluate(expression: &str, symbols: &HashMap<String, i64>) -> Result<i64, String> {
+    let expression = expression.trim();
+    if expression.is_empty() {
+        return Err("Missing value".to_string());
+    }
+
+    let mut result = 0i64;
+    let mut sign = 1;
+    let mut term = String::new();
+    let mut apply_term = |term: &mut String, sign: i64| -> Result<(), String> {
+        let value = parse_term(term.trim(), symbols)?;
+        result = result.wrapping_add(sign * value);
+        term.clear();
+        Ok(())
+    };
+    for (idx, character) in expression.char_indices() {
+        match character {
+            '+' | '-' if idx == 0 => sign = if character == '-' { -1 } else { 1 },
+            '+' | '-' => {
+                apply_term(&mut term, sign)?;
+                sign = if character == '-' { -1 } else { 1 };
+            }
+            _ => term.push(character),
+        }
+    }
+    apply_term(&mut term, sign)?;
+    Ok(result)
+}
+
+fn parse_term(term: &str, symbols: &HashMap<String, i64>) -> Result<i64, String> {
+    let lower = term.to_ascii_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else if term.starts_with(|c: char| c.is_ascii_digit()) {
+        term.parse().ok()
+    } else if term.is_empty() {
+        return Err("Missing value".to_string());
+    } else {
+        return symbols
+            .get(term)
+            .copied()
+            .ok_or(format!("Unknown symbol '{}'", term));
+    };
+    parsed.ok_or(format!("Invalid number '{}'", term))
+}
+
+fn split_first_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(idx) => (&text[..idx], text[idx..].trim()),
+        None => (text, ""),
+    }
+}
+
+fn is_long(operand: &str) -> bool {
+    split_first_word(operand).0.eq_ignore_ascii_case("LONG")
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut characters = name.chars();
+    matches!(characters.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && characters.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        && !MNEMONICS.contains(&name.to_ascii_uppercase().as_str())
+}
+
+fn to_nibble(value: i64) -> Result<u8, String> {
+    check_range(value, 0, 0xF, "nibble").map(|value| value as u8)
+}
+
+// Negative bytes are stored as two's complement
+fn to_byte(value: i64) -> Result<u8, String> {
+    check_range(value, -0x80, 0xFF, "byte").map(|value| value as u8)
+}
+
+fn to_word(value: i64) -> Result<u16, String> {
+    check_range(value, -0x8000, 0xFFFF, "word").map(|value| value as u16)
+}
+
+fn to_addr(value: i64) -> Result<u16, String> {
+    check_range(value, 0, 0xFFF, "address").map(|value| value as u16)
+}
+
+fn check_range(value: i64, min: i64, max: i64, what: &str) -> Result<i64, String> {
+    if (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("{} doesn't fit in a {}", value, what))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, AssembleError};
+    use crate::{disassembler::Disassembly, opcode::OpCode};
+    use proptest::prelude::*;
+
+    #[test]
+    fn program_test() {
+        let source = "
+            ; Draws a sprite forever
+            X_POS EQU 0x10
+            Y_POS EQU X_POS + 2
+
+            start:  CLS
+                    LD V0, X_POS
+                    ld v1, Y_POS - 1
+                    LD I, sprite
+            loop:
+                    DRW V0, V1, 2
+                    SHR VA
+                    LD [I], V0-V3
+                    LD I, LONG far + 1
+                    JP loop
+            sprite: DB 0b11110000, -1
+                    DW 0x1234
+            far:
+        ";
+        let rom = assemble(source).expect("Valid program");
+        assert_eq!(
+            rom,
+            vec![
+                0x00, 0xE0, 0x60, 0x10, 0x61, 0x11, 0xA2, 0x14, 0xD0, 0x12, 0x8A, 0xA6, 0x50, 0x32,
+                0xF0, 0x00, 0x02, 0x19, 0x12, 0x08, 0xF0, 0xFF, 0x12, 0x34,
+            ]
+        );
+    }
+
+    #[test]
+    fn error_test() {
+        let source = "
+            LD V0, 0x100
+            JP nowhere
+        dup:
+        dup:
+            FOO V1
+            DRW V0, I, 1
+        ";
+        let errors = assemble(source).expect_err("Invalid program");
+        let error = |line: usize, message: &str| AssembleError {
+            line,
+            message: message.to_string(),
+        };
+        assert_eq!(
+            errors,
+            vec![
+                error(2, "256 doesn't fit in a byte"),
+                error(3, "Unknown symbol 'nowhere'"),
+                error(5, "Duplicate symbol 'dup'"),
+                error(6, "Unknown instruction 'FOO'"),
+                error(7, "Invalid operands for DRW"),
+            ]
+        );
+    }
+
+    #[test]
+    fn disassembly_round_trip_test() {
+        let rom = [
+            0x22, 0x06, 0x12, 0x02, 0xFF, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x30, 0x01, 0x00, 0xEE,
+            0xAB,
+        ];
+        let listing = Disassembly::new(&rom).to_string();
+        assert_eq!(assemble(&listing), Ok(rom.to_vec()));
+    }
+
+    proptest! {
+        #[test]
+        fn mnemonic_round_trip_proptest(word in any::<u16>()) {
+            // The long form needs its operand, which the opcode doesn't carry
+            if let Some(opcode) = OpCode::from_bytes(word).filter(|op| *op != OpCode::LDIL()) {
+                prop_assert_eq!(assemble(&opcode.to_string()), Ok(word.to_be_bytes().to_vec()));
+            }
+        }
+    }
+}
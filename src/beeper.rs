@@ -1,26 +1,91 @@
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering},
+    Arc,
+};
 
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     Stream, StreamConfig,
 };
 
+use crate::vm::AUDIO_PATTERN_SIZE;
+
+type AudioPattern = [u8; AUDIO_PATTERN_SIZE];
+
+const PATTERN_BITS: f32 = (AUDIO_PATTERN_SIZE * 8) as f32;
+
+// State shared with the audio thread, all of it lock-free
+struct SharedState {
+    active: AtomicBool,
+    pitch: AtomicU8,
+    has_pattern: AtomicBool,
+    // The 128-bit pattern is split in two halves guarded by a sequence counter,
+    // the audio thread retries reading while the counter is odd or changed (a seqlock)
+    pattern_seq: AtomicU32,
+    pattern_high: AtomicU64,
+    pattern_low: AtomicU64,
+}
+
+impl SharedState {
+    fn new() -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            pitch: AtomicU8::new(0),
+            has_pattern: AtomicBool::new(false),
+            pattern_seq: AtomicU32::new(0),
+            pattern_high: AtomicU64::new(0),
+            pattern_low: AtomicU64::new(0),
+        }
+    }
+
+    fn store_pattern(&self, pattern: &AudioPattern) {
+        let mut high = [0; 8];
+        let mut low = [0; 8];
+        high.copy_from_slice(&pattern[..8]);
+        low.copy_from_slice(&pattern[8..]);
+
+        self.pattern_seq.fetch_add(1, Ordering::AcqRel);
+        self.pattern_high
+            .store(u64::from_be_bytes(high), Ordering::Release);
+        self.pattern_low
+            .store(u64::from_be_bytes(low), Ordering::Release);
+        self.pattern_seq.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn load_pattern(&self) -> AudioPattern {
+        loop {
+            let seq = self.pattern_seq.load(Ordering::Acquire);
+            let high = self.pattern_high.load(Ordering::Acquire);
+            let low = self.pattern_low.load(Ordering::Acquire);
+            if seq & 1 == 0 && seq == self.pattern_seq.load(Ordering::Acquire) {
+                let mut pattern = [0; AUDIO_PATTERN_SIZE];
+                pattern[..8].copy_from_slice(&high.to_be_bytes());
+                pattern[8..].copy_from_slice(&low.to_be_bytes());
+                return pattern;
+            }
+        }
+    }
+}
+
 pub struct Beeper {
     stream: Option<Stream>,
-    shared_state_ptr: Arc<AtomicBool>,
+    shared_state_ptr: Arc<SharedState>,
     previous_state: bool,
+    previous_pattern: Option<AudioPattern>,
+    previous_pitch: u8,
 }
 
 // @TODO handle errors
 impl Beeper {
     pub fn new() -> Self {
-        let initial_state = false;
-        let shared_state_ptr = Arc::new(AtomicBool::new(initial_state));
+        let shared_state_ptr = Arc::new(SharedState::new());
 
         Self {
             stream: None,
             shared_state_ptr,
-            previous_state: initial_state,
+            previous_state: false,
+            previous_pattern: None,
+            previous_pitch: 0,
         }
     }
 
@@ -48,13 +113,37 @@ impl Beeper {
         if self.previous_state != new_state {
             self.previous_state = new_state;
             self.shared_state_ptr
+                .active
                 .store(self.previous_state, Ordering::Relaxed);
         }
     }
+
+    pub fn set_audio_pattern(&mut self, pattern: Option<&AudioPattern>, pitch: u8) {
+        if self.previous_pitch != pitch {
+            self.previous_pitch = pitch;
+            self.shared_state_ptr.pitch.store(pitch, Ordering::Relaxed);
+        }
+
+        if self.previous_pattern.as_ref() != pattern {
+            self.previous_pattern = pattern.copied();
+            if let Some(pattern) = pattern {
+                self.shared_state_ptr.store_pattern(pattern);
+            }
+            self.shared_state_ptr
+                .has_pattern
+                .store(pattern.is_some(), Ordering::Release);
+        }
+    }
+}
+
+impl Default for Beeper {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 fn make_stream<T>(
-    shared_state_ptr: Arc<AtomicBool>,
+    shared_state_ptr: Arc<SharedState>,
     device: &cpal::Device,
     config: &StreamConfig,
 ) -> cpal::Stream
@@ -64,22 +153,33 @@ where
     let sample_rate = config.sample_rate.0 as f32;
     let channels = config.channels as usize;
 
-    // Produce a sine of maximum amplitude.
+    // Produce a sine of maximum amplitude, used until a rom loads an audio pattern.
     let mut sample_clock = 0f32;
     let mut sinewave_value_fn = move || {
         sample_clock = (sample_clock + 1.0) % sample_rate;
         (sample_clock * 440.0 * 2.0 * std::f32::consts::PI / sample_rate).sin()
     };
     let mut silence_value_fn = || 0.0;
+    let mut pattern_position = 0f32;
 
     device
         .build_output_stream(
             config,
             move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                if shared_state_ptr.load(Ordering::Relaxed) {
-                    write_data(data, channels, &mut sinewave_value_fn)
-                } else {
+                if !shared_state_ptr.active.load(Ordering::Relaxed) {
                     write_data(data, channels, &mut silence_value_fn)
+                } else if shared_state_ptr.has_pattern.load(Ordering::Acquire) {
+                    let pattern = shared_state_ptr.load_pattern();
+                    let step =
+                        pattern_playback_rate(shared_state_ptr.pitch.load(Ordering::Relaxed))
+                            / sample_rate;
+                    let mut pattern_value_fn = || {
+                        pattern_position = (pattern_position + step) % PATTERN_BITS;
+                        pattern_sample(&pattern, pattern_position)
+                    };
+                    write_data(data, channels, &mut pattern_value_fn)
+                } else {
+                    write_data(data, channels, &mut sinewave_value_fn)
                 }
             },
             |err| eprintln!("an error occurred on stream: {}", err),
@@ -87,6 +187,22 @@ where
         .unwrap()
 }
 
+#[inline]
+fn pattern_playback_rate(pitch: u8) -> f32 {
+    // Bits per second, as per the XO-CHIP spec
+    4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
+}
+
+#[inline]
+fn pattern_sample(pattern: &AudioPattern, position: f32) -> f32 {
+    let bit = position as usize % (AUDIO_PATTERN_SIZE * 8);
+    if pattern[bit / 8] & (0x80 >> (bit % 8)) != 0 {
+        1.0
+    } else {
+        -1.0
+    }
+}
+
 fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> f32)
 where
     T: cpal::Sample,
@@ -98,3 +214,34 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{pattern_playback_rate, pattern_sample, SharedState};
+
+    #[test]
+    fn pattern_handoff_test() {
+        let state = SharedState::new();
+        let mut pattern = [0; 16];
+        pattern[0] = 0xAB;
+        pattern[15] = 0xCD;
+
+        state.store_pattern(&pattern);
+        assert_eq!(state.load_pattern(), pattern);
+    }
+
+    #[test]
+    fn pattern_playback_test() {
+        assert!((pattern_playback_rate(64) - 4000.0).abs() < f32::EPSILON);
+        assert!((pattern_playback_rate(112) - 8000.0).abs() < 0.01);
+
+        let mut pattern = [0; 16];
+        pattern[0] = 0x80;
+        pattern[15] = 0x01;
+        assert_eq!(pattern_sample(&pattern, 0.5), 1.0);
+        assert_eq!(pattern_sample(&pattern, 1.0), -1.0);
+        assert_eq!(pattern_sample(&pattern, 127.9), 1.0);
+        // Wraps around
+        assert_eq!(pattern_sample(&pattern, 128.0), 1.0);
+    }
+}
@@ -0,0 +1,29 @@
+use std::process;
+
+use c8rust::assembler;
+
+const USAGE: &str = "Usage: c8asm <source> <output>";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let source = std::fs::read_to_string(&args[0]).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", args[0], err);
+        process::exit(2);
+    });
+    let rom = assembler::assemble(&source).unwrap_or_else(|errors| {
+        for error in errors {
+            eprintln!("{}:{}: {}", args[0], error.line, error.message);
+        }
+        process::exit(1);
+    });
+
+    if let Err(err) = std::fs::write(&args[1], rom) {
+        eprintln!("Failed to write {}: {}", args[1], err);
+        process::exit(2);
+    }
+}
@@ -0,0 +1,126 @@
+use std::process;
+use std::time::{Duration, Instant};
+
+use c8rust::assembler;
+use c8rust::cli::parse_number;
+use c8rust::keypad::KeypadState;
+use c8rust::quirks::Quirks;
+use c8rust::vm::{Engine, VM};
+
+const USAGE: &str = "Usage: c8bench [rom] [options]
+
+Runs a rom (a built-in workload by default) as fast as possible, interpreted
+with the instruction cache and without it, then with the block compiler, and
+prints the instructions per second.
+
+Options:
+  --instructions <n>    Instructions per run (default 20000000)
+  --quirks <profile>    vip, chip48, schip or xochip, none by default";
+
+const DEFAULT_INSTRUCTIONS: u64 = 20_000_000;
+
+// Arithmetic, a subroutine, sprite drawing and stores into memory, in a loop that never ends
+const WORKLOAD: &str = "
+            LD V5, 0
+    loop:   ADD V0, 1
+            ADD V1, V0
+            XOR V2, V1
+            SHR V3, V2
+            SE V0, 0
+            JP loop
+            CALL draw
+            JP loop
+    draw:   LD I, sprite
+            DRW V1, V2, 4
+            LD I, scratch
+            LD B, V1
+            LD [I], V0-V3
+            ADD V5, 1
+            RET
+    sprite: DB 0x90, 0x60, 0x60, 0x90
+    scratch:
+            DB 0, 0, 0, 0
+";
+
+struct Options {
+    rom_path: Option<String>,
+    instructions: u64,
+    quirks: Quirks,
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let rom = match &options.rom_path {
+        Some(path) => std::fs::read(path).unwrap_or_else(|err| {
+            eprintln!("Failed to read {}: {}", path, err);
+            process::exit(2);
+        }),
+        None => assembler::assemble(WORKLOAD).expect("Valid workload"),
+    };
+
+    let uncached = run(&rom, &options, Engine::Interpreter, false);
+    let cached = run(&rom, &options, Engine::Interpreter, true);
+    let compiled = run(&rom, &options, Engine::Blocks, true);
+    let rate = |elapsed: Duration| options.instructions as f64 / elapsed.as_secs_f64() / 1e6;
+    println!(
+        "Instruction cache off: {:>8.2} M instructions/s",
+        rate(uncached)
+    );
+    println!(
+        "Instruction cache on:  {:>8.2} M instructions/s ({:.2}x)",
+        rate(cached),
+        uncached.as_secs_f64() / cached.as_secs_f64()
+    );
+    println!(
+        "Block compiler:        {:>8.2} M instructions/s ({:.2}x)",
+        rate(compiled),
+        uncached.as_secs_f64() / compiled.as_secs_f64()
+    );
+}
+
+// Seeded the same every run, so all of them take the same path through the rom
+fn run(rom: &[u8], options: &Options, engine: Engine, is_cached: bool) -> Duration {
+    let mut vm = VM::with_seed(rom, options.quirks, 0);
+    vm.set_instruction_cache(is_cached);
+    let keypad = KeypadState::new();
+
+    let start = Instant::now();
+    vm.run(engine, &keypad, options.instructions);
+    let elapsed = start.elapsed();
+    if let Some(fault) = vm.get_fault() {
+        eprintln!("VM halted: {}", fault);
+        process::exit(1);
+    }
+    elapsed
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        rom_path: None,
+        instructions: DEFAULT_INSTRUCTIONS,
+        quirks: Quirks::default(),
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--instructions" => options.instructions = parse_number(&value()?)?,
+            "--quirks" => options.quirks = Quirks::from_name(&value()?)?,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+            _ if options.rom_path.is_none() => options.rom_path = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+
+    if options.instructions == 0 {
+        return Err("--instructions has to be at least 1".to_string());
+    }
+    Ok(options)
+}
@@ -0,0 +1,139 @@
+use std::convert::TryFrom;
+use std::process;
+use std::sync::mpsc::TryRecvError;
+
+use c8rust::cli::{parse_number, parse_tickrate};
+use c8rust::debugger::{Command, Console, Debugger, Response};
+use c8rust::gdbstub::{self, GdbServer};
+use c8rust::keypad::KeypadState;
+use c8rust::octo;
+use c8rust::quirks::Quirks;
+use c8rust::rng;
+use c8rust::timing::{Timing, FRAME_RATE};
+use c8rust::vm::VM;
+
+const USAGE: &str = "Usage: c8debug <rom> [options]
+
+Debugs a rom without a window, commands are read from stdin ('help' lists them).
+
+Options:
+  --tickrate <n>        Instructions per second (default 250)
+  --quirks <profile>    vip, chip48, schip or xochip, none by default
+  --seed <n>            Fixed RNG seed
+  --gdb <port>          Also accept a GDB connection on localhost";
+
+const DEFAULT_TICK_RATE: u64 = 250;
+
+struct Options {
+    rom_path: String,
+    tickrate: u64,
+    quirks: Quirks,
+    seed: Option<u64>,
+    gdb_port: Option<u16>,
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let rom = read_rom_or_exit(&options.rom_path);
+    let seed = options.seed.unwrap_or_else(rng::random_seed);
+    let mut vm = VM::with_seed(&rom, options.quirks, seed);
+    // There's no keyboard without a window, set registers instead
+    let keypad = KeypadState::new();
+    let mut debugger = Debugger::new();
+    let mut timing = Timing::new(options.tickrate, FRAME_RATE);
+    let console = Console::new();
+    console.reply(&debugger.execute(&mut vm, &keypad, Command::Registers));
+
+    let mut gdb = options.gdb_port.map(|port| {
+        GdbServer::bind((gdbstub::LOCALHOST, port)).unwrap_or_else(|err| {
+            eprintln!("Failed to listen for GDB on port {}: {}", port, err);
+            process::exit(2);
+        })
+    });
+
+    loop {
+        // Input is handled between frames, so the console and GDB both get their turn
+        loop {
+            match console.try_read() {
+                Ok(line) => match debugger.handle_line(&mut vm, &keypad, &line) {
+                    Response::Output(output) => console.reply(&output),
+                    Response::Quit => return,
+                },
+                Err(TryRecvError::Empty) => break,
+                // Without a terminal GDB is left to drive the VM
+                Err(TryRecvError::Disconnected) if gdb.is_some() => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        if let Some(gdb) = &mut gdb {
+            gdb.poll(&mut debugger, &mut vm, &keypad);
+        }
+
+        // Real time, so the timers count down the same as in the window
+        if timing.should_draw() {
+            timing.mark_draw();
+            let ticks = timing.take_frame_ticks();
+            if !debugger.is_paused() {
+                if let Some(report) = debugger.run(&mut vm, &keypad, ticks) {
+                    console.reply(&report);
+                }
+                vm.tick_timers();
+            }
+        }
+        timing.try_sleep();
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        rom_path: String::new(),
+        tickrate: DEFAULT_TICK_RATE,
+        quirks: Quirks::default(),
+        seed: None,
+        gdb_port: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--tickrate" => options.tickrate = parse_tickrate(&value()?)?,
+            "--quirks" => options.quirks = Quirks::from_name(&value()?)?,
+            "--seed" => options.seed = Some(parse_number(&value()?)?),
+            "--gdb" => {
+                let port = parse_number(&value()?)?;
+                let port = u16::try_from(port).map_err(|_| format!("Invalid port {}", port))?;
+                options.gdb_port = Some(port);
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+            _ if options.rom_path.is_empty() => options.rom_path = arg,
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+
+    if options.rom_path.is_empty() {
+        return Err("Missing rom".to_string());
+    }
+    Ok(options)
+}
+
+// Octo sources are compiled first, the same as when dropped on the window
+fn read_rom_or_exit(path: &str) -> Vec<u8> {
+    let rom = if path.ends_with(".8o") {
+        std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|source| octo::compile(&source).map_err(|err| err.to_string()))
+    } else {
+        std::fs::read(path).map_err(|err| err.to_string())
+    };
+    rom.unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", path, err);
+        process::exit(2);
+    })
+}
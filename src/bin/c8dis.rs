@@ -0,0 +1,29 @@
+use std::process;
+
+use c8rust::disassembler::Disassembly;
+
+const USAGE: &str = "Usage: c8dis <rom> [output]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() || args.len() > 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let rom = std::fs::read(&args[0]).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", args[0], err);
+        process::exit(2);
+    });
+    let listing = format!("; {}\n{}", args[0], Disassembly::new(&rom));
+
+    match args.get(1) {
+        Some(path) => {
+            if let Err(err) = std::fs::write(path, listing) {
+                eprintln!("Failed to write {}: {}", path, err);
+                process::exit(2);
+            }
+        }
+        None => print!("{}", listing),
+    }
+}
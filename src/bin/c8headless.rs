@@ -0,0 +1,193 @@
+use std::process;
+
+use c8rust::cli::parse_number;
+use c8rust::headless::{self, KeyPress, Limit};
+use c8rust::profiler::Profiler;
+use c8rust::quirks::Quirks;
+use c8rust::rng;
+use c8rust::timing::Pacing;
+use c8rust::trace::{self, TraceFilter, Tracer};
+use c8rust::vm::{Engine, VM};
+
+const USAGE: &str = "Usage: c8headless <rom> [options]
+
+Options:
+  --frames <n>          Run for n frames (default 600, 10 seconds)
+  --instructions <n>    Run for n instructions instead
+  --tickrate <n>        Instructions per second (default 250), or vip to run by
+                        COSMAC VIP instruction timing
+  --quirks <profile>    vip, chip48, schip or xochip, none by default
+  --seed <n>            Fixed RNG seed
+  --engine <name>       interpreter (default) or blocks, which compiles straight runs
+                        of instructions, tracing and profiling always interpret
+  --press <f:k[:n]>     Hold hex key k from frame f for n frames (default 1), repeatable
+  --script <file>       Key presses, one 'FRAME KEY [FRAMES]' per line
+  --pbm <file>          Write the final screen as PBM
+  --png <file>          Write the final screen as PNG
+  --json <file>         Write registers as JSON to a file instead of stdout
+  --trace <file>        Write every instruction to a file, - for stdout
+  --trace-range <s-e>   Only trace instructions from address s to e
+  --trace-limit <n>     Stop tracing after n lines
+  --profile <file>      Write hotspots, opcode counts, calls and coverage as text
+  --profile-json <file> Write the same as JSON
+  --profile-listing <file>
+                        Write the rom as hex, with coverage and counts per address";
+
+const DEFAULT_FRAMES: u64 = 600;
+const DEFAULT_TICK_RATE: u64 = 250;
+
+struct Options {
+    rom_path: String,
+    limit: Limit,
+    pacing: Pacing,
+    quirks: Quirks,
+    seed: Option<u64>,
+    engine: Engine,
+    presses: Vec<KeyPress>,
+    pbm_path: Option<String>,
+    png_path: Option<String>,
+    json_path: Option<String>,
+    trace_path: Option<String>,
+    trace_filter: TraceFilter,
+    profile_path: Option<String>,
+    profile_json_path: Option<String>,
+    profile_listing_path: Option<String>,
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let rom = read_or_exit(&options.rom_path);
+    let seed = options.seed.unwrap_or_else(rng::random_seed);
+    let mut vm = VM::with_seed(&rom, options.quirks, seed);
+    if let Some(path) = &options.trace_path {
+        match Tracer::create(path, options.trace_filter) {
+            Ok(tracer) => vm.set_tracer(Some(tracer)),
+            Err(err) => {
+                eprintln!("Failed to create {}: {}", path, err);
+                process::exit(2);
+            }
+        }
+    }
+    let is_profiling = options.profile_path.is_some()
+        || options.profile_json_path.is_some()
+        || options.profile_listing_path.is_some();
+    if is_profiling {
+        vm.set_profiler(Some(Profiler::new()));
+    }
+    let summary = headless::run(
+        &mut vm,
+        options.engine,
+        options.pacing,
+        options.limit,
+        &options.presses,
+    );
+    // Exiting below skips destructors, which would flush it
+    if let Some(mut tracer) = vm.take_tracer() {
+        tracer.flush();
+    }
+
+    if let Some(profiler) = vm.take_profiler() {
+        if let Some(path) = &options.profile_path {
+            write_or_exit(path, profiler.text_report(&rom).as_bytes());
+        }
+        if let Some(path) = &options.profile_json_path {
+            write_or_exit(path, profiler.json_report(&rom).as_bytes());
+        }
+        if let Some(path) = &options.profile_listing_path {
+            write_or_exit(path, profiler.annotated_listing(&rom).as_bytes());
+        }
+    }
+    if let Some(path) = &options.pbm_path {
+        write_or_exit(path, &headless::display_to_pbm(vm.get_display()));
+    }
+    if let Some(path) = &options.png_path {
+        write_or_exit(path, &headless::display_to_png(vm.get_display()));
+    }
+    let json = headless::vm_to_json(&vm, &summary);
+    match &options.json_path {
+        Some(path) => write_or_exit(path, json.as_bytes()),
+        None => print!("{}", json),
+    }
+
+    if let Some(fault) = summary.fault {
+        eprintln!("VM halted: {}", fault);
+        process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        rom_path: String::new(),
+        limit: Limit::Frames(DEFAULT_FRAMES),
+        pacing: Pacing::Rate(DEFAULT_TICK_RATE),
+        quirks: Quirks::default(),
+        seed: None,
+        engine: Engine::Interpreter,
+        presses: Vec::new(),
+        pbm_path: None,
+        png_path: None,
+        json_path: None,
+        trace_path: None,
+        trace_filter: TraceFilter::default(),
+        profile_path: None,
+        profile_json_path: None,
+        profile_listing_path: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--frames" => options.limit = Limit::Frames(parse_number(&value()?)?),
+            "--instructions" => options.limit = Limit::Instructions(parse_number(&value()?)?),
+            "--tickrate" => options.pacing = Pacing::parse(&value()?)?,
+            "--quirks" => options.quirks = Quirks::from_name(&value()?)?,
+            "--seed" => options.seed = Some(parse_number(&value()?)?),
+            "--engine" => options.engine = Engine::from_name(&value()?)?,
+            "--press" => options.presses.push(headless::parse_key_press(&value()?)?),
+            "--script" => {
+                let path = value()?;
+                let script = std::fs::read_to_string(&path)
+                    .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+                options.presses.extend(headless::parse_script(&script)?);
+            }
+            "--pbm" => options.pbm_path = Some(value()?),
+            "--png" => options.png_path = Some(value()?),
+            "--json" => options.json_path = Some(value()?),
+            "--trace" => options.trace_path = Some(value()?),
+            "--trace-range" => options.trace_filter.range = Some(trace::parse_range(&value()?)?),
+            "--trace-limit" => options.trace_filter.limit = Some(parse_number(&value()?)?),
+            "--profile" => options.profile_path = Some(value()?),
+            "--profile-json" => options.profile_json_path = Some(value()?),
+            "--profile-listing" => options.profile_listing_path = Some(value()?),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+            _ if options.rom_path.is_empty() => options.rom_path = arg,
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+
+    if options.rom_path.is_empty() {
+        return Err("Missing rom".to_string());
+    }
+    Ok(options)
+}
+
+fn read_or_exit(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", path, err);
+        process::exit(2);
+    })
+}
+
+fn write_or_exit(path: &str, data: &[u8]) {
+    if let Err(err) = std::fs::write(path, data) {
+        eprintln!("Failed to write {}: {}", path, err);
+        process::exit(2);
+    }
+}
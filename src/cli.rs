@@ -0,0 +1,35 @@
+// Argument parsing shared by the binaries and the debugger console
+
+// Decimal, or hex with a 0x prefix
+pub fn parse_number(value: &str) -> Result<u64, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| format!("Invalid number '{}'", value))
+}
+
+// Instructions per second, nothing would ever run at 0
+pub fn parse_tickrate(value: &str) -> Result<u64, String> {
+    match parse_number(value)? {
+        0 => Err("Tickrate has to be at least 1".to_string()),
+        tickrate => Ok(tickrate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_number, parse_tickrate};
+
+    #[test]
+    fn parse_number_test() {
+        assert_eq!(parse_number("512"), Ok(512));
+        assert_eq!(parse_number("0x200"), Ok(512));
+        assert!(parse_number("0X200").is_err());
+        assert!(parse_number("-1").is_err());
+        assert!(parse_number("18446744073709551616").is_err());
+
+        assert_eq!(parse_tickrate("0x1"), Ok(1));
+        assert!(parse_tickrate("0").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+// COSMAC VIP timing: instead of a fixed number of instructions per frame, instructions run
+// until their machine cycles use up what the VIP had left per 60Hz frame. Counts follow
+// analyses of the original interpreter, they include its fetch and decode overhead and
+// depend on the data for DRW, LD B and the other loops.
+
+use std::mem;
+
+use crate::opcode::OpCode;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::timing::FRAME_RATE;
+use crate::vm::{REGISTER_NUM, VM};
+
+// The CDP1802 runs at 1.7609MHz, a machine cycle takes 8 clocks
+const CLOCK_RATE: u64 = 1_760_900;
+const CLOCKS_PER_CYCLE: u64 = 8;
+pub const FRAME_CYCLES: u64 = CLOCK_RATE / CLOCKS_PER_CYCLE / FRAME_RATE;
+// The CDP1861 reads 8 bytes by DMA for each of its 128 lines, and the interrupt routine that
+// starts it (and counts down the timers) takes about as long as a short instruction
+const DISPLAY_CYCLES: u64 = 128 * 8 + 46;
+// Left to the interpreter every frame
+pub const INTERPRETER_CYCLES: u64 = FRAME_CYCLES - DISPLAY_CYCLES;
+
+// Fetching and decoding, paid by every instruction on top of its own cost
+const FETCH_CYCLES: u64 = 40;
+// Taken skips have to step over the next instruction as well
+const SKIP_CYCLES: u64 = 4;
+
+// Carries the cycles an instruction ran over a frame into the next one
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CycleBudget {
+    debt: u64,
+}
+
+impl CycleBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Runs one frame, step executes the next instruction and returns false to stop early
+    // Returns the instructions executed
+    pub fn run_frame(&mut self, vm: &mut VM, mut step: impl FnMut(&mut VM) -> bool) -> u64 {
+        let debt = mem::take(&mut self.debt);
+        if debt >= INTERPRETER_CYCLES {
+            self.debt = debt - INTERPRETER_CYCLES;
+            return 0;
+        }
+        let available = INTERPRETER_CYCLES - debt;
+
+        let mut used = 0;
+        let mut executed = 0;
+        while used < available {
+            let opcode = vm.peek_opcode();
+            // DRW waits for the display interrupt, so it only runs first thing in a frame
+            if matches!(opcode, Some(OpCode::DRWVVN(..))) && executed > 0 {
+                return executed;
+            }
+
+            let pc = vm.get_program_counter();
+            let registers = *vm.get_registers();
+            let index = vm.get_index_register();
+            if !step(vm) {
+                return executed;
+            }
+            executed += 1;
+
+            // Anything that can't be fetched faults in step
+            if let Some(opcode) = opcode {
+                let is_skip_taken =
+                    is_skip(opcode) && vm.get_program_counter().wrapping_sub(pc) > 2;
+                used += instruction_cycles(opcode, &registers, index, is_skip_taken);
+            }
+        }
+        self.debt = used - available;
+        executed
+    }
+
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u64(self.debt);
+    }
+
+    pub fn read_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        Ok(Self {
+            debt: reader.read_u64()?,
+        })
+    }
+}
+
+fn is_skip(opcode: OpCode) -> bool {
+    use OpCode::*;
+    matches!(
+        opcode,
+        SEVB(..) | SNEVB(..) | SEVV(..) | SNEVV(..) | SKPV(_) | SKNPV(_)
+    )
+}
+
+// Machine cycles for one instruction, given the registers and I from before it ran
+pub fn instruction_cycles(
+    opcode: OpCode,
+    registers: &[u8; REGISTER_NUM],
+    index: u16,
+    is_skip_taken: bool,
+) -> u64 {
+    use OpCode::*;
+    let cycles = match opcode {
+        // Clears the 256 display bytes one by one
+        CLS() => 3078,
+        RET() => 10,
+        JP(_) => 12,
+        CALL(_) => 26,
+        SEVB(..) | SNEVB(..) => 10,
+        SEVV(..) | SNEVV(..) => 14,
+        LDVB(..) => 6,
+        ADDVB(..) => 10,
+        LDVV(..) | ORVV(..) | ANDVV(..) | XORVV(..) | ADDVV(..) | SUBVV(..) | SHRVV(..)
+        | SUBNVV(..) | SHLVV(..) => 44,
+        LDIA(_) => 12,
+        // One more when the addition carries into the high byte
+        JPVA(addr) => 22 + page_carry(addr, registers[0x0]),
+        RNDVB(..) => 36,
+        DRWVVN(x, _, rows) => draw_cycles(registers[x as usize], rows),
+        SKPV(_) | SKNPV(_) => 14,
+        LDVDT(_) | LDDTV(_) | LDSTV(_) => 10,
+        // Counted per check, the VM runs it again until a key is down
+        LDVK(_) => 18,
+        ADDIV(x) => 16 + page_carry(index, registers[x as usize]) * 2,
+        LDFV(_) => 20,
+        // Digits are found by repeated subtraction
+        LDBV(x) => {
+            let value = registers[x as usize] as u64;
+            80 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+        }
+        LDIV(x) | LDVI(x) => 14 + 14 * (x as u64 + 1),
+        // Machine code calls, and everything the VIP interpreter didn't have, are costed
+        // like a register load
+        NOP() | SCDN(_) | SCUN(_) | SCR() | SCL() | EXIT() | LOW() | HIGH() | LDIVV(..)
+        | LDVVI(..) | LDIL() | PLANEN(_) | AUDIO() | LDHFV(_) | LDPV(_) | LDRV(_) | LDVR(_) => 6,
+    };
+    FETCH_CYCLES + cycles + if is_skip_taken { SKIP_CYCLES } else { 0 }
+}
+
+fn page_carry(address: u16, offset: u8) -> u64 {
+    ((address & 0xFF) + offset as u16 > 0xFF) as u64
+}
+
+// Each row is shifted into place one bit at a time, and written to a second byte
+// when the sprite isn't byte aligned
+fn draw_cycles(x: u8, rows: u8) -> u64 {
+    let shift = (x % 8) as u64;
+    let row = 46 + 8 * shift + if shift > 0 { 14 } else { 0 };
+    26 + rows as u64 * row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instruction_cycles, CycleBudget, FETCH_CYCLES, FRAME_CYCLES, INTERPRETER_CYCLES};
+    use crate::{keypad::KeypadState, opcode::OpCode, quirks::Quirks, vm::VM};
+
+    #[test]
+    fn instruction_cycles_test() {
+        assert_eq!(FRAME_CYCLES, 3668);
+        let mut registers = [0; 16];
+        registers[0x1] = 255;
+        registers[0x2] = 3;
+        let cycles = |opcode, is_skip_taken| {
+            instruction_cycles(opcode, &registers, 0x2FF, is_skip_taken) - FETCH_CYCLES
+        };
+        assert_eq!(cycles(OpCode::LDVB(0x0, 1), false), 6);
+        assert_eq!(cycles(OpCode::SEVB(0x0, 0), false), 10);
+        assert_eq!(cycles(OpCode::SEVB(0x0, 0), true), 14);
+        // 2 + 5 + 5 digits
+        assert_eq!(cycles(OpCode::LDBV(0x1), false), 80 + 16 * 12);
+        assert_eq!(cycles(OpCode::ADDIV(0x2), false), 18);
+        assert_eq!(cycles(OpCode::ADDIV(0x0), false), 16);
+        // Aligned and shifted by 3 bits
+        assert_eq!(cycles(OpCode::DRWVVN(0x0, 0x0, 2), false), 26 + 2 * 46);
+        assert_eq!(
+            cycles(OpCode::DRWVVN(0x2, 0x0, 2), false),
+            26 + 2 * (46 + 24 + 14)
+        );
+    }
+
+    #[test]
+    fn budget_test() {
+        let rom = [
+            0x70, 0x01, // 0x200 ADD V0, 0x01
+            0x12, 0x00, // 0x202 JP 0x200
+        ];
+        let mut vm = VM::with_seed(&rom, Quirks::cosmac_vip(), 0);
+        let keypad = KeypadState::new();
+        let mut budget = CycleBudget::new();
+        let mut tick = |vm: &mut VM| vm.tick(&keypad).is_ok();
+
+        // 50 and 52 cycles, the last one runs over into the next frame
+        let pair = 50 + 52;
+        let executed = budget.run_frame(&mut vm, &mut tick);
+        assert_eq!(executed, (INTERPRETER_CYCLES / pair * 2) + 1);
+        assert_eq!(budget.debt, 50 - INTERPRETER_CYCLES % pair);
+
+        // CLS runs over into the next frame, DRWs only run right after the display interrupt
+        let rom = [
+            0x00, 0xE0, // 0x200 CLS
+            0xD0, 0x01, // 0x202 DRW V0, V0, 1
+            0xD0, 0x01, // 0x204 DRW V0, V0, 1
+            0x12, 0x06, // 0x206 JP 0x206
+        ];
+        let mut vm = VM::with_seed(&rom, Quirks::cosmac_vip(), 0);
+        let mut budget = CycleBudget::new();
+        assert_eq!(budget.run_frame(&mut vm, &mut tick), 1);
+        assert_eq!(budget.debt, FETCH_CYCLES + 3078 - INTERPRETER_CYCLES);
+        assert_eq!(budget.run_frame(&mut vm, &mut tick), 1);
+        assert_eq!(vm.get_program_counter(), 0x204);
+        assert!(budget.run_frame(&mut vm, &mut tick) > 1);
+        assert_eq!(vm.get_program_counter(), 0x206);
+    }
+}
@@ -0,0 +1,733 @@
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::cli::parse_number;
+use crate::keypad::KeypadState;
+use crate::memory::MEM_SIZE;
+use crate::opcode::{Listing, OpCode, INSTRUCTION_SIZE};
+use crate::vm::VM;
+use crate::watchpoint::{WatchAction, WatchKind, Watchpoint};
+
+pub const PROMPT: &str = "(c8db) ";
+
+const MEMORY_BYTES_PER_LINE: usize = 16;
+const DEFAULT_MEMORY_LEN: usize = 64;
+const DEFAULT_LIST_LEN: usize = 8;
+
+const HELP: &str = "Commands (short forms in brackets), numbers are decimal or 0x hex:
+  break <addr>            [b]  Stop before the instruction at addr runs
+  delete [addr]           [d]  Remove a breakpoint, or all of them
+  breakpoints             [bl] List breakpoints
+  watch <addr> [len] ...  [wa] Stop on writes to len bytes (default 1)
+  unwatch [n]                  Remove watchpoint n, or all of them
+  watchpoints             [wl] List watchpoints
+  step [n]                [s]  Run n instructions (default 1)
+  next                    [n]  Step, running a CALL until it returns
+  continue                [c]  Run until a breakpoint or fault
+  finish                  [f]  Run until the current subroutine returns
+  pause                        Stop running
+  regs                    [r]  Print V0-VF, I, PC, SP and the timers
+  stack                        Print the call stack
+  mem <addr> [len]        [x]  Print len bytes of memory (default 64)
+  list [addr] [n]         [l]  Disassemble n instructions (default 8, from the PC)
+  set <reg> <value>            Set V0-VF, I, PC, DT or ST
+  write <addr> <byte>...  [w]  Write bytes to memory
+  help                    [h]  Print this text
+  quit                    [q]  Exit
+Watchpoints take 'read', 'rw' or 'exec' to watch other accesses, 'if <byte>' to only
+match that value and 'log' to print the access and the PC behind it without stopping.
+An empty line repeats the last command.";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Dt,
+    St,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Break(u16),
+    Delete(Option<u16>),
+    Breakpoints,
+    Watch(Watchpoint),
+    Unwatch(Option<usize>),
+    Watchpoints,
+    Step(u64),
+    Next,
+    Continue,
+    Finish,
+    Pause,
+    Registers,
+    Stack,
+    Memory { address: u16, len: usize },
+    List { address: Option<u16>, len: usize },
+    Set(Register, u16),
+    Write { address: u16, bytes: Vec<u8> },
+    Help,
+    Quit,
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match parts.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Err("Missing command".to_string()),
+    };
+    let arg = |idx: usize| -> Result<u64, String> {
+        match args.get(idx) {
+            Some(arg) => parse_number(arg),
+            None => Err(format!("Missing argument for {}", name)),
+        }
+    };
+    let optional_arg = |idx: usize| -> Result<Option<u64>, String> {
+        args.get(idx).map(|arg| parse_number(arg)).transpose()
+    };
+
+    let command = match name {
+        "break" | "b" => Command::Break(to_address(arg(0)?)?),
+        "delete" | "d" => Command::Delete(optional_arg(0)?.map(to_address).transpose()?),
+        "breakpoints" | "bl" => Command::Breakpoints,
+        "watch" | "wa" => Command::Watch(parse_watchpoint(args)?),
+        "unwatch" => Command::Unwatch(optional_arg(0)?.map(|id| id as usize)),
+        "watchpoints" | "wl" => Command::Watchpoints,
+        "step" | "s" => Command::Step(optional_arg(0)?.unwrap_or(1)),
+        "next" | "n" => Command::Next,
+        "continue" | "c" => Command::Continue,
+        "finish" | "f" => Command::Finish,
+        "pause" => Command::Pause,
+        "regs" | "r" => Command::Registers,
+        "stack" => Command::Stack,
+        "mem" | "x" => Command::Memory {
+            address: to_address(arg(0)?)?,
+            len: optional_arg(1)?.map_or(Ok(DEFAULT_MEMORY_LEN), to_len)?,
+        },
+        "list" | "l" => Command::List {
+            address: optional_arg(0)?.map(to_address).transpose()?,
+            len: optional_arg(1)?.map_or(Ok(DEFAULT_LIST_LEN), to_len)?,
+        },
+        "set" => {
+            let register = match args.first() {
+                Some(register) => parse_register(register)?,
+                None => return Err("Missing argument for set".to_string()),
+            };
+            let value = arg(1)?;
+            let max = match register {
+                Register::V(_) | Register::Dt | Register::St => u8::MAX as u64,
+                Register::I | Register::Pc => u16::MAX as u64,
+            };
+            if value > max {
+                return Err(format!("{:#X} is too large for that register", value));
+            }
+            Command::Set(register, value as u16)
+        }
+        "write" | "w" => {
+            let address = to_address(arg(0)?)?;
+            let bytes = (1..args.len().max(2))
+                .map(|idx| {
+                    let value = arg(idx)?;
+                    u8::try_from(value).map_err(|_| format!("{:#X} isn't a byte", value))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Command::Write { address, bytes }
+        }
+        "help" | "h" => Command::Help,
+        "quit" | "q" => Command::Quit,
+        _ => return Err(format!("Unknown command '{}', try 'help'", name)),
+    };
+    Ok(command)
+}
+
+// <addr> [len] [read|write|rw|exec] [if <byte>] [log]
+fn parse_watchpoint(args: &[&str]) -> Result<Watchpoint, String> {
+    let mut args = args.iter().peekable();
+    let start = match args.next() {
+        Some(address) => to_address(parse_number(address)?)?,
+        None => return Err("Missing argument for watch".to_string()),
+    };
+    let len = match args.peek().map(|arg| parse_number(arg)) {
+        Some(Ok(len)) => {
+            args.next();
+            len
+        }
+        _ => 1,
+    };
+    let end = match (start as u64 + len).checked_sub(1) {
+        Some(end) if len > 0 => to_address(end)?,
+        _ => return Err("Watchpoints cover at least one byte".to_string()),
+    };
+
+    let mut watchpoint = Watchpoint {
+        start,
+        end,
+        kind: WatchKind::Write,
+        value: None,
+        action: WatchAction::Pause,
+    };
+    while let Some(arg) = args.next() {
+        match *arg {
+            "read" => watchpoint.kind = WatchKind::Read,
+            "write" => watchpoint.kind = WatchKind::Write,
+            "rw" => watchpoint.kind = WatchKind::ReadWrite,
+            "exec" => watchpoint.kind = WatchKind::Execute,
+            "log" => watchpoint.action = WatchAction::Log,
+            "if" => {
+                let value = match args.next() {
+                    Some(value) => parse_number(value)?,
+                    None => return Err("Missing value after 'if'".to_string()),
+                };
+                let value =
+                    u8::try_from(value).map_err(|_| format!("{:#X} isn't a byte", value))?;
+                watchpoint.value = Some(value);
+            }
+            _ => return Err(format!("Unknown watchpoint option '{}'", arg)),
+        }
+    }
+    Ok(watchpoint)
+}
+
+fn to_address(value: u64) -> Result<u16, String> {
+    u16::try_from(value).map_err(|_| format!("Address {:#X} is out of memory", value))
+}
+
+// Anything longer would only repeat memory
+fn to_len(value: u64) -> Result<usize, String> {
+    match usize::try_from(value) {
+        Ok(len) if len <= MEM_SIZE => Ok(len),
+        _ => Err(format!("Length {} is larger than memory", value)),
+    }
+}
+
+fn parse_register(name: &str) -> Result<Register, String> {
+    let upper = name.to_ascii_uppercase();
+    match upper.as_str() {
+        "I" => Ok(Register::I),
+        "PC" => Ok(Register::Pc),
+        "DT" => Ok(Register::Dt),
+        "ST" => Ok(Register::St),
+        _ => upper
+            .strip_prefix('V')
+            .filter(|idx| idx.len() == 1)
+            .and_then(|idx| u8::from_str_radix(idx, 16).ok())
+            .map(Register::V)
+            .ok_or(format!("Unknown register '{}'", name)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Paused,
+    Running,
+    // Until the CALL that was stepped over returns to the instruction after it
+    StepOver { return_pc: u16, depth: usize },
+    // Until the subroutine that was running returns
+    Finish { depth: usize },
+}
+
+pub enum Response {
+    Output(String),
+    Quit,
+}
+
+// Runs the VM in place of the frame loop's ticks, so it works the same with or without a window
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    mode: Mode,
+    // Resuming from a breakpoint mustn't stop on it again straight away
+    resume_pc: Option<u16>,
+    last_command: Option<Command>,
+}
+
+impl Debugger {
+    // Starts out paused, so breakpoints can be set before anything runs
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Paused,
+            resume_pc: None,
+            last_command: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    // An empty line repeats the last command, like in gdb
+    pub fn handle_line(&mut self, vm: &mut VM, keypad: &KeypadState, line: &str) -> Response {
+        let command = if line.trim().is_empty() {
+            match &self.last_command {
+                Some(command) => command.clone(),
+                None => return Response::Output(String::new()),
+            }
+        } else {
+            match parse_command(line) {
+                Ok(command) => command,
+                Err(err) => return Response::Output(err),
+            }
+        };
+        self.last_command = Some(command.clone());
+
+        match command {
+            Command::Quit => Response::Quit,
+            command => Response::Output(self.execute(vm, keypad, command)),
+        }
+    }
+
+    pub fn execute(&mut self, vm: &mut VM, keypad: &KeypadState, command: Command) -> String {
+        let pc = vm.get_program_counter();
+        match command {
+            Command::Break(address) => {
+                self.breakpoints.insert(address);
+                format!("Breakpoint at {:#06X}", address)
+            }
+            Command::Delete(Some(address)) => {
+                if self.breakpoints.remove(&address) {
+                    format!("Deleted breakpoint at {:#06X}", address)
+                } else {
+                    format!("No breakpoint at {:#06X}", address)
+                }
+            }
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                "Deleted all breakpoints".to_string()
+            }
+            Command::Breakpoints if self.breakpoints.is_empty() => "No breakpoints".to_string(),
+            Command::Breakpoints => self
+                .breakpoints
+                .iter()
+                .map(|address| format!("{:#06X}", address))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Command::Watch(watchpoint) => {
+                let id = vm.watchpoints_mut().add(watchpoint);
+                format!("Watchpoint {}: {}", id, watchpoint)
+            }
+            Command::Unwatch(Some(id)) => match vm.watchpoints_mut().remove(id) {
+                Some(_) => format!("Deleted watchpoint {}", id),
+                None => format!("No watchpoint {}", id),
+            },
+            Command::Unwatch(None) => {
+                vm.watchpoints_mut().clear();
+                "Deleted all watchpoints".to_string()
+            }
+            Command::Watchpoints if vm.watchpoints().is_empty() => "No watchpoints".to_string(),
+            Command::Watchpoints => vm
+                .watchpoints()
+                .iter()
+                .map(|(id, watchpoint)| format!("{}: {}", id, watchpoint))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Command::Step(count) => {
+                self.mode = Mode::Paused;
+                let mut output = String::new();
+                for _ in 0..count {
+                    let result = vm.tick(keypad);
+                    if take_watch_hits(vm, &mut output) || result.is_err() {
+                        break;
+                    }
+                }
+                output + &describe_location(vm)
+            }
+            Command::Next => match vm.peek_opcode() {
+                Some(OpCode::CALL(_)) => {
+                    self.resume(
+                        vm,
+                        Mode::StepOver {
+                            return_pc: pc.wrapping_add(INSTRUCTION_SIZE),
+                            depth: vm.get_stack().len(),
+                        },
+                    );
+                    String::new()
+                }
+                _ => self.execute(vm, keypad, Command::Step(1)),
+            },
+            Command::Continue => {
+                self.resume(vm, Mode::Running);
+                "Continuing".to_string()
+            }
+            Command::Finish => match vm.get_stack().len() {
+                0 => "Not inside a subroutine".to_string(),
+                depth => {
+                    self.resume(vm, Mode::Finish { depth });
+                    "Running until the subroutine returns".to_string()
+                }
+            },
+            Command::Pause if self.is_paused() => "Already paused".to_string(),
+            Command::Pause => {
+                self.mode = Mode::Paused;
+                format!("Paused\n{}", describe_location(vm))
+            }
+            Command::Registers => describe_registers(vm),
+            Command::Stack => describe_stack(vm),
+            Command::Memory { address, len } => dump_memory(vm, address, len),
+            Command::List { address, len } => list_instructions(vm, address.unwrap_or(pc), len),
+            Command::Set(register, value) => {
+                match register {
+                    Register::V(idx) => vm.set_register(idx as usize, value as u8),
+                    Register::I => vm.set_index_register(value),
+                    Register::Pc => vm.set_program_counter(value),
+                    Register::Dt => vm.set_delay_timer(value as u8),
+                    Register::St => vm.set_sound_timer(value as u8),
+                }
+                describe_registers(vm)
+            }
+            Command::Write { address, bytes } => {
+                if address as usize + bytes.len() > u16::MAX as usize + 1 {
+                    return "Write goes past the end of memory".to_string();
+                }
+                for (offset, byte) in bytes.iter().enumerate() {
+                    vm.write_memory(address + offset as u16, *byte);
+                }
+                format!("Wrote {} bytes at {:#06X}", bytes.len(), address)
+            }
+            Command::Help => HELP.to_string(),
+            Command::Quit => String::new(),
+        }
+    }
+
+    // Runs up to ticks instructions, returns a report when execution stops or watchpoints log
+    pub fn run(&mut self, vm: &mut VM, keypad: &KeypadState, ticks: u64) -> Option<String> {
+        let mut output = String::new();
+        for _ in 0..ticks {
+            if self.is_paused() {
+                break;
+            }
+
+            let pc = vm.get_program_counter();
+            let is_resuming = self.resume_pc.take() == Some(pc);
+            if !is_resuming && self.breakpoints.contains(&pc) {
+                let _ = writeln!(output, "Breakpoint at {:#06X}", pc);
+                return Some(self.stop(vm, output));
+            }
+            let result = vm.tick(keypad);
+            // Watchpoints stop after the instruction that tripped them
+            if take_watch_hits(vm, &mut output) || result.is_err() {
+                return Some(self.stop(vm, output));
+            }
+
+            let depth = vm.get_stack().len();
+            match self.mode {
+                Mode::StepOver {
+                    return_pc,
+                    depth: call_depth,
+                } if vm.get_program_counter() == return_pc && depth == call_depth => {
+                    return Some(self.stop(vm, output));
+                }
+                Mode::Finish { depth: call_depth } if depth < call_depth => {
+                    return Some(self.stop(vm, output));
+                }
+                _ => (),
+            }
+        }
+
+        if output.is_empty() {
+            None
+        } else {
+            output.pop();
+            Some(output)
+        }
+    }
+
+    fn resume(&mut self, vm: &VM, mode: Mode) {
+        self.mode = mode;
+        self.resume_pc = Some(vm.get_program_counter());
+    }
+
+    fn stop(&mut self, vm: &VM, reason: String) -> String {
+        self.mode = Mode::Paused;
+        reason + &describe_location(vm)
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Every hit is printed on a line of its own, returns whether any of them pauses
+fn take_watch_hits(vm: &mut VM, output: &mut String) -> bool {
+    let mut is_paused = false;
+    for hit in vm.watchpoints_mut().take_hits() {
+        let _ = writeln!(output, "{}", hit);
+        is_paused |= hit.action == WatchAction::Pause;
+    }
+    is_paused
+}
+
+// The instruction at the PC, or the fault that stopped the VM there
+fn describe_location(vm: &VM) -> String {
+    match vm.get_fault() {
+        Some(fault) => format!("VM halted: {}", fault),
+        None => list_instructions(vm, vm.get_program_counter(), 1),
+    }
+}
+
+fn describe_registers(vm: &VM) -> String {
+    let mut text = String::new();
+    for (idx, value) in vm.get_registers().iter().enumerate() {
+        let separator = if idx % 8 == 7 { "\n" } else { " " };
+        let _ = write!(text, "V{:X}={:02X}{}", idx, value, separator);
+    }
+    let _ = write!(
+        text,
+        "I={:#06X} PC={:#06X} SP={} DT={:02X} ST={:02X}\n{}",
+        vm.get_index_register(),
+        vm.get_program_counter(),
+        vm.get_stack().len(),
+        vm.get_delay_timer(),
+        vm.get_sound_timer(),
+        describe_location(vm)
+    );
+    text
+}
+
+fn describe_stack(vm: &VM) -> String {
+    let stack = vm.get_stack();
+    if stack.is_empty() {
+        return "Stack is empty".to_string();
+    }
+    // Innermost call first
+    stack
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(depth, address)| format!("#{} called from {:#06X}", depth, address))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn dump_memory(vm: &VM, address: u16, len: usize) -> String {
+    let end = (address as usize).saturating_add(len).min(MEM_SIZE);
+    (address as usize..end)
+        .step_by(MEMORY_BYTES_PER_LINE)
+        .map(|line_start| {
+            let line_end = (line_start + MEMORY_BYTES_PER_LINE).min(end);
+            let bytes: Vec<String> = (line_start..line_end)
+                .map(|address| format!("{:02X}", vm.read_memory(address as u16)))
+                .collect();
+            format!("{:#06X}: {}", line_start, bytes.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Decodes linearly from address, so data in between is shown as instructions as well
+fn list_instructions(vm: &VM, address: u16, len: usize) -> String {
+    let mut lines = Vec::with_capacity(len);
+    let mut address = address;
+    for _ in 0..len {
+        let marker = if address == vm.get_program_counter() {
+            "=>"
+        } else {
+            "  "
+        };
+        let listing = Listing::read(address, |address| vm.read_word(address));
+        lines.push(format!(
+            "{} {:#06X}: {:<8}  {}",
+            marker, address, listing.raw, listing.mnemonic
+        ));
+        address = address.wrapping_add(listing.size);
+    }
+    lines.join("\n")
+}
+
+// Reads commands on a thread of its own, so the window and the VM keep going while it waits
+pub struct Console {
+    lines: Receiver<String>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let is_sent = match line {
+                    Ok(line) => sender.send(line).is_ok(),
+                    Err(_) => false,
+                };
+                if !is_sent {
+                    break;
+                }
+            }
+        });
+        Self { lines }
+    }
+
+    pub fn try_read(&self) -> Result<String, TryRecvError> {
+        self.lines.try_recv()
+    }
+
+    pub fn reply(&self, output: &str) {
+        if !output.is_empty() {
+            println!("{}", output);
+        }
+        print!("{}", PROMPT);
+        let _ = io::stdout().flush();
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_command, Command, Debugger, Register, Response};
+    use crate::watchpoint::{WatchAction, WatchKind, Watchpoint};
+    use crate::{keypad::KeypadState, quirks::Quirks, vm::VM};
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse_command("b 0x204"), Ok(Command::Break(0x204)));
+        assert_eq!(parse_command("step"), Ok(Command::Step(1)));
+        assert_eq!(parse_command("s 10"), Ok(Command::Step(10)));
+        assert_eq!(
+            parse_command("set vA 0x20"),
+            Ok(Command::Set(Register::V(0xA), 0x20))
+        );
+        assert_eq!(
+            parse_command("w 0x300 1 0xFF"),
+            Ok(Command::Write {
+                address: 0x300,
+                bytes: vec![0x01, 0xFF]
+            })
+        );
+        assert_eq!(
+            parse_command("wa 0x300 2 rw if 5 log"),
+            Ok(Command::Watch(Watchpoint {
+                start: 0x300,
+                end: 0x301,
+                kind: WatchKind::ReadWrite,
+                value: Some(5),
+                action: WatchAction::Log,
+            }))
+        );
+        assert!(parse_command("watch 0xFFFF 2").is_err());
+        assert!(parse_command("watch 0x300 if").is_err());
+        assert!(parse_command("set v0 256").is_err());
+        assert!(parse_command("set vG 1").is_err());
+        assert!(parse_command("b 0x10000").is_err());
+        assert!(parse_command("w 0x300").is_err());
+        assert_eq!(
+            parse_command("x 0x200 0x10000"),
+            Ok(Command::Memory {
+                address: 0x200,
+                len: 0x10000
+            })
+        );
+        assert!(parse_command("x 0x200 18446744073709551615").is_err());
+        assert!(parse_command("l 0x200 0x10001").is_err());
+        assert!(parse_command("frobnicate").is_err());
+    }
+
+    #[test]
+    fn execution_test() {
+        let rom = [
+            0x22, 0x06, // 0x200 CALL 0x206
+            0x70, 0x01, // 0x202 ADD V0, 0x01
+            0x12, 0x02, // 0x204 JP 0x202
+            0x71, 0x01, // 0x206 ADD V1, 0x01
+            0x00, 0xEE, // 0x208 RET
+        ];
+        let mut vm = VM::with_seed(&rom, Quirks::default(), 0);
+        let keypad = KeypadState::new();
+        let mut debugger = Debugger::new();
+
+        // Nothing runs while paused
+        assert_eq!(debugger.run(&mut vm, &keypad, 10), None);
+        assert_eq!(vm.get_program_counter(), 0x200);
+
+        // Into the call, then out of it
+        debugger.execute(&mut vm, &keypad, Command::Step(1));
+        assert_eq!(vm.get_program_counter(), 0x206);
+        assert_eq!(vm.get_stack(), &[0x200]);
+        debugger.execute(&mut vm, &keypad, Command::Finish);
+        assert!(debugger.run(&mut vm, &keypad, 10).is_some());
+        assert_eq!(vm.get_program_counter(), 0x202);
+        assert!(debugger.is_paused());
+
+        // Stops at a breakpoint, and doesn't stop on it again when continuing
+        debugger.execute(&mut vm, &keypad, Command::Break(0x204));
+        debugger.execute(&mut vm, &keypad, Command::Continue);
+        let report = debugger.run(&mut vm, &keypad, 10).expect("Breakpoint hit");
+        assert!(report.starts_with("Breakpoint at 0x0204"));
+        debugger.execute(&mut vm, &keypad, Command::Continue);
+        assert!(debugger.run(&mut vm, &keypad, 10).is_some());
+        assert_eq!(vm.get_program_counter(), 0x204);
+        assert_eq!(vm.get_registers()[0x0], 2);
+
+        // Stepping over a call runs all of it
+        vm.set_program_counter(0x200);
+        debugger.execute(&mut vm, &keypad, Command::Delete(None));
+        debugger.execute(&mut vm, &keypad, Command::Next);
+        assert!(!debugger.is_paused());
+        assert!(debugger.run(&mut vm, &keypad, 10).is_some());
+        assert_eq!(vm.get_program_counter(), 0x202);
+        assert_eq!(vm.get_registers()[0x1], 2);
+
+        // Setting state, an empty line repeats the last command
+        debugger.execute(&mut vm, &keypad, Command::Set(Register::V(0x3), 0x42));
+        assert_eq!(vm.get_registers()[0x3], 0x42);
+        debugger.execute(
+            &mut vm,
+            &keypad,
+            Command::Write {
+                address: 0x300,
+                bytes: vec![0xAB, 0xCD],
+            },
+        );
+        assert_eq!(vm.read_memory(0x301), 0xCD);
+        debugger.handle_line(&mut vm, &keypad, "s");
+        assert!(matches!(
+            debugger.handle_line(&mut vm, &keypad, ""),
+            Response::Output(_)
+        ));
+        assert_eq!(vm.get_program_counter(), 0x202);
+        assert!(matches!(
+            debugger.handle_line(&mut vm, &keypad, "q"),
+            Response::Quit
+        ));
+    }
+
+    #[test]
+    fn watch_test() {
+        let rom = [
+            0xA3, 0x00, // 0x200 LD I, 0x300
+            0x70, 0x01, // 0x202 ADD V0, 0x01
+            0xF0, 0x55, // 0x204 LD [I], V0
+            0x12, 0x02, // 0x206 JP 0x202
+        ];
+        let mut vm = VM::with_seed(&rom, Quirks::default(), 0);
+        let keypad = KeypadState::new();
+        let mut debugger = Debugger::new();
+
+        // Logging keeps running, pausing stops after the write
+        debugger.handle_line(&mut vm, &keypad, "watch 0x300 log");
+        debugger.handle_line(&mut vm, &keypad, "watch 0x300 if 3");
+        debugger.execute(&mut vm, &keypad, Command::Continue);
+        let report = debugger.run(&mut vm, &keypad, 7).expect("Logged writes");
+        assert_eq!(
+            report,
+            "Watchpoint 1: write 0x0300 = 0x01 by PC 0x0204\n\
+             Watchpoint 1: write 0x0300 = 0x02 by PC 0x0204"
+        );
+        assert!(!debugger.is_paused());
+        let report = debugger.run(&mut vm, &keypad, 10).expect("Paused");
+        assert!(report.contains("Watchpoint 2: write 0x0300 = 0x03 by PC 0x0204"));
+        assert!(debugger.is_paused());
+        assert_eq!(vm.get_program_counter(), 0x206);
+
+        debugger.handle_line(&mut vm, &keypad, "unwatch");
+        assert!(vm.watchpoints().is_empty());
+    }
+}
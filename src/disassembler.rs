@@ -0,0 +1,296 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::memory::MEM_REGION_RAM;
+use crate::opcode::{Listing, OpCode, INSTRUCTION_SIZE};
+
+const DATA_BYTES_PER_LINE: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LabelKind {
+    Location,
+    Subroutine,
+}
+
+// Code is found by following control flow from the entry point, everything
+// that's never reached is printed as data
+pub struct Disassembly {
+    rom: Vec<u8>,
+    // Instruction start offsets in the rom, with their size in bytes
+    instructions: BTreeMap<usize, usize>,
+    labels: BTreeMap<u16, LabelKind>,
+}
+
+impl Disassembly {
+    pub fn new(rom: &[u8]) -> Self {
+        let mut disassembly = Self {
+            rom: rom.to_vec(),
+            instructions: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        };
+        disassembly.trace(MEM_REGION_RAM);
+        disassembly
+    }
+
+    pub fn is_code(&self, addr: u16) -> bool {
+        addr >= MEM_REGION_RAM
+            && self
+                .instructions
+                .contains_key(&((addr - MEM_REGION_RAM) as usize))
+    }
+
+    fn trace(&mut self, entry: u16) {
+        let mut pending = vec![entry];
+        let mut covered = BTreeSet::new();
+
+        while let Some(addr) = pending.pop() {
+            let offset = match self.offset_of(addr) {
+                // Misaligned code faults, so it can't be a real target
+                Some(offset) if addr % 2 == 0 => offset,
+                _ => continue,
+            };
+            if self.instructions.contains_key(&offset) {
+                continue;
+            }
+            let (opcode, size) = match self.decode(offset) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+            // Overlapping another instruction means this path is misread
+            if (offset..offset + size).any(|byte| covered.contains(&byte)) {
+                continue;
+            }
+            covered.extend(offset..offset + size);
+            self.instructions.insert(offset, size);
+
+            let next = addr.wrapping_add(size as u16);
+            match opcode {
+                OpCode::JP(target) => {
+                    self.add_label(target, LabelKind::Location);
+                    pending.push(target);
+                }
+                OpCode::CALL(target) => {
+                    self.add_label(target, LabelKind::Subroutine);
+                    pending.push(target);
+                    pending.push(next);
+                }
+                // Usually a jump table, its first entry is as good a guess as any
+                OpCode::JPVA(target) => {
+                    self.add_label(target, LabelKind::Location);
+                    pending.push(target);
+                }
+                OpCode::SEVB(..)
+                | OpCode::SNEVB(..)
+                | OpCode::SEVV(..)
+                | OpCode::SNEVV(..)
+                | OpCode::SKPV(..)
+                | OpCode::SKNPV(..) => {
+                    pending.push(next);
+                    let skipped_size = self
+                        .offset_of(next)
+                        .and_then(|offset| self.decode(offset))
+                        .map_or(INSTRUCTION_SIZE as usize, |(_, size)| size);
+                    pending.push(next.wrapping_add(skipped_size as u16));
+                }
+                OpCode::RET() | OpCode::EXIT() => (),
+                _ => pending.push(next),
+            }
+        }
+    }
+
+    fn add_label(&mut self, addr: u16, kind: LabelKind) {
+        // Calls win over jumps, so subroutines are named as such
+        let label = self.labels.entry(addr).or_insert(kind);
+        if kind == LabelKind::Subroutine {
+            *label = kind;
+        }
+    }
+
+    fn offset_of(&self, addr: u16) -> Option<usize> {
+        let offset = addr.checked_sub(MEM_REGION_RAM)? as usize;
+        if offset + INSTRUCTION_SIZE as usize <= self.rom.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    fn decode(&self, offset: usize) -> Option<(OpCode, usize)> {
+        let word = self.word_at(offset)?;
+        let opcode = OpCode::from_bytes(word)?;
+        // The long address has to be there as well
+        let size = opcode.size() as usize;
+        self.rom.get(offset..offset + size).map(|_| (opcode, size))
+    }
+
+    fn word_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self.rom.get(offset..offset + INSTRUCTION_SIZE as usize)?;
+        Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    // Only addresses that start a line can carry a label, the middle of an instruction can't
+    fn label_name(&self, addr: u16) -> Option<String> {
+        if !self.starts_line(addr) {
+            return None;
+        }
+        self.labels.get(&addr).map(|kind| match kind {
+            LabelKind::Location => format!("loc_{:03X}", addr),
+            LabelKind::Subroutine => format!("sub_{:03X}", addr),
+        })
+    }
+
+    fn starts_line(&self, addr: u16) -> bool {
+        let offset = match addr.checked_sub(MEM_REGION_RAM) {
+            Some(offset) if (offset as usize) < self.rom.len() => offset as usize,
+            _ => return false,
+        };
+        match self.instructions.range(..=offset).next_back() {
+            Some((start, size)) => *start == offset || start + size <= offset,
+            None => true,
+        }
+    }
+
+    // Targets outside of the rom keep their numeric address
+    fn target_name(&self, addr: u16) -> String {
+        match self.offset_of(addr) {
+            Some(_) => self
+                .label_name(addr)
+                .unwrap_or_else(|| format!("{:#05X}", addr)),
+            None => format!("{:#05X}", addr),
+        }
+    }
+
+    fn format_instruction(&self, offset: usize) -> String {
+        let (opcode, _) = self.decode(offset).expect("Traced instruction");
+        match opcode {
+            OpCode::JP(addr) => format!("JP {}", self.target_name(addr)),
+            OpCode::CALL(addr) => format!("CALL {}", self.target_name(addr)),
+            OpCode::JPVA(addr) => format!("JP V0, {}", self.target_name(addr)),
+            _ => {
+                let addr = MEM_REGION_RAM + offset as u16;
+                let read_word = |addr| {
+                    self.offset_of(addr)
+                        .and_then(|offset| self.word_at(offset))
+                        .unwrap_or_default()
+                };
+                Listing::read(addr, read_word).mnemonic
+            }
+        }
+    }
+}
+
+// Output is valid input for the assembler, addresses and raw words are in comments
+impl std::fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            let addr = MEM_REGION_RAM as usize + offset;
+            if let Some(label) = self.label_name(addr as u16) {
+                writeln!(f, "{}:", label)?;
+            }
+
+            if let Some(size) = self.instructions.get(&offset) {
+                let raw: String = self.rom[offset..offset + size]
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect();
+                writeln!(
+                    f,
+                    "    {:<24}; {:#05X}  {}",
+                    self.format_instruction(offset),
+                    addr,
+                    raw
+                )?;
+                offset += size;
+                continue;
+            }
+
+            // Data runs until the next instruction or label, in short lines
+            let mut end = offset + 1;
+            while end < self.rom.len()
+                && end - offset < DATA_BYTES_PER_LINE
+                && !self.instructions.contains_key(&end)
+                && !self
+                    .labels
+                    .contains_key(&((MEM_REGION_RAM as usize + end) as u16))
+            {
+                end += 1;
+            }
+            let bytes: Vec<String> = self.rom[offset..end]
+                .iter()
+                .map(|byte| format!("{:#04X}", byte))
+                .collect();
+            writeln!(
+                f,
+                "    {:<24}; {:#05X}",
+                format!("DB {}", bytes.join(", ")),
+                addr
+            )?;
+            offset = end;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Disassembly;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn control_flow_test() {
+        let rom = [
+            0x22, 0x08, // 0x200 CALL sub_208
+            0x30, 0x01, // 0x202 SE V0, 0x01
+            0x12, 0x02, // 0x204 JP loc_202
+            0x12, 0x0C, // 0x206 JP loc_20C
+            0xA2, 0x10, // 0x208 LD I, 0x210
+            0x00, 0xEE, // 0x20A RET
+            0xF0, 0x00, 0x12, 0x34, // 0x20C LD I, LONG 0x1234
+            0x00, 0x00, // 0x210 SYS 0x000
+            0xFF, 0xFF, 0x3C, // 0x212 data
+        ];
+        let disassembly = Disassembly::new(&rom);
+        assert!(disassembly.is_code(0x206));
+        assert!(disassembly.is_code(0x20C));
+        assert!(!disassembly.is_code(0x20E));
+        assert!(!disassembly.is_code(0x212));
+
+        let listing = disassembly.to_string();
+        let lines: Vec<&str> = listing.lines().map(|line| line.trim_end()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "    CALL sub_208            ; 0x200  2208",
+                "loc_202:",
+                "    SE V0, 0x01             ; 0x202  3001",
+                "    JP loc_202              ; 0x204  1202",
+                "    JP loc_20C              ; 0x206  120C",
+                "sub_208:",
+                "    LD I, 0x210             ; 0x208  A210",
+                "    RET                     ; 0x20A  00EE",
+                "loc_20C:",
+                "    LD I, LONG 0x1234       ; 0x20C  F0001234",
+                "    SYS 0x000               ; 0x210  0000",
+                "    DB 0xFF, 0xFF, 0x3C     ; 0x212",
+            ]
+        );
+
+        // Targets inside an instruction keep their number, no line could carry the label
+        let rom = [
+            0xF0, 0x00, 0x12, 0x01, // 0x200 LD I, LONG 0x1201
+            0x22, 0x02, // 0x204 CALL 0x202
+            0x12, 0x01, // 0x206 JP 0x201
+        ];
+        let listing = Disassembly::new(&rom).to_string();
+        let lines: Vec<&str> = listing.lines().map(|line| line.trim_end()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "    LD I, LONG 0x1201       ; 0x200  F0001201",
+                "    CALL 0x202              ; 0x204  2202",
+                "    JP 0x201                ; 0x206  1201",
+            ]
+        );
+        assert_eq!(assemble(&listing), Ok(rom.to_vec()));
+    }
+}
@@ -1,12 +1,25 @@
 use std::mem::size_of;
 
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
 // Default mode graphics
 const GFX_WIDTH_DEFAULT: usize = 64;
 const GFX_HEIGHT_DEFAULT: usize = 32;
 
+// High resolution (SCHIP) mode graphics
+const GFX_WIDTH_HIGH: usize = 128;
+const GFX_HEIGHT_HIGH: usize = 64;
+
 type Storage = u32;
 const STORAGE_BITS: usize = Storage::BITS as usize;
-const PACKED_WIDTH: usize = GFX_WIDTH_DEFAULT / STORAGE_BITS;
+// Packed state is sized to fit the largest resolution mode
+const PACKED_WIDTH: usize = GFX_WIDTH_HIGH / STORAGE_BITS;
+const PACKED_PLANE_SIZE: usize = PACKED_WIDTH * GFX_HEIGHT_HIGH;
+
+// XO-CHIP bit planes, stored one after another
+pub const PLANE_NUM: usize = 2;
+const PLANE_MASK_DEFAULT: u8 = 0b01;
+const PLANE_MASK_ALL: u8 = 0b11;
 
 struct Bounds {
     pub min: usize,
@@ -33,10 +46,13 @@ impl Bounds {
 pub struct ModificationData<'a> {
     pub offset: usize,
     pub data: &'a [Storage],
+    pub mode: (usize, usize),
 }
 
 pub struct DisplayState {
-    packed_state: [Storage; PACKED_WIDTH * GFX_HEIGHT_DEFAULT],
+    packed_state: [Storage; PACKED_PLANE_SIZE * PLANE_NUM],
+    high_res: bool,
+    selected_planes: u8,
     was_modified: bool,
     modification: Bounds,
 }
@@ -44,22 +60,55 @@ pub struct DisplayState {
 impl DisplayState {
     pub fn new() -> Self {
         Self {
-            packed_state: [0; PACKED_WIDTH * GFX_HEIGHT_DEFAULT],
+            packed_state: [0; PACKED_PLANE_SIZE * PLANE_NUM],
+            high_res: false,
+            selected_planes: PLANE_MASK_DEFAULT,
             was_modified: false,
             modification: Bounds::new(0),
         }
     }
 
     pub fn get_current_mode(&self) -> (usize, usize) {
-        // @TODO implement different modes and mode selection
-        (GFX_WIDTH_DEFAULT, GFX_HEIGHT_DEFAULT)
+        if self.high_res {
+            (GFX_WIDTH_HIGH, GFX_HEIGHT_HIGH)
+        } else {
+            (GFX_WIDTH_DEFAULT, GFX_HEIGHT_DEFAULT)
+        }
+    }
+
+    pub fn is_high_res(&self) -> bool {
+        self.high_res
+    }
+
+    pub fn set_high_res(&mut self, high_res: bool) {
+        // Packing depends on the mode, so the whole screen is cleared (and re-sent) on switch
+        self.high_res = high_res;
+        self.clear_all(false);
+    }
+
+    pub fn get_selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    pub fn select_planes(&mut self, mask: u8) {
+        self.selected_planes = mask & PLANE_MASK_ALL;
+    }
+
+    pub fn is_plane_selected(&self, plane: usize) -> bool {
+        self.selected_planes & (1 << plane) != 0
     }
 
-    pub fn pop_modifications(&mut self) -> Option<ModificationData> {
+    // Both planes, laid out the way the renderer uploads them
+    pub fn get_packed_state(&self) -> &[Storage] {
+        &self.packed_state
+    }
+
+    pub fn pop_modifications(&mut self) -> Option<ModificationData<'_>> {
         let result = if self.was_modified {
             Some(ModificationData {
                 offset: self.modification.min * size_of::<Storage>(),
                 data: &self.packed_state[self.modification.min..=self.modification.max],
+                mode: self.get_current_mode(),
             })
         } else {
             None
@@ -70,22 +119,70 @@ impl DisplayState {
     }
 
     pub fn clear(&mut self, clear_with: bool) {
+        // Only affects the selected planes
+        self.clear_planes(self.selected_planes, clear_with);
+    }
+
+    pub fn clear_all(&mut self, clear_with: bool) {
+        self.clear_planes(PLANE_MASK_ALL, clear_with);
+    }
+
+    pub fn scroll_down(&mut self, rows: usize) {
         let (gfx_width, gfx_height) = self.get_current_mode();
-        for x in 0..gfx_width {
+        for plane in self.selected_plane_indices() {
+            for y in (0..gfx_height).rev() {
+                for x in 0..gfx_width {
+                    let value = y >= rows && self.get(plane, x, y - rows);
+                    self.set(plane, x, y, value);
+                }
+            }
+        }
+    }
+
+    pub fn scroll_up(&mut self, rows: usize) {
+        let (gfx_width, gfx_height) = self.get_current_mode();
+        for plane in self.selected_plane_indices() {
             for y in 0..gfx_height {
-                self.set(x, y, clear_with);
+                for x in 0..gfx_width {
+                    let value = y + rows < gfx_height && self.get(plane, x, y + rows);
+                    self.set(plane, x, y, value);
+                }
+            }
+        }
+    }
+
+    pub fn scroll_left(&mut self, cols: usize) {
+        let (gfx_width, gfx_height) = self.get_current_mode();
+        for plane in self.selected_plane_indices() {
+            for x in 0..gfx_width {
+                for y in 0..gfx_height {
+                    let value = x + cols < gfx_width && self.get(plane, x + cols, y);
+                    self.set(plane, x, y, value);
+                }
             }
         }
     }
 
-    pub fn get(&self, x: usize, y: usize) -> bool {
-        let (col, nibble) = self.get_bucket(x, y);
+    pub fn scroll_right(&mut self, cols: usize) {
+        let (gfx_width, gfx_height) = self.get_current_mode();
+        for plane in self.selected_plane_indices() {
+            for x in (0..gfx_width).rev() {
+                for y in 0..gfx_height {
+                    let value = x >= cols && self.get(plane, x - cols, y);
+                    self.set(plane, x, y, value);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, plane: usize, x: usize, y: usize) -> bool {
+        let (col, nibble) = self.get_bucket(plane, x, y);
         let mask = 1 << nibble;
         self.packed_state[col] & mask != 0
     }
 
-    pub fn set(&mut self, x: usize, y: usize, value: bool) {
-        let (col, nibble) = self.get_bucket(x, y);
+    pub fn set(&mut self, plane: usize, x: usize, y: usize, value: bool) {
+        let (col, nibble) = self.get_bucket(plane, x, y);
         let mask = 1 << nibble;
         if value {
             self.packed_state[col] |= mask;
@@ -95,6 +192,53 @@ impl DisplayState {
         self.extend_modification(col);
     }
 
+    pub fn get_pixel(&self, x: usize, y: usize) -> u8 {
+        // Palette index, with plane 0 as the low bit
+        (0..PLANE_NUM).fold(0, |color, plane| {
+            color | ((self.get(plane, x, y) as u8) << plane)
+        })
+    }
+
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        for packed in self.packed_state.iter() {
+            writer.write_u32(*packed);
+        }
+        writer.write_bool(self.high_res);
+        writer.write_u8(self.selected_planes);
+    }
+
+    pub fn read_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        let mut display = Self::new();
+        for packed in display.packed_state.iter_mut() {
+            *packed = reader.read_u32()?;
+        }
+        display.high_res = reader.read_bool()?;
+        display.select_planes(reader.read_u8()?);
+        // Nothing on the GPU side matches the restored screen, so all of it is re-sent
+        display.mark_all_modified();
+        Ok(display)
+    }
+
+    pub fn mark_all_modified(&mut self) {
+        self.extend_modification(0);
+        self.extend_modification(self.packed_state.len() - 1);
+    }
+
+    fn clear_planes(&mut self, mask: u8, clear_with: bool) {
+        let (gfx_width, gfx_height) = self.get_current_mode();
+        for plane in planes_in(mask) {
+            for x in 0..gfx_width {
+                for y in 0..gfx_height {
+                    self.set(plane, x, y, clear_with);
+                }
+            }
+        }
+    }
+
+    fn selected_plane_indices(&self) -> impl Iterator<Item = usize> {
+        planes_in(self.selected_planes)
+    }
+
     fn extend_modification(&mut self, col: usize) {
         if self.was_modified {
             self.modification.extend(col)
@@ -105,15 +249,26 @@ impl DisplayState {
     }
 
     #[inline]
-    fn get_bucket(&self, x: usize, y: usize) -> (usize, usize) {
+    fn get_bucket(&self, plane: usize, x: usize, y: usize) -> (usize, usize) {
         let (gfx_width, _) = self.get_current_mode();
         let real_x = x / STORAGE_BITS;
-        let col = (y * (gfx_width / STORAGE_BITS)) + real_x;
+        let col = (plane * PACKED_PLANE_SIZE) + (y * (gfx_width / STORAGE_BITS)) + real_x;
         let nibble = (STORAGE_BITS * (real_x + 1)) - x - 1;
         (col, nibble)
     }
 }
 
+#[inline]
+fn planes_in(mask: u8) -> impl Iterator<Item = usize> {
+    (0..PLANE_NUM).filter(move |plane| mask & (1 << plane) != 0)
+}
+
+impl Default for DisplayState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::mem::size_of;
@@ -124,27 +279,80 @@ mod tests {
     #[test]
     fn modification_test() {
         let mut gfx = DisplayState::new();
-        gfx.set(0, 0, true);
+        gfx.set(0, 0, 0, true);
 
         let modification = gfx.pop_modifications().expect("No modifications");
         assert_eq!(modification.data.len(), 1);
         assert_eq!(modification.offset, 0);
 
-        gfx.set(63, 31, true);
+        gfx.set(0, 63, 31, true);
 
         let modification = gfx.pop_modifications().expect("No modifications");
         assert_eq!(modification.data.len(), 1);
         assert_eq!(modification.offset, 63 * size_of::<u32>());
 
-        gfx.set(63, 31, true);
-        gfx.set(0, 0, true);
+        gfx.set(0, 63, 31, true);
+        gfx.set(0, 0, 0, true);
 
         let modification = gfx.pop_modifications().expect("No modifications");
         assert_eq!(modification.data.len(), 64);
         assert_eq!(modification.offset, 0);
 
-        if let Some(_) = gfx.pop_modifications() {
-            assert!(false);
+        assert!(gfx.pop_modifications().is_none());
+    }
+
+    #[test]
+    fn mode_switch_test() {
+        let mut gfx = DisplayState::new();
+        gfx.set(0, 0, 0, true);
+        gfx.pop_modifications();
+
+        gfx.set_high_res(true);
+        assert!(gfx.is_high_res());
+        assert_eq!(gfx.get_current_mode(), (128, 64));
+        assert!(!gfx.get(0, 0, 0));
+
+        let modification = gfx.pop_modifications().expect("No modifications");
+        assert_eq!(modification.offset, 0);
+        // Both planes get cleared
+        assert_eq!(modification.data.len(), 512);
+        assert_eq!(modification.mode, (128, 64));
+
+        gfx.set(0, 127, 63, true);
+        assert!(gfx.get(0, 127, 63));
+        gfx.pop_modifications();
+
+        gfx.set_high_res(false);
+        assert_eq!(gfx.get_current_mode(), (64, 32));
+        let modification = gfx.pop_modifications().expect("No modifications");
+        assert_eq!(modification.data.len(), super::PACKED_PLANE_SIZE + 64);
+    }
+
+    #[test]
+    fn scroll_test() {
+        let mut gfx = DisplayState::new();
+        gfx.set(0, 10, 10, true);
+
+        gfx.scroll_down(4);
+        assert!(!gfx.get(0, 10, 10));
+        assert!(gfx.get(0, 10, 14));
+
+        gfx.scroll_right(4);
+        assert!(gfx.get(0, 14, 14));
+
+        gfx.scroll_left(8);
+        assert!(gfx.get(0, 6, 14));
+        assert!(!gfx.get(0, 14, 14));
+
+        gfx.scroll_up(14);
+        assert!(gfx.get(0, 6, 0));
+
+        // Pixels scrolled off screen are discarded
+        gfx.scroll_down(32);
+        for x in 0..super::GFX_WIDTH_DEFAULT {
+            for y in 0..super::GFX_HEIGHT_DEFAULT {
+                assert!(!gfx.get(0, x, y));
+            }
         }
     }
 
@@ -159,23 +367,57 @@ mod tests {
             let mut gfx = DisplayState::new();
             // Set
             for (x, y) in coords.iter() {
-                gfx.set(*x, *y, true);
+                gfx.set(0, *x, *y, true);
             }
             for x in 0..super::GFX_WIDTH_DEFAULT {
                 for y in 0..super::GFX_HEIGHT_DEFAULT {
-                    let res = gfx.get(x, y);
+                    let res = gfx.get(0, x, y);
                     assert!(res == coords.contains(&(x, y)));
                 }
             }
             // Reset
             for (x, y) in coords.iter() {
-                gfx.set(*x, *y, false);
+                gfx.set(0, *x, *y, false);
             }
             for x in 0..super::GFX_WIDTH_DEFAULT {
                 for y in 0..super::GFX_HEIGHT_DEFAULT {
-                    assert!(gfx.get(x, y) == false);
+                    assert!(!gfx.get(0, x, y));
                 }
             }
         }
     }
+
+    #[test]
+    fn plane_test() {
+        let mut gfx = DisplayState::new();
+        assert_eq!(gfx.get_selected_planes(), 0b01);
+        gfx.pop_modifications();
+
+        gfx.set(1, 0, 0, true);
+        let modification = gfx.pop_modifications().expect("No modifications");
+        assert_eq!(
+            modification.offset,
+            super::PACKED_PLANE_SIZE * size_of::<u32>()
+        );
+        assert_eq!(gfx.get_pixel(0, 0), 0b10);
+
+        gfx.set(0, 0, 0, true);
+        assert_eq!(gfx.get_pixel(0, 0), 0b11);
+
+        // Only selected planes are cleared and scrolled
+        gfx.select_planes(0b10);
+        gfx.scroll_right(4);
+        assert_eq!(gfx.get_pixel(0, 0), 0b01);
+        assert_eq!(gfx.get_pixel(4, 0), 0b10);
+
+        gfx.clear(false);
+        assert_eq!(gfx.get_pixel(0, 0), 0b01);
+        assert_eq!(gfx.get_pixel(4, 0), 0b00);
+
+        // Mode switches clear every plane
+        gfx.set(1, 4, 0, true);
+        gfx.set_high_res(true);
+        assert_eq!(gfx.get_pixel(0, 0), 0b00);
+        assert_eq!(gfx.get_pixel(4, 0), 0b00);
+    }
 }
@@ -1,129 +1,747 @@
-use winit::{
-    event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent},
-    event_loop::ControlFlow,
-    window::Window,
+use std::convert::TryFrom;
+use std::mem::size_of;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use winit::event::{ModifiersState, VirtualKeyCode};
+
+use crate::{
+    debugger::{Command, Console, Debugger, Response},
+    fault::VmFault,
+    gdbstub::{self, GdbServer},
+    keypad::KeypadState,
+    movie::{self, Movie, MovieHeader},
+    octo,
+    quirks::Quirks,
+    renderer::{Palette, DEFAULT_PALETTE},
+    rewind::Rewind,
+    rng,
+    timing::{Pacing, Timing, FRAME_RATE},
+    trace::Tracer,
+    vm::{Engine, AUDIO_PATTERN_SIZE, VM},
 };
 
-use crate::{beeper::Beeper, keypad::KeypadState, renderer::Renderer, timing::Timing, vm::VM};
+pub const WINDOW_TITLE: &str = "CHIP-8 Emulator";
 
 const TICK_RATE_MIN: u64 = 100;
 const TICK_RATE_NORMAL: u64 = 250;
 const TICK_RATE_FAST: u64 = 500;
 const TICK_RATE_MAX: u64 = 1000;
+const VIP_TIMING_KEY: VirtualKeyCode = VirtualKeyCode::F11;
 
-// Instructions per second
+// Instructions per second, executed in per-frame batches
 const DEFAULT_TICK_RATE: u64 = TICK_RATE_NORMAL;
-// Frames per second
-const DEFAULT_FRAME_RATE: u64 = 60;
+// How far back the rewind buffer reaches
+const DEFAULT_REWIND_SECONDS: u64 = 10;
+pub const MAX_REWIND_SECONDS: u64 = 60 * 60;
+
+// Dropped files with this extension are Octo sources
+const OCTO_EXTENSION: &str = "8o";
+
+const REWIND_KEY: VirtualKeyCode = VirtualKeyCode::Back;
+const RECORD_MOVIE_KEY: VirtualKeyCode = VirtualKeyCode::F9;
+const PLAY_MOVIE_KEY: VirtualKeyCode = VirtualKeyCode::F10;
+
+pub struct Config {
+    // Fixed RNG seed for every loaded rom, random per rom otherwise
+    pub seed: Option<u64>,
+    // Rewinding is disabled with 0
+    pub rewind_seconds: u64,
+    // Takes debugger commands on stdin, see debugger.rs
+    pub debug: bool,
+    // Local port for GDB to attach to, see gdbstub.rs
+    pub gdb_port: Option<u16>,
+    // Traces every instruction of every rom, see trace.rs
+    pub tracer: Option<Tracer>,
+    // Starting speed, the speed keys switch between presets
+    pub pacing: Pacing,
+    // Runs instructions outside of the debugger
+    pub engine: Engine,
+    // Colors for the 2-bit XO-CHIP pixel values, drawn by the frontend
+    pub palette: Palette,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            rewind_seconds: DEFAULT_REWIND_SECONDS,
+            debug: false,
+            gdb_port: None,
+            tracer: None,
+            pacing: Pacing::Rate(DEFAULT_TICK_RATE),
+            engine: Engine::Interpreter,
+            palette: DEFAULT_PALETTE,
+        }
+    }
+}
+
+// The console and GDB share one debugger, so breakpoints set in either apply to both
+struct DebugSession {
+    debugger: Debugger,
+    console: Option<Console>,
+    gdb: Option<GdbServer>,
+}
+
+enum MovieMode {
+    Off,
+    Recording(Movie),
+    Playing { movie: Movie, frame: usize },
+}
+
+// Input and control from the frontend
+pub enum Message {
+    KeyPressed(VirtualKeyCode),
+    KeyReleased(VirtualKeyCode),
+    ModifiersChanged(ModifiersState),
+    // A rom or Octo source dropped onto the window
+    LoadRom(PathBuf),
+    Quit,
+}
+
+// Everything the frontend has to do besides drawing frames
+#[derive(Debug, PartialEq)]
+pub enum Notice {
+    Title(String),
+    // The emulation thread is done, after a Quit message or from the debugger
+    Exit,
+}
+
+// What the frontend needs to draw and play one frame
+pub struct Frame {
+    // Only when the display changed since the last frame
+    pub display: Option<DisplayUpdate>,
+    pub mode: (usize, usize),
+    pub is_beeper_active: bool,
+    pub audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    pub pitch: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DisplayUpdate {
+    // Both planes, the way DisplayState packs them
+    pub packed_state: Vec<u32>,
+    // Words that changed, only these have to be uploaded
+    pub modified: Range<usize>,
+}
 
+// Holds the latest frame, a frontend that falls behind skips frames instead of queueing them
+#[derive(Default)]
+pub struct FrameSlot {
+    frame: Mutex<Option<Frame>>,
+}
+
+// The frontend's side of the emulation thread
+pub struct EmulatorHandle {
+    messages: Sender<Message>,
+    notices: Receiver<Notice>,
+    frames: Arc<FrameSlot>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// Runs the VM on its own thread, so the window can block without stalling it
 pub struct Emulator {
-    renderer: Renderer,
-    beeper: Beeper,
     vm: VM,
     keypad: KeypadState,
     timing: Timing,
+    // The timing's tickrate only applies to Pacing::Rate
+    pacing: Pacing,
+    engine: Engine,
+    quirks: Quirks,
+    seed: Option<u64>,
+    rewind: Rewind,
+    is_rewinding: bool,
+    movie: MovieMode,
+    // Save state slots and movies are stored next to the rom
+    rom_path: Option<PathBuf>,
+    rom: Vec<u8>,
+    modifiers: ModifiersState,
+    displayed_fault: Option<VmFault>,
+    debug: Option<DebugSession>,
+    messages: Receiver<Message>,
+    notices: Sender<Notice>,
+    frames: Arc<FrameSlot>,
+}
+
+impl FrameSlot {
+    pub fn publish(&self, mut frame: Frame) {
+        let mut slot = self.frame.lock().unwrap();
+        // Changes from a skipped frame still have to reach the renderer
+        if let Some(Frame {
+            display: Some(skipped),
+            ..
+        }) = slot.take()
+        {
+            let modified = match &frame.display {
+                Some(update) => {
+                    update.modified.start.min(skipped.modified.start)
+                        ..update.modified.end.max(skipped.modified.end)
+                }
+                None => skipped.modified.clone(),
+            };
+            let packed_state = match frame.display.take() {
+                Some(update) => update.packed_state,
+                None => skipped.packed_state,
+            };
+            frame.display = Some(DisplayUpdate {
+                packed_state,
+                modified,
+            });
+        }
+        *slot = Some(frame);
+    }
+
+    pub fn take(&self) -> Option<Frame> {
+        self.frame.lock().unwrap().take()
+    }
+}
+
+impl EmulatorHandle {
+    pub fn send(&self, message: Message) {
+        // A stopped thread has already sent Notice::Exit
+        let _ = self.messages.send(message);
+    }
+
+    pub fn try_notice(&self) -> Option<Notice> {
+        self.notices.try_recv().ok()
+    }
+
+    pub fn take_frame(&self) -> Option<Frame> {
+        self.frames.take()
+    }
+
+    // Stops the thread and waits for it, so traces are flushed
+    pub fn stop(&mut self) {
+        self.send(Message::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
 
 impl Emulator {
-    pub fn new(window: &Window) -> Self {
-        let renderer = pollster::block_on(Renderer::new(window));
-        let mut beeper = Beeper::new();
-        beeper.start_stream();
-        let vm = VM::new(&[]);
-        let keypad = KeypadState::new();
-        let timing = Timing::new(DEFAULT_TICK_RATE, DEFAULT_FRAME_RATE);
+    // Runs on its own thread until the frontend hangs up or the debugger quits
+    pub fn spawn(mut config: Config) -> EmulatorHandle {
+        let (message_sender, messages) = mpsc::channel();
+        let (notices, notice_receiver) = mpsc::channel();
+        let frames = Arc::new(FrameSlot::default());
+        let published = frames.clone();
+        let thread = thread::Builder::new()
+            .name("emulator".to_string())
+            .spawn(move || {
+                let quirks = Quirks::default();
+                let seed = config.seed;
+                let mut vm = VM::with_seed(&[], quirks, seed.unwrap_or_else(rng::random_seed));
+                vm.set_tracer(config.tracer.take());
+                let keypad = KeypadState::new();
+                let debug = start_debug_session(&config, &mut vm, &keypad);
+                let mut emulator = Self {
+                    vm,
+                    keypad,
+                    timing: Timing::new(DEFAULT_TICK_RATE, FRAME_RATE),
+                    pacing: config.pacing,
+                    engine: config.engine,
+                    quirks,
+                    seed,
+                    rewind: Rewind::new(rewind_frames(config.rewind_seconds)),
+                    is_rewinding: false,
+                    movie: MovieMode::Off,
+                    rom_path: None,
+                    rom: Vec::new(),
+                    modifiers: ModifiersState::empty(),
+                    displayed_fault: None,
+                    debug,
+                    messages,
+                    notices,
+                    frames: published,
+                };
+                emulator.set_pacing(config.pacing);
+                emulator.run();
+            })
+            .expect("Failed to start the emulator thread");
 
-        Self {
-            renderer,
-            beeper,
-            vm,
-            keypad,
-            timing,
-        }
-    }
-
-    pub fn handle_window_event(&mut self, event: WindowEvent) -> Option<ControlFlow> {
-        match event {
-            WindowEvent::CloseRequested => {
-                println!("The close button was pressed; stopping");
-                return Some(ControlFlow::Exit);
-            }
-            WindowEvent::DroppedFile(path_buf) => {
-                let rom = std::fs::read(path_buf.into_os_string().to_str().unwrap()).unwrap();
-                self.vm = VM::new(&rom);
-                self.keypad = KeypadState::new()
-            }
-            WindowEvent::Resized(physical_size) => self.renderer.on_resize(physical_size),
-            WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
-                self.renderer.on_resize(*new_inner_size)
-            }
-            WindowEvent::KeyboardInput {
-                input:
-                    KeyboardInput {
-                        state: element_state,
-                        virtual_keycode: Some(keycode),
-                        ..
-                    },
-                ..
-            } => match element_state {
-                ElementState::Pressed => self.on_key_pressed(keycode),
-                ElementState::Released => self.on_key_released(keycode),
-            },
-            _ => (),
-        };
+        EmulatorHandle {
+            messages: message_sender,
+            notices: notice_receiver,
+            frames,
+            thread: Some(thread),
+        }
+    }
 
-        None
+    fn run(&mut self) {
+        loop {
+            if !self.handle_messages() || !self.update_debugger() {
+                break;
+            }
+
+            // A new rom was loaded after the last one halted
+            if self.displayed_fault.is_some() && !self.vm.is_halted() {
+                self.displayed_fault = None;
+                self.notify(Notice::Title(WINDOW_TITLE.to_string()));
+            }
+
+            // Everything runs off the frame clock: a batch of instructions, then the 60Hz timers
+            if self.timing.should_draw() {
+                self.timing.mark_draw();
+                if self.is_rewinding {
+                    self.rewind_frame();
+                } else if self.is_debugger_paused() {
+                    // Frames keep being drawn, but the VM and its timers stand still
+                } else {
+                    self.update_movie();
+                    self.run_frame();
+                    self.rewind.push(self.vm.save_state());
+                }
+                self.publish_frame();
+            }
+
+            self.timing.try_sleep();
+        }
+        self.notify(Notice::Exit);
+    }
+
+    // False once the frontend asks to quit or is gone
+    fn handle_messages(&mut self) -> bool {
+        loop {
+            match self.messages.try_recv() {
+                Ok(Message::KeyPressed(keycode)) => self.on_key_pressed(keycode),
+                Ok(Message::KeyReleased(keycode)) => self.on_key_released(keycode),
+                Ok(Message::ModifiersChanged(modifiers)) => self.modifiers = modifiers,
+                Ok(Message::LoadRom(path)) => self.load_rom(path),
+                Ok(Message::Quit) | Err(TryRecvError::Disconnected) => return false,
+                Err(TryRecvError::Empty) => return true,
+            }
+        }
+    }
+
+    fn notify(&self, notice: Notice) {
+        // Nobody is left to tell once the frontend is gone
+        let _ = self.notices.send(notice);
+    }
+
+    fn publish_frame(&mut self) {
+        let modified = self.vm.pop_display_modifications().map(|data| {
+            let start = data.offset / size_of::<u32>();
+            start..start + data.data.len()
+        });
+        let display = modified.map(|modified| DisplayUpdate {
+            packed_state: self.vm.get_display().get_packed_state().to_vec(),
+            modified,
+        });
+        self.frames.publish(Frame {
+            display,
+            mode: self.vm.get_display().get_current_mode(),
+            // Rewinding plays back silently
+            is_beeper_active: !self.is_rewinding && self.vm.is_beeper_active(),
+            audio_pattern: self.vm.get_audio_pattern().copied(),
+            pitch: self.vm.get_pitch(),
+        });
+    }
+
+    fn run_frame(&mut self) {
+        match self.pacing {
+            Pacing::Rate(_) => self.run_frame_ticks(),
+            Pacing::VipCycles => self.run_frame_cycles(),
+        }
+        if let Some(fault) = self.vm.get_fault() {
+            self.on_fault(fault);
+        }
+        self.vm.tick_timers();
     }
 
-    pub fn handle_update(&mut self, window: &Window) -> Option<ControlFlow> {
-        if self.timing.should_tick() {
-            self.vm.tick(&self.keypad);
-            self.beeper.set_beeper_active(self.vm.is_beeper_active());
-            self.timing.mark_tick()
+    fn run_frame_ticks(&mut self) {
+        let ticks = self.timing.take_frame_ticks();
+        match &mut self.debug {
+            Some(session) => {
+                let report = session.debugger.run(&mut self.vm, &self.keypad, ticks);
+                if let (Some(report), Some(console)) = (report, &session.console) {
+                    console.reply(&report);
+                }
+            }
+            None => {
+                self.vm.run(self.engine, &self.keypad, ticks);
+            }
         }
+    }
 
-        if self.timing.should_draw() {
-            window.request_redraw();
-            self.timing.mark_draw()
+    // One instruction at a time, so each can be costed
+    fn run_frame_cycles(&mut self) {
+        let keypad = &self.keypad;
+        match &mut self.debug {
+            Some(session) => {
+                self.vm.run_frame_cycles(|vm| {
+                    let report = session.debugger.run(vm, keypad, 1);
+                    if let (Some(report), Some(console)) = (report, &session.console) {
+                        console.reply(&report);
+                    }
+                    !session.debugger.is_paused() && !vm.is_halted()
+                });
+            }
+            None => {
+                self.vm.run_frame_cycles(|vm| vm.tick(keypad).is_ok());
+            }
         }
+    }
 
-        self.timing.try_sleep();
+    // Octo sources are compiled first, failures leave the running rom alone
+    fn load_rom(&mut self, path: PathBuf) {
+        let is_octo = path.extension() == Some(OCTO_EXTENSION.as_ref());
+        let rom = if is_octo {
+            std::fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|source| octo::compile(&source).map_err(|err| err.to_string()))
+        } else {
+            std::fs::read(&path).map_err(|err| err.to_string())
+        };
+        let rom = match rom {
+            Ok(rom) => rom,
+            Err(err) => {
+                eprintln!("Failed to load {}: {}", path.display(), err);
+                self.notify(Notice::Title(format!("{} - {}", WINDOW_TITLE, err)));
+                return;
+            }
+        };
 
-        None
+        self.stop_movie();
+        self.rom = rom;
+        self.rom_path = Some(path);
+        self.restart_rom(self.seed.unwrap_or_else(rng::random_seed));
+        self.notify(Notice::Title(WINDOW_TITLE.to_string()));
     }
 
-    pub fn handle_redraw(&mut self) -> Option<ControlFlow> {
-        if let Some(modification_data) = self.vm.pop_display_modifications() {
-            self.renderer.write_display_modifications(modification_data);
+    // Commands are handled between frames, while paused the VM only changes through them
+    // False once the console quits
+    fn update_debugger(&mut self) -> bool {
+        let session = match self.debug.as_mut() {
+            Some(session) => session,
+            None => return true,
+        };
+        if let Some(console) = &session.console {
+            while let Ok(line) = console.try_read() {
+                match session
+                    .debugger
+                    .handle_line(&mut self.vm, &self.keypad, &line)
+                {
+                    Response::Output(output) => console.reply(&output),
+                    Response::Quit => return false,
+                }
+            }
         }
-        self.renderer.on_redraw();
+        if let Some(gdb) = &mut session.gdb {
+            gdb.poll(&mut session.debugger, &mut self.vm, &self.keypad);
+        }
+        true
+    }
 
-        None
+    fn is_debugger_paused(&self) -> bool {
+        matches!(&self.debug, Some(session) if session.debugger.is_paused())
+    }
+
+    fn restart_rom(&mut self, seed: u64) {
+        self.replace_vm(VM::with_seed(&self.rom, self.quirks, seed));
+        self.keypad = KeypadState::new();
+        self.rewind.clear();
+        self.timing.reset_frame_ticks();
+    }
+
+    // Watchpoints and the trace belong to whoever set them rather than to the machine state
+    fn replace_vm(&mut self, mut vm: VM) {
+        std::mem::swap(vm.watchpoints_mut(), self.vm.watchpoints_mut());
+        vm.set_tracer(self.vm.take_tracer());
+        self.vm = vm;
+    }
+
+    fn rewind_frame(&mut self) {
+        let state = match self.rewind.step_back() {
+            Some(state) => state,
+            None => return,
+        };
+        // Restored displays are marked as modified in full, so the GPU buffer follows along
+        match VM::from_state(state) {
+            Ok(vm) => self.replace_vm(vm),
+            Err(err) => eprintln!("Failed to rewind: {}", err),
+        }
+    }
+
+    fn on_fault(&mut self, fault: VmFault) {
+        // Halted VMs keep returning the same fault, only report it once
+        if self.displayed_fault != Some(fault) {
+            eprintln!("VM halted: {}", fault);
+            self.notify(Notice::Title(format!(
+                "{} - Halted: {}",
+                WINDOW_TITLE, fault
+            )));
+            self.displayed_fault = Some(fault);
+        }
     }
 
     fn on_key_pressed(&mut self, keycode: VirtualKeyCode) {
+        // Slot keys overlap the keypad, the modifier decides
+        if let Some(slot) = map_slot_key(keycode) {
+            if self.modifiers.shift() {
+                return self.save_state(slot);
+            } else if self.modifiers.ctrl() {
+                self.stop_movie();
+                return self.load_state(slot);
+            }
+        }
+
         if let Some(key_idx) = map_key(keycode) {
             self.keypad.state[key_idx as usize] = true;
+        } else if keycode == RECORD_MOVIE_KEY {
+            self.toggle_recording();
+        } else if keycode == PLAY_MOVIE_KEY {
+            self.toggle_playback();
+        } else if self.is_movie_active() {
+            // Anything else would desync the movie from its recorded input
+        } else if keycode == REWIND_KEY {
+            self.is_rewinding = true;
         } else {
-            self.adjust_tickrate(keycode);
+            self.adjust_pacing(keycode);
+            self.adjust_quirks(keycode);
         }
     }
 
     fn on_key_released(&mut self, keycode: VirtualKeyCode) {
         if let Some(key_idx) = map_key(keycode) {
             self.keypad.state[key_idx as usize] = false;
+        } else if keycode == REWIND_KEY {
+            self.is_rewinding = false;
+        }
+    }
+
+    fn adjust_pacing(&mut self, keycode: VirtualKeyCode) {
+        let pacing = match keycode {
+            VirtualKeyCode::F1 => Pacing::Rate(TICK_RATE_MIN),
+            VirtualKeyCode::F2 => Pacing::Rate(TICK_RATE_NORMAL),
+            VirtualKeyCode::F3 => Pacing::Rate(TICK_RATE_FAST),
+            VirtualKeyCode::F4 => Pacing::Rate(TICK_RATE_MAX),
+            VIP_TIMING_KEY => Pacing::VipCycles,
+            _ => return,
+        };
+        self.set_pacing(pacing);
+    }
+
+    // Cycles owed from the last frame don't carry over into another speed
+    fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacing = pacing;
+        if let Pacing::Rate(tickrate) = pacing {
+            self.timing.tickrate = tickrate;
+        }
+        self.vm.reset_cycles();
+    }
+
+    fn adjust_quirks(&mut self, keycode: VirtualKeyCode) {
+        let quirks = match keycode {
+            VirtualKeyCode::F5 => Quirks::cosmac_vip(),
+            VirtualKeyCode::F6 => Quirks::chip48(),
+            VirtualKeyCode::F7 => Quirks::schip(),
+            VirtualKeyCode::F8 => Quirks::xochip(),
+            _ => return,
+        };
+        // Applies to the running rom as well
+        self.quirks = quirks;
+        self.vm.set_quirks(quirks);
+    }
+
+    fn save_state(&mut self, slot: u8) {
+        let path = match self.state_path(slot) {
+            Some(path) => path,
+            None => return,
+        };
+        match std::fs::write(&path, self.vm.save_state()) {
+            Ok(_) => println!("Saved state to {}", path.display()),
+            Err(err) => eprintln!("Failed to save state to {}: {}", path.display(), err),
+        }
+    }
+
+    fn load_state(&mut self, slot: u8) {
+        let path = match self.state_path(slot) {
+            Some(path) => path,
+            None => return,
+        };
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) => return eprintln!("Failed to read state {}: {}", path.display(), err),
+        };
+        match VM::from_state(&data) {
+            Ok(vm) => {
+                // The restored display is marked as modified in full, so the next redraw re-sends it
+                self.quirks = vm.get_quirks();
+                self.replace_vm(vm);
+                self.keypad = KeypadState::new();
+                self.rewind.clear();
+                println!("Loaded state from {}", path.display());
+            }
+            Err(err) => eprintln!("Failed to load state {}: {}", path.display(), err),
+        }
+    }
+
+    fn is_movie_active(&self) -> bool {
+        !matches!(self.movie, MovieMode::Off)
+    }
+
+    fn toggle_recording(&mut self) {
+        if let MovieMode::Recording(_) = self.movie {
+            return self.stop_movie();
+        }
+        self.stop_movie();
+        if self.rom_path.is_none() {
+            return;
+        }
+
+        // Recording always starts from a fresh boot of the rom
+        let header = MovieHeader {
+            rom_hash: movie::rom_hash(&self.rom),
+            quirks: self.quirks,
+            seed: self.seed.unwrap_or_else(rng::random_seed),
+            pacing: self.pacing,
+        };
+        self.restart_rom(header.seed);
+        self.movie = MovieMode::Recording(Movie::new(header));
+        println!("Recording movie");
+    }
+
+    fn toggle_playback(&mut self) {
+        if let MovieMode::Playing { .. } = self.movie {
+            return self.stop_movie();
+        }
+        self.stop_movie();
+        let path = match self.movie_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let movie = match std::fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| Movie::from_bytes(&data).map_err(|err| err.to_string()))
+        {
+            Ok(movie) => movie,
+            Err(err) => return eprintln!("Failed to load movie {}: {}", path.display(), err),
+        };
+
+        let header = *movie.get_header();
+        if header.rom_hash != movie::rom_hash(&self.rom) {
+            return eprintln!("Movie {} was recorded with a different rom", path.display());
+        }
+        self.quirks = header.quirks;
+        self.set_pacing(header.pacing);
+        self.restart_rom(header.seed);
+        println!("Playing movie {} ({} frames)", path.display(), movie.len());
+        self.movie = MovieMode::Playing { movie, frame: 0 };
+    }
+
+    fn stop_movie(&mut self) {
+        match std::mem::replace(&mut self.movie, MovieMode::Off) {
+            MovieMode::Off => (),
+            MovieMode::Recording(movie) => {
+                if let Some(path) = self.movie_path() {
+                    match std::fs::write(&path, movie.to_bytes()) {
+                        Ok(_) => println!("Saved movie to {}", path.display()),
+                        Err(err) => {
+                            eprintln!("Failed to save movie to {}: {}", path.display(), err)
+                        }
+                    }
+                }
+            }
+            MovieMode::Playing { .. } => {
+                // Hand control back to the keyboard
+                self.keypad = KeypadState::new();
+                println!("Movie playback stopped");
+            }
         }
     }
 
-    fn adjust_tickrate(&mut self, keycode: VirtualKeyCode) {
-        match keycode {
-            VirtualKeyCode::F1 => self.timing.tickrate = TICK_RATE_MIN,
-            VirtualKeyCode::F2 => self.timing.tickrate = TICK_RATE_NORMAL,
-            VirtualKeyCode::F3 => self.timing.tickrate = TICK_RATE_FAST,
-            VirtualKeyCode::F4 => self.timing.tickrate = TICK_RATE_MAX,
-            _ => (),
+    // Records the input of the coming frame, or replaces it with the recorded one
+    fn update_movie(&mut self) {
+        match &mut self.movie {
+            MovieMode::Off => (),
+            MovieMode::Recording(movie) => movie.record_frame(&self.keypad),
+            MovieMode::Playing { movie, frame } => match movie.get_frame(*frame) {
+                Some(keypad) => {
+                    self.keypad = keypad;
+                    *frame += 1;
+                }
+                None => self.stop_movie(),
+            },
         }
     }
+
+    fn state_path(&self, slot: u8) -> Option<PathBuf> {
+        self.rom_sibling_path(&format!(".state{}", slot))
+    }
+
+    fn movie_path(&self) -> Option<PathBuf> {
+        self.rom_sibling_path(".c8m")
+    }
+
+    fn rom_sibling_path(&self, suffix: &str) -> Option<PathBuf> {
+        let rom_path = self.rom_path.as_ref()?;
+        let mut path = rom_path.clone().into_os_string();
+        path.push(suffix);
+        Some(path.into())
+    }
+}
+
+// Frames the rewind buffer holds, lengths that don't fit disable it
+fn rewind_frames(seconds: u64) -> usize {
+    seconds
+        .checked_mul(FRAME_RATE)
+        .and_then(|frames| usize::try_from(frames).ok())
+        .unwrap_or(0)
+}
+
+// The console starts out paused, GDB alone leaves the VM running until it attaches
+fn start_debug_session(config: &Config, vm: &mut VM, keypad: &KeypadState) -> Option<DebugSession> {
+    if !config.debug && config.gdb_port.is_none() {
+        return None;
+    }
+
+    let mut debugger = Debugger::new();
+    let gdb = config
+        .gdb_port
+        .and_then(|port| match GdbServer::bind((gdbstub::LOCALHOST, port)) {
+            Ok(server) => {
+                println!("Waiting for GDB on {}:{}", gdbstub::LOCALHOST, port);
+                Some(server)
+            }
+            Err(err) => {
+                eprintln!("Failed to listen for GDB on port {}: {}", port, err);
+                None
+            }
+        });
+    let console = if config.debug {
+        let console = Console::new();
+        console.reply("Debugger is paused, 'continue' to run and 'help' for commands");
+        Some(console)
+    } else {
+        debugger.execute(vm, keypad, Command::Continue);
+        None
+    };
+
+    Some(DebugSession {
+        debugger,
+        console,
+        gdb,
+    })
+}
+
+fn map_slot_key(keycode: VirtualKeyCode) -> Option<u8> {
+    match keycode {
+        VirtualKeyCode::Key0 => Some(0),
+        VirtualKeyCode::Key1 => Some(1),
+        VirtualKeyCode::Key2 => Some(2),
+        VirtualKeyCode::Key3 => Some(3),
+        VirtualKeyCode::Key4 => Some(4),
+        VirtualKeyCode::Key5 => Some(5),
+        VirtualKeyCode::Key6 => Some(6),
+        VirtualKeyCode::Key7 => Some(7),
+        VirtualKeyCode::Key8 => Some(8),
+        VirtualKeyCode::Key9 => Some(9),
+        _ => None,
+    }
 }
 
 fn map_key(scancode: VirtualKeyCode) -> Option<u8> {
@@ -151,3 +769,67 @@ fn map_key(scancode: VirtualKeyCode) -> Option<u8> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{rewind_frames, Config, DisplayUpdate, Emulator, Frame, FrameSlot, Notice};
+    use std::time::{Duration, Instant};
+
+    fn frame(display: Option<DisplayUpdate>, pitch: u8) -> Frame {
+        Frame {
+            display,
+            mode: (64, 32),
+            is_beeper_active: false,
+            audio_pattern: None,
+            pitch,
+        }
+    }
+
+    #[test]
+    fn rewind_frames_test() {
+        assert_eq!(rewind_frames(10), 600);
+        assert_eq!(rewind_frames(0), 0);
+        assert_eq!(rewind_frames(u64::MAX), 0);
+    }
+
+    #[test]
+    fn frame_slot_test() {
+        let update = |value, modified| DisplayUpdate {
+            packed_state: vec![value; 8],
+            modified,
+        };
+        let slot = FrameSlot::default();
+        slot.publish(frame(Some(update(1, 2..4)), 1));
+        slot.publish(frame(None, 2));
+        // The newest frame wins, but keeps the display changes it skipped over
+        let latest = slot.take().expect("Published frame");
+        assert_eq!(latest.pitch, 2);
+        assert_eq!(latest.display, Some(update(1, 2..4)));
+        assert!(slot.take().is_none());
+
+        slot.publish(frame(Some(update(1, 2..4)), 1));
+        slot.publish(frame(Some(update(2, 6..7)), 2));
+        assert_eq!(slot.take().unwrap().display, Some(update(2, 2..7)));
+    }
+
+    #[test]
+    fn thread_test() {
+        let mut emulator = Emulator::spawn(Config::default());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let first = loop {
+            if let Some(frame) = emulator.take_frame() {
+                break frame;
+            }
+            assert!(Instant::now() < deadline, "No frame published");
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        // The first frame is cleared from the top left
+        let display = first.display.expect("Display update");
+        assert_eq!(display.modified.start, 0);
+        assert!(display.modified.end > 0);
+        assert_eq!(first.mode, (64, 32));
+
+        emulator.stop();
+        assert_eq!(emulator.try_notice(), Some(Notice::Exit));
+    }
+}
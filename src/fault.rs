@@ -0,0 +1,41 @@
+use std::fmt;
+
+// Errors caused by the guest program, each halts the VM at the faulting instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmFault {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    StackOverflow { pc: u16 },
+    StackUnderflow { pc: u16 },
+    AddressOutOfRange { pc: u16, address: usize },
+    PcMisaligned { pc: u16 },
+}
+
+impl VmFault {
+    pub fn get_pc(&self) -> u16 {
+        match *self {
+            VmFault::UnknownOpcode { pc, .. }
+            | VmFault::StackOverflow { pc }
+            | VmFault::StackUnderflow { pc }
+            | VmFault::AddressOutOfRange { pc, .. }
+            | VmFault::PcMisaligned { pc } => pc,
+        }
+    }
+}
+
+impl fmt::Display for VmFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            VmFault::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown opcode {:#06x} at {:#06x}", opcode, pc)
+            }
+            VmFault::StackOverflow { pc } => write!(f, "Stack overflow at {:#06x}", pc),
+            VmFault::StackUnderflow { pc } => write!(f, "Stack underflow at {:#06x}", pc),
+            VmFault::AddressOutOfRange { pc, address } => {
+                write!(f, "Address {:#x} out of range at {:#06x}", address, pc)
+            }
+            VmFault::PcMisaligned { pc } => write!(f, "Misaligned program counter {:#06x}", pc),
+        }
+    }
+}
+
+impl std::error::Error for VmFault {}
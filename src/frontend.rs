@@ -0,0 +1,108 @@
+// The window side of the emulator: input goes to the emulation thread, frames come back
+
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use winit::{
+    event::{ElementState, KeyboardInput, WindowEvent},
+    event_loop::ControlFlow,
+    window::Window,
+};
+
+use crate::{
+    beeper::Beeper,
+    display::ModificationData,
+    emulator::{Config, Emulator, EmulatorHandle, Message, Notice},
+    renderer::Renderer,
+    timing::FRAME_RATE,
+};
+
+pub struct Frontend {
+    renderer: Renderer,
+    beeper: Beeper,
+    emulator: EmulatorHandle,
+}
+
+impl Frontend {
+    pub fn new(window: &Window, config: Config) -> Self {
+        let renderer = pollster::block_on(Renderer::new(window, config.palette));
+        let mut beeper = Beeper::new();
+        beeper.start_stream();
+
+        Self {
+            renderer,
+            beeper,
+            emulator: Emulator::spawn(config),
+        }
+    }
+
+    pub fn handle_window_event(&mut self, event: WindowEvent) -> Option<ControlFlow> {
+        match event {
+            WindowEvent::CloseRequested => {
+                println!("The close button was pressed; stopping");
+                self.emulator.stop();
+                return Some(ControlFlow::Exit);
+            }
+            WindowEvent::DroppedFile(path_buf) => self.emulator.send(Message::LoadRom(path_buf)),
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.emulator.send(Message::ModifiersChanged(modifiers))
+            }
+            WindowEvent::Resized(physical_size) => self.renderer.on_resize(physical_size),
+            WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                self.renderer.on_resize(*new_inner_size)
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: element_state,
+                        virtual_keycode: Some(keycode),
+                        ..
+                    },
+                ..
+            } => self.emulator.send(match element_state {
+                ElementState::Pressed => Message::KeyPressed(keycode),
+                ElementState::Released => Message::KeyReleased(keycode),
+            }),
+            _ => (),
+        };
+
+        None
+    }
+
+    // Runs after every batch of window events, and otherwise wakes up once a frame to pick up
+    // the latest one from the emulation thread
+    pub fn handle_update(&mut self, window: &Window) -> Option<ControlFlow> {
+        while let Some(notice) = self.emulator.try_notice() {
+            match notice {
+                Notice::Title(title) => window.set_title(&title),
+                Notice::Exit => {
+                    self.emulator.stop();
+                    return Some(ControlFlow::Exit);
+                }
+            }
+        }
+
+        if let Some(frame) = self.emulator.take_frame() {
+            if let Some(update) = &frame.display {
+                self.renderer.write_display_modifications(ModificationData {
+                    offset: update.modified.start * size_of::<u32>(),
+                    data: &update.packed_state[update.modified.clone()],
+                    mode: frame.mode,
+                });
+            }
+            self.beeper.set_beeper_active(frame.is_beeper_active);
+            self.beeper
+                .set_audio_pattern(frame.audio_pattern.as_ref(), frame.pitch);
+            window.request_redraw();
+        }
+
+        let frame = Duration::from_secs(1) / FRAME_RATE as u32;
+        Some(ControlFlow::WaitUntil(Instant::now() + frame))
+    }
+
+    pub fn handle_redraw(&mut self) -> Option<ControlFlow> {
+        self.renderer.on_redraw();
+
+        None
+    }
+}
@@ -0,0 +1,505 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::debugger::{Command, Debugger};
+use crate::fault::VmFault;
+use crate::keypad::KeypadState;
+use crate::vm::{REGISTER_NUM, VM};
+
+// GDB remote serial protocol over TCP, so existing debuggers and scripts can attach to the VM.
+// Execution control goes through a Debugger, the stub only translates packets. Registers are
+// numbered and laid out as
+//   0-15  V0-VF  1 byte each
+//   16    I      2 bytes
+//   17    PC     2 bytes
+//   18    SP     1 byte, the stack depth, read only
+//   19    DT     1 byte
+//   20    ST     1 byte
+// with 2 byte values in big endian, the same as words in CHIP-8 memory.
+
+// Only reachable from this machine, the protocol has no authentication
+pub const LOCALHOST: &str = "127.0.0.1";
+
+const REGISTER_I: usize = REGISTER_NUM;
+const REGISTER_PC: usize = REGISTER_NUM + 1;
+const REGISTER_SP: usize = REGISTER_NUM + 2;
+const REGISTER_DT: usize = REGISTER_NUM + 3;
+const REGISTER_ST: usize = REGISTER_NUM + 4;
+const REGISTER_COUNT: usize = REGISTER_NUM + 5;
+
+// Largest packet the client may send, told to it in qSupported
+const PACKET_SIZE: usize = 0x1000;
+// Sent outside of packets to stop a running target (Ctrl-C)
+const INTERRUPT: u8 = 0x03;
+const READ_BUFFER_SIZE: usize = 0x400;
+
+// Stop reasons, as POSIX signal numbers
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const ERROR_REPLY: &str = "E01";
+
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<Client>,
+}
+
+impl GdbServer {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            client: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.client.is_some()
+    }
+
+    // Accepts a client and handles whatever it sent, never blocks. Call it between frames
+    pub fn poll(&mut self, debugger: &mut Debugger, vm: &mut VM, keypad: &KeypadState) {
+        if self.client.is_none() {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(_) => return,
+            };
+            if stream.set_nonblocking(true).is_err() {
+                return;
+            }
+            let _ = stream.set_nodelay(true);
+            // GDB expects the target to be stopped once attached
+            debugger.execute(vm, keypad, Command::Pause);
+            self.client = Some(Client::new(stream));
+        }
+
+        if let Some(client) = &mut self.client {
+            if !matches!(client.poll(debugger, vm, keypad), Ok(true)) {
+                self.client = None;
+                // Left running on its own, like any detached target
+                debugger.execute(vm, keypad, Command::Continue);
+            }
+        }
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    input: Vec<u8>,
+    // The stop reply for a continue is sent once the debugger pauses again
+    is_running: bool,
+    signal: u8,
+    is_closing: bool,
+}
+
+impl Client {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            input: Vec::new(),
+            is_running: false,
+            signal: SIGTRAP,
+            is_closing: false,
+        }
+    }
+
+    // Returns false once the client detached
+    fn poll(
+        &mut self,
+        debugger: &mut Debugger,
+        vm: &mut VM,
+        keypad: &KeypadState,
+    ) -> io::Result<bool> {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(len) => self.input.extend_from_slice(&buffer[..len]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        while let Some(packet) = self.next_packet()? {
+            if let Some(reply) = self.handle_packet(&packet, debugger, vm, keypad) {
+                self.send_packet(&reply)?;
+            }
+            if self.is_closing {
+                return Ok(false);
+            }
+        }
+
+        if self.is_running && debugger.is_paused() {
+            self.is_running = false;
+            let reply = stop_reply(vm, self.signal);
+            self.send_packet(&reply)?;
+        }
+        Ok(true)
+    }
+
+    // Packets are $data#checksum, acknowledged with + (or - to ask for it again).
+    // Acknowledgements from the client aren't needed, since replies are never resent
+    fn next_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let start = match self
+                .input
+                .iter()
+                .position(|byte| *byte == b'$' || *byte == INTERRUPT)
+            {
+                Some(start) => start,
+                None => {
+                    self.input.clear();
+                    return Ok(None);
+                }
+            };
+            if self.input[start] == INTERRUPT {
+                self.input.drain(..=start);
+                return Ok(Some(char::from(INTERRUPT).to_string()));
+            }
+
+            self.input.drain(..start);
+            let end = match self.input.iter().position(|byte| *byte == b'#') {
+                Some(end) if self.input.len() >= end + 3 => end,
+                _ => return Ok(None),
+            };
+            let data = self.input[1..end].to_vec();
+            let checksum = std::str::from_utf8(&self.input[end + 1..end + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            self.input.drain(..end + 3);
+
+            if checksum != Some(checksum_of(&data)) {
+                self.send(b"-")?;
+                continue;
+            }
+            self.send(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn handle_packet(
+        &mut self,
+        packet: &str,
+        debugger: &mut Debugger,
+        vm: &mut VM,
+        keypad: &KeypadState,
+    ) -> Option<String> {
+        let kind = packet.chars().next()?;
+        let args = &packet[kind.len_utf8()..];
+        let reply = match kind {
+            '\x03' => {
+                if !debugger.is_paused() {
+                    debugger.execute(vm, keypad, Command::Pause);
+                    self.signal = SIGINT;
+                }
+                return None;
+            }
+            '?' => stop_reply(vm, self.signal),
+            'g' => (0..REGISTER_COUNT)
+                .map(|idx| to_hex(&read_register(vm, idx).unwrap_or_default()))
+                .collect(),
+            'G' => ok_or_error(write_registers(vm, args)),
+            'p' => usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|idx| read_register(vm, idx))
+                .map_or_else(|| ERROR_REPLY.to_string(), |bytes| to_hex(&bytes)),
+            'P' => ok_or_error(args.split_once('=').and_then(|(idx, value)| {
+                write_register(vm, usize::from_str_radix(idx, 16).ok()?, &from_hex(value)?)
+            })),
+            'm' => parse_range(args)
+                .map(|(address, len)| {
+                    let bytes: Vec<u8> = (0..len)
+                        .map(|offset| vm.read_memory((address + offset) as u16))
+                        .collect();
+                    to_hex(&bytes)
+                })
+                .unwrap_or_else(|| ERROR_REPLY.to_string()),
+            'M' => ok_or_error(args.split_once(':').and_then(|(range, data)| {
+                let (address, len) = parse_range(range)?;
+                let bytes = from_hex(data).filter(|bytes| bytes.len() == len)?;
+                for (offset, byte) in bytes.iter().enumerate() {
+                    vm.write_memory((address + offset) as u16, *byte);
+                }
+                Some(())
+            })),
+            // Software and hardware breakpoints are the same thing here
+            'Z' | 'z' => {
+                let mut fields = args.split(',');
+                match (fields.next(), fields.next().and_then(parse_address)) {
+                    (Some("0"), Some(address)) | (Some("1"), Some(address)) => {
+                        let command = if kind == 'Z' {
+                            Command::Break(address)
+                        } else {
+                            Command::Delete(Some(address))
+                        };
+                        debugger.execute(vm, keypad, command);
+                        "OK".to_string()
+                    }
+                    (Some("0"), None) | (Some("1"), None) => ERROR_REPLY.to_string(),
+                    _ => String::new(),
+                }
+            }
+            's' | 'c' => {
+                if !args.is_empty() {
+                    match parse_address(args) {
+                        Some(address) => vm.set_program_counter(address),
+                        None => return Some(ERROR_REPLY.to_string()),
+                    }
+                }
+                self.signal = SIGTRAP;
+                if kind == 's' {
+                    debugger.execute(vm, keypad, Command::Step(1));
+                    stop_reply(vm, self.signal)
+                } else {
+                    debugger.execute(vm, keypad, Command::Continue);
+                    self.is_running = true;
+                    return None;
+                }
+            }
+            'D' => {
+                self.is_closing = true;
+                "OK".to_string()
+            }
+            'k' => {
+                self.is_closing = true;
+                return None;
+            }
+            // There's only the one thread
+            'H' | 'T' => "OK".to_string(),
+            'q' => match args.split(':').next().unwrap_or_default() {
+                "Supported" => format!("PacketSize={:x}", PACKET_SIZE),
+                "Attached" => "1".to_string(),
+                "C" => "QC1".to_string(),
+                "fThreadInfo" => "m1".to_string(),
+                "sThreadInfo" => "l".to_string(),
+                _ => String::new(),
+            },
+            // An empty reply tells the client a packet isn't supported
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.send(packet.as_bytes())
+    }
+
+    // Replies are small, so the socket is made blocking just for the write
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let result = self.stream.write_all(data);
+        self.stream.set_nonblocking(true)?;
+        result
+    }
+}
+
+fn stop_reply(vm: &VM, signal: u8) -> String {
+    let signal = match vm.get_fault() {
+        Some(VmFault::UnknownOpcode { .. }) | Some(VmFault::PcMisaligned { .. }) => SIGILL,
+        Some(_) => SIGSEGV,
+        None => signal,
+    };
+    format!("S{:02x}", signal)
+}
+
+fn register_size(idx: usize) -> Option<usize> {
+    match idx {
+        REGISTER_I | REGISTER_PC => Some(2),
+        _ if idx < REGISTER_COUNT => Some(1),
+        _ => None,
+    }
+}
+
+fn read_register(vm: &VM, idx: usize) -> Option<Vec<u8>> {
+    let bytes = match idx {
+        REGISTER_I => vm.get_index_register().to_be_bytes().to_vec(),
+        REGISTER_PC => vm.get_program_counter().to_be_bytes().to_vec(),
+        REGISTER_SP => vec![vm.get_stack().len() as u8],
+        REGISTER_DT => vec![vm.get_delay_timer()],
+        REGISTER_ST => vec![vm.get_sound_timer()],
+        _ => vec![*vm.get_registers().get(idx)?],
+    };
+    Some(bytes)
+}
+
+fn write_register(vm: &mut VM, idx: usize, bytes: &[u8]) -> Option<()> {
+    if register_size(idx)? != bytes.len() {
+        return None;
+    }
+    let word = || u16::from_be_bytes([bytes[0], bytes[1]]);
+    match idx {
+        REGISTER_I => vm.set_index_register(word()),
+        REGISTER_PC => vm.set_program_counter(word()),
+        // The stack depth only changes through CALL and RET
+        REGISTER_SP if bytes[0] as usize == vm.get_stack().len() => (),
+        REGISTER_SP => return None,
+        REGISTER_DT => vm.set_delay_timer(bytes[0]),
+        REGISTER_ST => vm.set_sound_timer(bytes[0]),
+        _ => vm.set_register(idx, bytes[0]),
+    }
+    Some(())
+}
+
+fn write_registers(vm: &mut VM, hex: &str) -> Option<()> {
+    let bytes = from_hex(hex)?;
+    let mut offset = 0;
+    for idx in 0..REGISTER_COUNT {
+        let size = register_size(idx)?;
+        write_register(vm, idx, bytes.get(offset..offset + size)?)?;
+        offset += size;
+    }
+    Some(())
+}
+
+// ADDR,LENGTH in hex, staying inside the address space
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let (address, len) = args.split_once(',')?;
+    let address = usize::from_str_radix(address, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    match address.checked_add(len) {
+        Some(end) if end <= u16::MAX as usize + 1 && len <= PACKET_SIZE / 2 => Some((address, len)),
+        _ => None,
+    }
+}
+
+fn parse_address(hex: &str) -> Option<u16> {
+    u16::from_str_radix(hex, 16).ok()
+}
+
+fn ok_or_error(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => ERROR_REPLY.to_string(),
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Odd lengths fail on the last byte
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum_of, GdbServer};
+    use crate::{debugger::Debugger, keypad::KeypadState, quirks::Quirks, vm::VM};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn read_packet(stream: &mut TcpStream) -> String {
+        let mut packet = Vec::new();
+        let mut byte = [0];
+        // Skips acknowledgements
+        while packet.is_empty() || !packet.ends_with(b"#") {
+            stream.read_exact(&mut byte).expect("Reply");
+            if !packet.is_empty() || byte[0] == b'$' {
+                packet.push(byte[0]);
+            }
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).expect("Checksum");
+        let data = &packet[1..packet.len() - 1];
+        let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16);
+        assert_eq!(checksum, Ok(checksum_of(data)));
+        String::from_utf8(data.to_vec()).unwrap()
+    }
+
+    fn send_packet(stream: &mut TcpStream, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        stream.write_all(packet.as_bytes()).expect("Request");
+    }
+
+    fn command(stream: &mut TcpStream, data: &str) -> String {
+        send_packet(stream, data);
+        read_packet(stream)
+    }
+
+    #[test]
+    fn loopback_test() {
+        let rom = [
+            0x70, 0x01, // 0x200 ADD V0, 0x01
+            0x12, 0x00, // 0x202 JP 0x200
+        ];
+        let mut vm = VM::with_seed(&rom, Quirks::default(), 0);
+        let keypad = KeypadState::new();
+        let mut debugger = Debugger::new();
+        let mut server = GdbServer::bind("127.0.0.1:0").expect("Loopback socket");
+        let address = server.local_addr().expect("Bound address");
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).expect("Connection");
+            stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+            assert_eq!(
+                command(&mut stream, "qSupported:swbreak+"),
+                "PacketSize=1000"
+            );
+            assert_eq!(command(&mut stream, "?"), "S05");
+            let registers = command(&mut stream, "g");
+            assert_eq!(registers.len(), (16 + 2 + 2 + 3) * 2);
+            assert_eq!(&registers[32..40], "00000200");
+
+            assert_eq!(command(&mut stream, "P3=2a"), "OK");
+            assert_eq!(command(&mut stream, "p3"), "2a");
+            assert_eq!(command(&mut stream, "P12=01"), "E01");
+            assert_eq!(command(&mut stream, "M300,2:abcd"), "OK");
+            assert_eq!(command(&mut stream, "m2ff,4"), "00abcd00");
+            assert_eq!(command(&mut stream, "mffffffffffffffff,1"), "E01");
+
+            // Runs until the breakpoint on the jump
+            assert_eq!(command(&mut stream, "Z0,202,2"), "OK");
+            assert_eq!(command(&mut stream, "c"), "S05");
+            assert_eq!(command(&mut stream, "p11"), "0202");
+            assert_eq!(command(&mut stream, "s"), "S05");
+            assert_eq!(command(&mut stream, "p11"), "0200");
+            assert_eq!(command(&mut stream, "p0"), "01");
+
+            // Without breakpoints it only stops when interrupted
+            assert_eq!(command(&mut stream, "z0,202,2"), "OK");
+            send_packet(&mut stream, "c");
+            thread::sleep(Duration::from_millis(20));
+            stream.write_all(&[0x03]).unwrap();
+            assert_eq!(read_packet(&mut stream), "S02");
+
+            assert_eq!(command(&mut stream, "vMustReplyEmpty"), "");
+            assert_eq!(command(&mut stream, "D"), "OK");
+        });
+
+        // Same as the frame loop: handle packets, then run a batch of instructions
+        let start = Instant::now();
+        while !client.is_finished() && start.elapsed() < TIMEOUT {
+            server.poll(&mut debugger, &mut vm, &keypad);
+            debugger.run(&mut vm, &keypad, 10);
+            thread::sleep(Duration::from_millis(1));
+        }
+        client.join().expect("Client checks passed");
+
+        // Detaching lets the VM run again
+        server.poll(&mut debugger, &mut vm, &keypad);
+        assert!(!server.is_attached());
+        assert!(!debugger.is_paused());
+    }
+}
@@ -0,0 +1,406 @@
+// Windowless execution, for running roms on machines without a display or GPU
+
+use crate::display::DisplayState;
+use crate::fault::VmFault;
+use crate::keypad::KeypadState;
+use crate::savestate::crc32;
+use crate::timing::{Pacing, Timing, FRAME_RATE};
+use crate::vm::{Engine, VM};
+
+// Gray levels for the 2-bit XO-CHIP pixel values, matching the default palette
+const PNG_LEVELS: [u8; 4] = [0x00, 0xFF, 0x80, 0xC0];
+// Largest payload of a stored (uncompressed) deflate block
+const DEFLATE_BLOCK_SIZE: usize = 0xFFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Frames(u64),
+    Instructions(u64),
+}
+
+// Holds a key down from a frame, for a number of frames
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyPress {
+    pub frame: u64,
+    pub key: u8,
+    pub frames: u64,
+}
+
+impl KeyPress {
+    fn is_held(&self, frame: u64) -> bool {
+        frame >= self.frame && frame - self.frame < self.frames
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RunSummary {
+    pub frames: u64,
+    pub instructions: u64,
+    pub fault: Option<VmFault>,
+}
+
+// FRAME:KEY[:FRAMES], the key is a hex digit and held for one frame by default
+pub fn parse_key_press(spec: &str) -> Result<KeyPress, String> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err(format!("Expected FRAME:KEY[:FRAMES], got '{}'", spec));
+    }
+    make_key_press(&parts)
+}
+
+// One key press per line as FRAME KEY [FRAMES], # starts a comment
+pub fn parse_script(script: &str) -> Result<Vec<KeyPress>, String> {
+    let mut presses = Vec::new();
+    for (line_idx, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() {
+            continue;
+        }
+        if parts.len() > 3 {
+            return Err(format!(
+                "Line {}: expected FRAME KEY [FRAMES]",
+                line_idx + 1
+            ));
+        }
+        let press =
+            make_key_press(&parts).map_err(|err| format!("Line {}: {}", line_idx + 1, err))?;
+        presses.push(press);
+    }
+    Ok(presses)
+}
+
+fn make_key_press(parts: &[&str]) -> Result<KeyPress, String> {
+    let frame = parts[0]
+        .parse()
+        .map_err(|_| format!("Invalid frame '{}'", parts[0]))?;
+    let key = u8::from_str_radix(parts[1], 16)
+        .ok()
+        .filter(|key| *key < 0x10)
+        .ok_or(format!("Invalid key '{}'", parts[1]))?;
+    let frames = match parts.get(2) {
+        Some(frames) => frames
+            .parse()
+            .map_err(|_| format!("Invalid frame count '{}'", frames))?,
+        None => 1,
+    };
+    Ok(KeyPress { frame, key, frames })
+}
+
+pub fn keypad_at(presses: &[KeyPress], frame: u64) -> KeypadState {
+    let mut keypad = KeypadState::new();
+    for press in presses.iter().filter(|press| press.is_held(frame)) {
+        keypad.state[press.key as usize] = true;
+    }
+    keypad
+}
+
+// Same frame loop as the windowed emulator: a batch of instructions, then the timers
+// VIP cycles are always interpreted
+pub fn run(
+    vm: &mut VM,
+    engine: Engine,
+    pacing: Pacing,
+    limit: Limit,
+    presses: &[KeyPress],
+) -> RunSummary {
+    let tickrate = match pacing {
+        Pacing::Rate(tickrate) => tickrate,
+        Pacing::VipCycles => return run_cycles(vm, limit, presses),
+    };
+    let mut timing = Timing::new(tickrate, FRAME_RATE);
+    let mut summary = RunSummary {
+        frames: 0,
+        instructions: 0,
+        fault: None,
+    };
+
+    loop {
+        match limit {
+            Limit::Frames(frames) if summary.frames >= frames => break,
+            Limit::Instructions(instructions) if summary.instructions >= instructions => break,
+            _ => (),
+        }
+
+        let keypad = keypad_at(presses, summary.frames);
+        let frame_ticks = timing.take_frame_ticks();
+        let ticks = match limit {
+            Limit::Instructions(instructions) => {
+                frame_ticks.min(instructions - summary.instructions)
+            }
+            Limit::Frames(_) => frame_ticks,
+        };
+        summary.instructions += vm.run(engine, &keypad, ticks);
+        if let Some(fault) = vm.get_fault() {
+            summary.fault = Some(fault);
+            return summary;
+        }
+        if ticks < frame_ticks {
+            // Stopped mid-frame, so the frame doesn't count
+            return summary;
+        }
+        vm.tick_timers();
+        summary.frames += 1;
+    }
+
+    summary
+}
+
+fn run_cycles(vm: &mut VM, limit: Limit, presses: &[KeyPress]) -> RunSummary {
+    let mut summary = RunSummary {
+        frames: 0,
+        instructions: 0,
+        fault: None,
+    };
+
+    loop {
+        match limit {
+            Limit::Frames(frames) if summary.frames >= frames => break,
+            Limit::Instructions(instructions) if summary.instructions >= instructions => break,
+            _ => (),
+        }
+
+        let keypad = keypad_at(presses, summary.frames);
+        let mut is_stopped = false;
+        vm.run_frame_cycles(|vm| {
+            if matches!(limit, Limit::Instructions(instructions) if summary.instructions >= instructions)
+            {
+                is_stopped = true;
+                return false;
+            }
+            match vm.tick(&keypad) {
+                Ok(()) => summary.instructions += 1,
+                Err(fault) => summary.fault = Some(fault),
+            }
+            summary.fault.is_none()
+        });
+        // Stopped mid-frame, so the frame doesn't count
+        if is_stopped || summary.fault.is_some() {
+            return summary;
+        }
+        vm.tick_timers();
+        summary.frames += 1;
+    }
+
+    summary
+}
+
+// Binary PBM, a pixel is set when any plane is
+pub fn display_to_pbm(display: &DisplayState) -> Vec<u8> {
+    let (width, height) = display.get_current_mode();
+    let mut data = format!("P4\n{} {}\n", width, height).into_bytes();
+    for y in 0..height {
+        for byte_x in (0..width).step_by(8) {
+            let byte = (0..8).fold(0u8, |byte, bit| {
+                let set = display.get_pixel(byte_x + bit, y) != 0;
+                byte | (set as u8) << (7 - bit)
+            });
+            data.push(byte);
+        }
+    }
+    data
+}
+
+// 8-bit grayscale PNG, deflate is used in stored mode so no compression library is needed
+pub fn display_to_png(display: &DisplayState) -> Vec<u8> {
+    let (width, height) = display.get_current_mode();
+
+    let mut scanlines = Vec::with_capacity((width + 1) * height);
+    for y in 0..height {
+        // Filter type none
+        scanlines.push(0);
+        scanlines.extend((0..width).map(|x| PNG_LEVELS[display.get_pixel(x, y) as usize]));
+    }
+
+    // zlib header without a preset dictionary, checked with (0x78 << 8 | 0x01) % 31 == 0
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = scanlines.chunks(DEFLATE_BLOCK_SIZE).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&scanlines).to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, grayscale, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    write_png_chunk(&mut png, b"IHDR", &header);
+    write_png_chunk(&mut png, b"IDAT", &zlib);
+    write_png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let checked_start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[checked_start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+pub fn vm_to_json(vm: &VM, summary: &RunSummary) -> String {
+    let registers: Vec<String> = vm.get_registers().iter().map(u8::to_string).collect();
+    let fault = match summary.fault {
+        Some(fault) => format!("\"{}\"", fault),
+        None => "null".to_string(),
+    };
+    format!(
+        concat!(
+            "{{\n",
+            "  \"frames\": {},\n",
+            "  \"instructions\": {},\n",
+            "  \"pc\": {},\n",
+            "  \"i\": {},\n",
+            "  \"v\": [{}],\n",
+            "  \"delay_timer\": {},\n",
+            "  \"sound_timer\": {},\n",
+            "  \"fault\": {}\n",
+            "}}\n"
+        ),
+        summary.frames,
+        summary.instructions,
+        vm.get_program_counter(),
+        vm.get_index_register(),
+        registers.join(", "),
+        vm.get_delay_timer(),
+        vm.get_sound_timer(),
+        fault
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        adler32, display_to_pbm, display_to_png, keypad_at, parse_key_press, parse_script, run,
+        KeyPress, Limit,
+    };
+    use crate::{
+        quirks::Quirks,
+        savestate::crc32,
+        timing::Pacing,
+        vm::{Engine, VM},
+    };
+
+    #[test]
+    fn script_test() {
+        assert_eq!(
+            parse_key_press("10:a:5"),
+            Ok(KeyPress {
+                frame: 10,
+                key: 0xA,
+                frames: 5
+            })
+        );
+        assert!(parse_key_press("10:10").is_err());
+        assert!(parse_key_press("10").is_err());
+
+        let presses = parse_script("# start\n0 5\n\n30 F 2 # hold F\n").expect("Valid script");
+        assert_eq!(presses.len(), 2);
+        assert!(keypad_at(&presses, 0).state[0x5]);
+        assert!(!keypad_at(&presses, 1).state[0x5]);
+        assert!(keypad_at(&presses, 31).state[0xF]);
+        assert!(!keypad_at(&presses, 32).state[0xF]);
+
+        assert_eq!(
+            parse_script("0 1\n2 X\n"),
+            Err("Line 2: Invalid key 'X'".to_string())
+        );
+    }
+
+    #[test]
+    fn run_test() {
+        // 7001 - ADD V0, 0x01, 1200 - JP 0x200
+        let rom = [0x70, 0x01, 0x12, 0x00];
+        let mut vm = VM::with_seed(&rom, Quirks::default(), 0);
+        let summary = run(
+            &mut vm,
+            Engine::Interpreter,
+            Pacing::Rate(600),
+            Limit::Frames(3),
+            &[],
+        );
+        assert_eq!(summary.frames, 3);
+        assert_eq!(summary.instructions, 30);
+        assert_eq!(vm.get_registers()[0x0], 15);
+
+        let mut vm = VM::with_seed(&rom, Quirks::default(), 0);
+        let summary = run(
+            &mut vm,
+            Engine::Interpreter,
+            Pacing::Rate(600),
+            Limit::Instructions(25),
+            &[],
+        );
+        assert_eq!(summary.frames, 2);
+        assert_eq!(summary.instructions, 25);
+
+        // 50 and 52 cycles per instruction, so 25 pairs and an ADD fit into a VIP frame
+        let mut vm = VM::with_seed(&rom, Quirks::cosmac_vip(), 0);
+        let summary = run(
+            &mut vm,
+            Engine::Interpreter,
+            Pacing::VipCycles,
+            Limit::Frames(1),
+            &[],
+        );
+        assert_eq!(summary.instructions, 51);
+        assert_eq!(vm.get_registers()[0x0], 26);
+
+        // FFFF isn't an instruction
+        let mut vm = VM::with_seed(&[0xFF, 0xFF], Quirks::default(), 0);
+        let summary = run(
+            &mut vm,
+            Engine::Interpreter,
+            Pacing::Rate(600),
+            Limit::Frames(3),
+            &[],
+        );
+        assert!(summary.fault.is_some());
+        assert_eq!(summary.instructions, 0);
+    }
+
+    #[test]
+    fn image_test() {
+        // A050 - LD I, 0x050 (font sprite 0), D011 - DRW V0, V1, 1 draws its top row, 0xF0
+        let rom = [0xA0, 0x50, 0xD0, 0x11];
+        let mut vm = VM::with_seed(&rom, Quirks::default(), 0);
+        run(
+            &mut vm,
+            Engine::Interpreter,
+            Pacing::Rate(120),
+            Limit::Instructions(2),
+            &[],
+        );
+
+        let pbm = display_to_pbm(vm.get_display());
+        let header = b"P4\n64 32\n";
+        assert_eq!(&pbm[..header.len()], header);
+        assert_eq!(pbm.len(), header.len() + 8 * 32);
+        assert_eq!(pbm[header.len()], 0xF0);
+        assert_eq!(pbm[header.len() + 1], 0x00);
+
+        let png = display_to_png(vm.get_display());
+        assert_eq!(&png[1..4], b"PNG");
+        assert_eq!(&png[12..16], b"IHDR");
+        let ihdr_crc = u32::from_be_bytes([png[29], png[30], png[31], png[32]]);
+        assert_eq!(ihdr_crc, crc32(&png[12..29]));
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
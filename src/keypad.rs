@@ -11,4 +11,26 @@ impl KeypadState {
             state: [false; KEYPAD_SIZE],
         }
     }
+
+    // Key n is bit n
+    pub fn to_bits(&self) -> u16 {
+        self.state
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (key, pressed)| bits | (*pressed as u16) << key)
+    }
+
+    pub fn from_bits(bits: u16) -> Self {
+        let mut keypad = Self::new();
+        for (key, pressed) in keypad.state.iter_mut().enumerate() {
+            *pressed = bits & (1 << key) != 0;
+        }
+        keypad
+    }
+}
+
+impl Default for KeypadState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
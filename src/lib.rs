@@ -1,9 +1,27 @@
+pub mod assembler;
 pub mod beeper;
+pub mod cli;
+pub mod cycles;
+pub mod debugger;
+pub mod disassembler;
 pub mod display;
 pub mod emulator;
+pub mod fault;
+pub mod frontend;
+pub mod gdbstub;
+pub mod headless;
 pub mod keypad;
 pub mod memory;
+pub mod movie;
+pub mod octo;
 pub mod opcode;
+pub mod profiler;
+pub mod quirks;
 pub mod renderer;
+pub mod rewind;
+pub mod rng;
+pub mod savestate;
 pub mod timing;
+pub mod trace;
 pub mod vm;
+pub mod watchpoint;
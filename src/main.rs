@@ -1,25 +1,46 @@
-use c8rust::emulator::Emulator;
+use std::convert::TryFrom;
+
+use c8rust::cli::parse_number;
+use c8rust::emulator::{Config, MAX_REWIND_SECONDS, WINDOW_TITLE};
+use c8rust::frontend::Frontend;
+use c8rust::renderer;
+use c8rust::timing::Pacing;
+use c8rust::trace::{self, TraceFilter, Tracer};
+use c8rust::vm::Engine;
 use winit::{dpi::LogicalSize, event::Event, event_loop::EventLoop, window::WindowBuilder};
 
+const USAGE: &str = "Usage: c8rust [--seed <number>] [--rewind <seconds>] [--debug] [--gdb <port>]
+              [--tickrate <instructions per second or vip>] [--engine <interpreter or blocks>]
+              [--palette <4 RRGGBB colors, comma separated>]
+              [--trace <file or -> [--trace-range <start-end>] [--trace-limit <lines>]]";
+
 fn main() {
     env_logger::init();
 
+    let config = match parse_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            std::process::exit(2);
+        }
+    };
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
-        .with_title("CHIP-8 Emulator")
+        .with_title(WINDOW_TITLE)
         .with_inner_size(LogicalSize::new(1080, 540))
         .build(&event_loop)
         .unwrap();
 
-    let mut emulator = Emulator::new(&window);
+    let mut frontend = Frontend::new(&window, config);
 
     event_loop.run(move |event, _, control_flow| {
         let flow_change = match event {
             Event::WindowEvent {
                 window_id, event, ..
-            } if window_id == window.id() => emulator.handle_window_event(event),
-            Event::MainEventsCleared => emulator.handle_update(&window),
-            Event::RedrawRequested(_) => emulator.handle_redraw(),
+            } if window_id == window.id() => frontend.handle_window_event(event),
+            Event::MainEventsCleared => frontend.handle_update(&window),
+            Event::RedrawRequested(_) => frontend.handle_redraw(),
             _ => None,
         };
 
@@ -28,3 +49,46 @@ fn main() {
         }
     })
 }
+
+// Numbers are decimal or 0x prefixed hex
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut trace_path = None;
+    let mut trace_filter = TraceFilter::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--seed" => config.seed = Some(parse_number(&value()?)?),
+            "--rewind" => {
+                let seconds = parse_number(&value()?)?;
+                if seconds > MAX_REWIND_SECONDS {
+                    return Err(format!(
+                        "Rewind is limited to {} seconds",
+                        MAX_REWIND_SECONDS
+                    ));
+                }
+                config.rewind_seconds = seconds;
+            }
+            "--debug" => config.debug = true,
+            "--gdb" => {
+                let port = parse_number(&value()?)?;
+                let port = u16::try_from(port).map_err(|_| format!("Invalid port {}", port))?;
+                config.gdb_port = Some(port);
+            }
+            "--tickrate" => config.pacing = Pacing::parse(&value()?)?,
+            "--engine" => config.engine = Engine::from_name(&value()?)?,
+            "--palette" => config.palette = renderer::parse_palette(&value()?)?,
+            "--trace" => trace_path = Some(value()?),
+            "--trace-range" => trace_filter.range = Some(trace::parse_range(&value()?)?),
+            "--trace-limit" => trace_filter.limit = Some(parse_number(&value()?)?),
+            _ => return Err(format!("Unknown argument '{}'", arg)),
+        }
+    }
+
+    if let Some(path) = trace_path {
+        let tracer = Tracer::create(&path, trace_filter)
+            .map_err(|err| format!("Failed to create {}: {}", path, err))?;
+        config.tracer = Some(tracer);
+    }
+    Ok(config)
+}
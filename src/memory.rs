@@ -1,22 +1,38 @@
-// Memory region sizes
-const MEM_SIZE: usize = 4096;
+use crate::opcode::OpCode;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::watchpoint::{Access, Watchpoints};
+
+// Memory region sizes (XO-CHIP extends the address space to 64 KiB)
+pub const MEM_SIZE: usize = 0x10000;
 #[allow(dead_code)] // I'll leave those for now
 const MEM_SIZE_INT: usize = 0x1FF;
 const MEM_SIZE_FONT: usize = 0x50;
+const MEM_SIZE_FONT_LARGE: usize = 0xA0;
 #[allow(dead_code)]
-const MEM_SIZE_RAM: usize = 0xDFF;
+const MEM_SIZE_RAM: usize = 0xFDFF;
 
 // Memory region starting addresses
 #[allow(dead_code)]
 const MEM_REGION_INT: u16 = 0x000;
 const MEM_REGION_FONT: u16 = 0x050;
-const MEM_REGION_RAM: u16 = 0x200;
+const MEM_REGION_FONT_LARGE: u16 = 0x0A0;
+// Roms are loaded here
+pub const MEM_REGION_RAM: u16 = 0x200;
 
 // Stack
 pub const STACK_SIZE: usize = 16;
 
 pub struct Memory {
     memory: [u8; MEM_SIZE],
+    // Not part of the machine state, so not saved either
+    watchpoints: Watchpoints,
+    // Marks every byte the guest reads while a profiler is attached, empty otherwise
+    data_reads: Vec<bool>,
+    // Fetched instructions by address, dropped when either of their bytes is written
+    // Empty when the cache is off
+    decoded: Vec<Option<OpCode>>,
+    // Bumped by every write over a decoded instruction, so compiled code knows it's stale
+    code_generation: u64,
 }
 
 pub struct Stack {
@@ -28,6 +44,10 @@ impl Memory {
     pub fn new() -> Self {
         Self {
             memory: [0; MEM_SIZE],
+            watchpoints: Watchpoints::new(),
+            data_reads: Vec::new(),
+            decoded: vec![None; MEM_SIZE],
+            code_generation: 0,
         }
     }
 
@@ -40,10 +60,20 @@ impl Memory {
         MEM_REGION_FONT
     }
 
+    pub fn load_large_font(&mut self, fontset: &[u8]) -> u16 {
+        self.write_region(
+            MEM_REGION_FONT_LARGE as usize,
+            MEM_REGION_FONT_LARGE as usize + MEM_SIZE_FONT_LARGE,
+            fontset,
+        );
+        MEM_REGION_FONT_LARGE
+    }
+
     pub fn load_rom(&mut self, rom_data: &[u8]) -> u16 {
-        // Roms are stored BE
-        for (i, data) in rom_data.iter().enumerate() {
-            self.memory[MEM_REGION_RAM as usize + i] = u8::from_be(*data);
+        // Roms are stored BE, anything that doesn't fit is dropped
+        let max_len = MEM_SIZE - MEM_REGION_RAM as usize;
+        for (i, data) in rom_data.iter().take(max_len).enumerate() {
+            self.poke8(MEM_REGION_RAM as usize + i, u8::from_be(*data));
         }
 
         MEM_REGION_RAM
@@ -53,20 +83,134 @@ impl Memory {
         MEM_REGION_FONT + (5 * sprite_id) as u16
     }
 
+    pub fn get_large_font_sprite_location(&self, sprite_id: usize) -> u16 {
+        MEM_REGION_FONT_LARGE + (10 * sprite_id) as u16
+    }
+
+    // Accessors below panic out of range, so guest controlled addresses should be checked first
+    pub fn is_valid_range(&self, address: usize, len: usize) -> bool {
+        matches!(address.checked_add(len), Some(end) if end <= MEM_SIZE)
+    }
+
+    // Guest accesses go through set8, get8, get16 and fetch16, which are watched
     pub fn set8(&mut self, address: usize, value: u8) {
-        self.memory[address] = value;
+        self.poke8(address, value);
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.check(Access::Write, address, value);
+        }
+    }
+
+    pub fn get8(&mut self, address: usize) -> u8 {
+        let value = self.memory[address];
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.check(Access::Read, address, value);
+        }
+        if let Some(is_read) = self.data_reads.get_mut(address) {
+            *is_read = true;
+        }
+        value
     }
 
-    pub fn get8(&self, address: usize) -> u8 {
+    pub fn get16(&mut self, address: usize) -> u16 {
+        (self.get8(address) as u16) << 8 | self.get8(address + 1) as u16
+    }
+
+    // Only the first byte counts as executed, None for words that aren't instructions
+    #[inline]
+    pub fn fetch_opcode(&mut self, address: usize) -> Option<OpCode> {
+        if !self.watchpoints.is_empty() {
+            self.watchpoints
+                .check(Access::Execute, address, self.memory[address]);
+        }
+        self.decode(address)
+    }
+
+    // Like fetch_opcode, without counting as an execute
+    #[inline]
+    pub fn decode(&mut self, address: usize) -> Option<OpCode> {
+        if let Some(Some(opcode)) = self.decoded.get(address) {
+            return Some(*opcode);
+        }
+        let opcode = OpCode::from_bytes(self.peek16(address))?;
+        if let Some(entry) = self.decoded.get_mut(address) {
+            *entry = Some(opcode);
+        }
+        Some(opcode)
+    }
+
+    pub fn code_generation(&self) -> u64 {
+        self.code_generation
+    }
+
+    pub fn set_decode_cache(&mut self, is_enabled: bool) {
+        // Instructions decoded so far aren't tracked anymore
+        self.code_generation = self.code_generation.wrapping_add(1);
+        self.decoded = if is_enabled {
+            vec![None; MEM_SIZE]
+        } else {
+            Vec::new()
+        };
+    }
+
+    // Tools and lookahead use the peek and poke accessors, which aren't watched
+    pub fn peek8(&self, address: usize) -> u8 {
         self.memory[address]
     }
 
-    pub fn get16(&self, address: usize) -> u16 {
+    pub fn peek16(&self, address: usize) -> u16 {
         (self.memory[address] as u16) << 8 | self.memory[address + 1] as u16
     }
 
+    pub fn poke8(&mut self, address: usize, value: u8) {
+        self.memory[address] = value;
+        self.invalidate(address, address + 1);
+    }
+
+    pub fn data_reads_mut(&mut self) -> &mut Vec<bool> {
+        &mut self.data_reads
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.memory);
+    }
+
+    pub fn read_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        let mut memory = Self::new();
+        memory.memory.copy_from_slice(reader.read_bytes(MEM_SIZE)?);
+        Ok(memory)
+    }
+
     fn write_region(&mut self, start: usize, end: usize, data: &[u8]) {
         self.memory[start..end].copy_from_slice(data);
+        self.invalidate(start, end);
+    }
+
+    // Instructions starting on the byte before the range cover its first byte as well
+    #[inline]
+    fn invalidate(&mut self, start: usize, end: usize) {
+        // Without the cache any write could be to code
+        let mut is_code = self.decoded.is_empty();
+        let end = end.min(self.decoded.len());
+        for entry in self.decoded[start.saturating_sub(1).min(end)..end].iter_mut() {
+            is_code |= entry.take().is_some();
+        }
+        if is_code {
+            self.code_generation = self.code_generation.wrapping_add(1);
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -78,13 +222,51 @@ impl Stack {
         }
     }
 
-    pub fn push(&mut self, value: u16) {
+    // Returns false when the stack is full
+    pub fn push(&mut self, value: u16) -> bool {
+        if self.stack_pointer == STACK_SIZE {
+            return false;
+        }
         self.stack[self.stack_pointer] = value;
-        self.stack_pointer += 1
+        self.stack_pointer += 1;
+        true
     }
 
-    pub fn pop(&mut self) -> u16 {
+    pub fn pop(&mut self) -> Option<u16> {
+        if self.stack_pointer == 0 {
+            return None;
+        }
         self.stack_pointer -= 1;
-        self.stack[self.stack_pointer]
+        Some(self.stack[self.stack_pointer])
+    }
+
+    // Bottom to top, only the entries in use
+    pub fn as_slice(&self) -> &[u16] {
+        &self.stack[..self.stack_pointer]
+    }
+
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        for value in self.stack.iter() {
+            writer.write_u16(*value);
+        }
+        writer.write_u8(self.stack_pointer as u8);
+    }
+
+    pub fn read_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        let mut stack = Self::new();
+        for value in stack.stack.iter_mut() {
+            *value = reader.read_u16()?;
+        }
+        stack.stack_pointer = reader.read_u8()? as usize;
+        if stack.stack_pointer > STACK_SIZE {
+            return Err(SaveStateError::InvalidValue("stack pointer"));
+        }
+        Ok(stack)
+    }
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -0,0 +1,135 @@
+use crate::keypad::KeypadState;
+use crate::quirks::Quirks;
+use crate::savestate::{crc32, SaveStateError, StateReader, StateWriter};
+use crate::timing::Pacing;
+
+// Movies share the save state container (see savestate.rs), the payload is
+//   rom hash  u32   CRC-32 of the rom
+//   quirks
+//   seed      u64   RNG seed the VM starts with
+//   pacing    u8    0 for a fixed rate, followed by the instructions per second as a u64,
+//                   which decides the instructions per frame, 1 for COSMAC VIP cycles
+//   frames    u32   count, followed by one u16 keypad bitmask per frame
+pub const MOVIE_VERSION: u16 = 2;
+
+const MAGIC: [u8; 4] = *b"C8MV";
+
+// Everything needed to start the VM exactly as it was when recording began
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovieHeader {
+    pub rom_hash: u32,
+    pub quirks: Quirks,
+    pub seed: u64,
+    pub pacing: Pacing,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Movie {
+    header: MovieHeader,
+    frames: Vec<u16>,
+}
+
+impl Movie {
+    pub fn new(header: MovieHeader) -> Self {
+        Self {
+            header,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn get_header(&self) -> &MovieHeader {
+        &self.header
+    }
+
+    pub fn record_frame(&mut self, keypad: &KeypadState) {
+        self.frames.push(keypad.to_bits());
+    }
+
+    pub fn get_frame(&self, frame: usize) -> Option<KeypadState> {
+        self.frames
+            .get(frame)
+            .map(|bits| KeypadState::from_bits(*bits))
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_u32(self.header.rom_hash);
+        self.header.quirks.write_state(&mut writer);
+        writer.write_u64(self.header.seed);
+        self.header.pacing.write_state(&mut writer);
+        writer.write_u32(self.frames.len() as u32);
+        for bits in self.frames.iter() {
+            writer.write_u16(*bits);
+        }
+        writer.finish_as(MAGIC, MOVIE_VERSION)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SaveStateError> {
+        let mut reader = StateReader::new_as(data, MAGIC, MOVIE_VERSION)?;
+        let header = MovieHeader {
+            rom_hash: reader.read_u32()?,
+            quirks: Quirks::read_state(&mut reader)?,
+            seed: reader.read_u64()?,
+            pacing: Pacing::read_state(&mut reader)?,
+        };
+        let frame_num = reader.read_u32()?;
+        let frames = (0..frame_num)
+            .map(|_| reader.read_u16())
+            .collect::<Result<_, _>>()?;
+        reader.finish()?;
+
+        Ok(Self { header, frames })
+    }
+}
+
+pub fn rom_hash(rom: &[u8]) -> u32 {
+    crc32(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rom_hash, Movie, MovieHeader};
+    use crate::{keypad::KeypadState, quirks::Quirks, savestate::SaveStateError, timing::Pacing};
+
+    #[test]
+    fn movie_round_trip_test() {
+        let mut movie = Movie::new(MovieHeader {
+            rom_hash: rom_hash(&[0x12, 0x00]),
+            quirks: Quirks::cosmac_vip(),
+            seed: 0xC8,
+            pacing: Pacing::Rate(700),
+        });
+        let mut keypad = KeypadState::new();
+        movie.record_frame(&keypad);
+        keypad.state[0x3] = true;
+        keypad.state[0xF] = true;
+        movie.record_frame(&keypad);
+
+        let data = movie.to_bytes();
+        let loaded = Movie::from_bytes(&data).expect("Valid movie");
+        assert_eq!(loaded, movie);
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get_frame(1).map(|k| k.state), Some(keypad.state));
+        assert!(loaded.get_frame(2).is_none());
+
+        let vip = Movie::new(MovieHeader {
+            pacing: Pacing::VipCycles,
+            ..*movie.get_header()
+        });
+        assert_eq!(Movie::from_bytes(&vip.to_bytes()), Ok(vip));
+
+        // Save states aren't movies
+        assert_eq!(
+            Movie::from_bytes(&crate::vm::VM::new(&[], Quirks::default()).save_state()),
+            Err(SaveStateError::BadMagic)
+        );
+    }
+}
@@ -0,0 +1,1076 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::memory::{MEM_REGION_RAM, MEM_SIZE};
+use crate::opcode::OpCode;
+
+// Compiler for Octo, the structured assembly language most modern CHIP-8 games are written in,
+// see https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md
+//
+// Like the original, compilation stops at the first error and names can be used before their
+// label is defined everywhere an address is expected (jump, call, i := and :unpack).
+
+// Guards against macros that expand into themselves
+const MAX_MACRO_EXPANSIONS: usize = 100_000;
+const ADDR_MAX: i64 = 0xFFF;
+const LONG_ADDR_MAX: i64 = 0xFFFF;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OctoError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for OctoError {}
+
+pub fn compile(source: &str) -> Result<Vec<u8>, OctoError> {
+    let mut compiler = Compiler::new(source);
+    compiler.compile()?;
+    Ok(compiler.memory[MEM_REGION_RAM as usize..compiler.rom_end].to_vec())
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+// Open blocks, with the placeholder jumps that get patched once their end is known
+enum Block {
+    Loop { start: u16, breaks: Vec<u16> },
+    If { jump: u16 },
+    Else { jump: u16 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FixupKind {
+    // Low 12 bits of an instruction word
+    Address,
+    // A whole word, after i := long
+    Long,
+    // Low nibble of a byte, as in :unpack
+    HighNibble,
+    HighByte,
+    LowByte,
+}
+
+struct Fixup {
+    addr: u16,
+    kind: FixupKind,
+    name: String,
+    line: usize,
+}
+
+enum Target {
+    Known(i64),
+    Label(String),
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    // Line of the last token taken, errors point there
+    line: usize,
+    memory: Vec<u8>,
+    here: usize,
+    rom_end: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<(Block, usize)>,
+    expansions: usize,
+}
+
+impl Compiler {
+    fn new(source: &str) -> Self {
+        let tokens = source
+            .lines()
+            .enumerate()
+            .flat_map(|(line_idx, line)| {
+                let code = line.split('#').next().unwrap_or_default();
+                code.split_whitespace().map(move |text| Token {
+                    text: text.to_string(),
+                    line: line_idx + 1,
+                })
+            })
+            .collect();
+
+        Self {
+            tokens,
+            line: 1,
+            memory: vec![0; MEM_SIZE],
+            here: MEM_REGION_RAM as usize,
+            rom_end: MEM_REGION_RAM as usize,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn compile(&mut self) -> Result<(), OctoError> {
+        // Execution starts with a jump to main, dropped again if main comes first
+        self.emit_address_op(OpCode::JP, Target::Label("main".to_string()))?;
+
+        while let Some(token) = self.next() {
+            self.statement(&token.text)?;
+        }
+
+        if let Some((block, line)) = self.blocks.pop() {
+            self.line = line;
+            return match block {
+                Block::Loop { .. } => self.error("This 'loop' has no matching 'again'"),
+                Block::If { .. } | Block::Else { .. } => {
+                    self.error("This 'begin' has no matching 'end'")
+                }
+            };
+        }
+        if !self.labels.contains_key("main") {
+            self.line = 1;
+            return self.error("This program has no 'main' label");
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            let target = match self.labels.get(&fixup.name) {
+                Some(target) => *target as i64,
+                None => return self.error(format!("Undefined name '{}'", fixup.name)),
+            };
+            self.patch(fixup.addr, fixup.kind, target)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: &str) -> Result<(), OctoError> {
+        match token {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(name)?;
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                self.define_constant(name, value)?;
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc_body()?;
+                self.define_constant(name, value)?;
+            }
+            ":macro" => self.define_macro()?,
+            ":unpack" => self.unpack()?,
+            ":org" => {
+                let addr = self.value()? as i64;
+                if addr < MEM_REGION_RAM as i64 || addr >= MEM_SIZE as i64 {
+                    return self.error(format!("Can't :org to {:#X}", addr));
+                }
+                self.here = addr as usize;
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(&[byte])?;
+            }
+            ":call" => {
+                let target = self.target()?;
+                self.emit_address_op(OpCode::CALL, target)?;
+            }
+            // Debugger hints, only meaningful to the Octo IDE
+            ":breakpoint" | ":proto" => {
+                self.name()?;
+            }
+            ":monitor" => {
+                self.take()?;
+                self.take()?;
+            }
+            "clear" => self.emit_op(OpCode::CLS())?,
+            "return" | ";" => self.emit_op(OpCode::RET())?,
+            "hires" => self.emit_op(OpCode::HIGH())?,
+            "lores" => self.emit_op(OpCode::LOW())?,
+            "exit" => self.emit_op(OpCode::EXIT())?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit_op(OpCode::SCDN(n))?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit_op(OpCode::SCUN(n))?;
+            }
+            "scroll-right" => self.emit_op(OpCode::SCR())?,
+            "scroll-left" => self.emit_op(OpCode::SCL())?,
+            "audio" => self.emit_op(OpCode::AUDIO())?,
+            // Masks above 3 only select planes that don't exist, like the assembler they're kept
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit_op(OpCode::PLANEN(n))?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.emit_op(OpCode::LDBV(x))?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let opcode = if self.peek_is("-") {
+                    self.take()?;
+                    let y = self.register()?;
+                    if token == "save" {
+                        OpCode::LDIVV(x, y)
+                    } else {
+                        OpCode::LDVVI(x, y)
+                    }
+                } else if token == "save" {
+                    OpCode::LDIV(x)
+                } else {
+                    OpCode::LDVI(x)
+                };
+                self.emit_op(opcode)?;
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit_op(OpCode::LDRV(x))?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit_op(OpCode::LDVR(x))?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit_op(OpCode::DRWVVN(x, y, n))?;
+            }
+            "jump" => {
+                let target = self.target()?;
+                self.emit_address_op(OpCode::JP, target)?;
+            }
+            "jump0" => {
+                let target = self.target()?;
+                self.emit_address_op(OpCode::JPVA, target)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit_op(match token {
+                    "delay" => OpCode::LDDTV(x),
+                    "buzzer" => OpCode::LDSTV(x),
+                    _ => OpCode::LDPV(x),
+                })?;
+            }
+            "i" => self.index_statement()?,
+            "loop" => {
+                let start = self.here as u16;
+                self.open_block(Block::Loop {
+                    start,
+                    breaks: Vec::new(),
+                });
+            }
+            "while" => {
+                let (skip_if_true, _) = self.condition()?;
+                self.emit_op(skip_if_true)?;
+                let jump = self.emit_placeholder_jump()?;
+                match self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|(block, _)| matches!(block, Block::Loop { .. }))
+                {
+                    Some((Block::Loop { breaks, .. }, _)) => breaks.push(jump),
+                    _ => return self.error("'while' is only allowed inside a loop"),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop { start, breaks }, _)) => {
+                    self.emit_address_op(OpCode::JP, Target::Known(start as i64))?;
+                    for jump in breaks {
+                        self.patch(jump, FixupKind::Address, self.here as i64)?;
+                    }
+                }
+                _ => return self.error("'again' without a matching 'loop'"),
+            },
+            "if" => {
+                let (skip_if_true, skip_if_false) = self.condition()?;
+                match self.take()?.text.as_str() {
+                    // Only the next statement is conditional
+                    "then" => self.emit_op(skip_if_false)?,
+                    "begin" => {
+                        self.emit_op(skip_if_true)?;
+                        let jump = self.emit_placeholder_jump()?;
+                        self.open_block(Block::If { jump });
+                    }
+                    other => {
+                        return self.error(format!("Expected 'then' or 'begin', got '{}'", other))
+                    }
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some((Block::If { jump }, _)) => {
+                    let end_jump = self.emit_placeholder_jump()?;
+                    self.patch(jump, FixupKind::Address, self.here as i64)?;
+                    self.open_block(Block::Else { jump: end_jump });
+                }
+                _ => return self.error("'else' without a matching 'begin'"),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::If { jump }, _)) | Some((Block::Else { jump }, _)) => {
+                    self.patch(jump, FixupKind::Address, self.here as i64)?;
+                }
+                _ => return self.error("'end' without a matching 'begin'"),
+            },
+            _ => {
+                if let Some(x) = self.lookup_register(token) {
+                    self.register_statement(x)?;
+                } else if self.macros.contains_key(token) {
+                    self.expand_macro(token)?;
+                } else if let Some(value) = parse_number(token) {
+                    let byte = self.check_byte(value)?;
+                    self.emit(&[byte])?;
+                } else if is_name(token) {
+                    // Anything else is a subroutine call
+                    let target = self.resolve(token)?;
+                    self.emit_address_op(OpCode::CALL, target)?;
+                } else {
+                    return self.error(format!("Unexpected '{}'", token));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn index_statement(&mut self) -> Result<(), OctoError> {
+        let operator = self.take()?.text;
+        match operator.as_str() {
+            ":=" if self.peek_is("long") => {
+                self.take()?;
+                let target = self.target()?;
+                self.emit_op(OpCode::LDIL())?;
+                let addr = self.here as u16;
+                self.emit(&[0, 0])?;
+                self.resolve_target(addr, FixupKind::Long, target)
+            }
+            ":=" if self.peek_is("hex") || self.peek_is("bighex") => {
+                let is_big = self.take()?.text == "bighex";
+                let x = self.register()?;
+                self.emit_op(if is_big {
+                    OpCode::LDHFV(x)
+                } else {
+                    OpCode::LDFV(x)
+                })
+            }
+            ":=" => {
+                let target = self.target()?;
+                self.emit_address_op(OpCode::LDIA, target)
+            }
+            "+=" => {
+                let x = self.register()?;
+                self.emit_op(OpCode::ADDIV(x))
+            }
+            _ => self.error(format!("Unknown operator 'i {}'", operator)),
+        }
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), OctoError> {
+        let operator = self.take()?.text;
+        let source = self.take()?;
+        let y = self.lookup_register(&source.text);
+        let opcode = match (operator.as_str(), y) {
+            (":=", _) if source.text == "key" => OpCode::LDVK(x),
+            (":=", _) if source.text == "delay" => OpCode::LDVDT(x),
+            (":=", _) if source.text == "random" => OpCode::RNDVB(x, self.byte()?),
+            (":=", Some(y)) => OpCode::LDVV(x, y),
+            ("+=", Some(y)) => OpCode::ADDVV(x, y),
+            ("-=", Some(y)) => OpCode::SUBVV(x, y),
+            ("=-", Some(y)) => OpCode::SUBNVV(x, y),
+            ("|=", Some(y)) => OpCode::ORVV(x, y),
+            ("&=", Some(y)) => OpCode::ANDVV(x, y),
+            ("^=", Some(y)) => OpCode::XORVV(x, y),
+            (">>=", Some(y)) => OpCode::SHRVV(x, y),
+            ("<<=", Some(y)) => OpCode::SHLVV(x, y),
+            (":=", None) | ("+=", None) | ("-=", None) => {
+                self.tokens.push_front(source);
+                let byte = self.byte()?;
+                match operator.as_str() {
+                    ":=" => OpCode::LDVB(x, byte),
+                    "+=" => OpCode::ADDVB(x, byte),
+                    // There's no subtraction of a constant, but adding its negation wraps around the same
+                    _ => OpCode::ADDVB(x, byte.wrapping_neg()),
+                }
+            }
+            _ => return self.error(format!("Unknown operator '{} {}'", operator, source.text)),
+        };
+        self.emit_op(opcode)
+    }
+
+    // Returns instructions that skip the next one when the condition is true, and when it's false.
+    // Comparisons are computed in vf first, the same as Octo does
+    fn condition(&mut self) -> Result<(OpCode, OpCode), OctoError> {
+        let x = self.register()?;
+        let operator = self.take()?.text;
+        match operator.as_str() {
+            "key" => return Ok((OpCode::SKPV(x), OpCode::SKNPV(x))),
+            "-key" => return Ok((OpCode::SKNPV(x), OpCode::SKPV(x))),
+            _ => (),
+        }
+
+        let source = self.take()?;
+        let y = self.lookup_register(&source.text);
+        if y.is_none() {
+            self.tokens.push_front(source);
+        }
+        let byte = match y {
+            Some(_) => 0,
+            None => self.byte()?,
+        };
+        let equal = |x: u8| match y {
+            Some(y) => (OpCode::SEVV(x, y), OpCode::SNEVV(x, y)),
+            None => (OpCode::SEVB(x, byte), OpCode::SNEVB(x, byte)),
+        };
+
+        let (subtract_from_x, is_strict) = match operator.as_str() {
+            "==" => return Ok(equal(x)),
+            "!=" => {
+                let (skip_if_equal, skip_if_not_equal) = equal(x);
+                return Ok((skip_if_not_equal, skip_if_equal));
+            }
+            "<" => (true, true),
+            ">=" => (true, false),
+            ">" => (false, true),
+            "<=" => (false, false),
+            _ => return self.error(format!("Unknown comparison '{}'", operator)),
+        };
+
+        // vf ends up 1 when no borrow happened, so x < y exactly when vf = x - y leaves vf at 0
+        const VF: u8 = 0xF;
+        match (subtract_from_x, y) {
+            (true, Some(y)) => {
+                self.emit_op(OpCode::LDVV(VF, x))?;
+                self.emit_op(OpCode::SUBVV(VF, y))?;
+            }
+            (true, None) => {
+                self.emit_op(OpCode::LDVB(VF, byte))?;
+                self.emit_op(OpCode::SUBNVV(VF, x))?;
+            }
+            (false, Some(y)) => {
+                self.emit_op(OpCode::LDVV(VF, y))?;
+                self.emit_op(OpCode::SUBVV(VF, x))?;
+            }
+            (false, None) => {
+                self.emit_op(OpCode::LDVB(VF, byte))?;
+                self.emit_op(OpCode::SUBVV(VF, x))?;
+            }
+        }
+        let flag = if is_strict { 0 } else { 1 };
+        Ok((OpCode::SEVB(VF, flag), OpCode::SNEVB(VF, flag)))
+    }
+
+    // :unpack n label loads v0 with n in the high nibble and the high address nibble,
+    // :unpack long label loads v0 with the high address byte, v1 gets the low byte either way
+    fn unpack(&mut self) -> Result<(), OctoError> {
+        let (high_kind, high) = if self.peek_is("long") {
+            self.take()?;
+            (FixupKind::HighByte, 0)
+        } else {
+            (FixupKind::HighNibble, self.nibble()? << 4)
+        };
+        let target = self.target()?;
+        let (high_target, low_target) = match target {
+            Target::Known(value) => (Target::Known(value), Target::Known(value)),
+            Target::Label(name) => (Target::Label(name.clone()), Target::Label(name)),
+        };
+
+        self.emit_op(OpCode::LDVB(0x0, high))?;
+        self.resolve_target(self.here as u16 - 1, high_kind, high_target)?;
+        self.emit_op(OpCode::LDVB(0x1, 0))?;
+        self.resolve_target(self.here as u16 - 1, FixupKind::LowByte, low_target)
+    }
+
+    fn define_label(&mut self, name: String) -> Result<(), OctoError> {
+        // Main right at the start makes the jump to it pointless
+        if name == "main" && self.here == MEM_REGION_RAM as usize + 2 && self.rom_end == self.here {
+            self.fixups.retain(|fixup| fixup.addr != MEM_REGION_RAM);
+            self.here = MEM_REGION_RAM as usize;
+            self.rom_end = MEM_REGION_RAM as usize;
+        }
+        self.check_unused(&name)?;
+        self.labels.insert(name, self.here as u16);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: String, value: f64) -> Result<(), OctoError> {
+        if self.labels.contains_key(&name) {
+            return self.error(format!("The name '{}' is already a label", name));
+        }
+        self.constants.insert(name, value);
+        Ok(())
+    }
+
+    fn define_macro(&mut self) -> Result<(), OctoError> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.take()?;
+            if token.text == "{" {
+                break;
+            }
+            params.push(token.text);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.take()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => (),
+            }
+            body.push(token);
+        }
+        self.macros.insert(name, Macro { params, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), OctoError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return self.error(format!("Too many expansions of macro '{}'", name));
+        }
+
+        let param_num = self.macros[name].params.len();
+        let mut args = Vec::with_capacity(param_num);
+        for _ in 0..param_num {
+            args.push(self.take()?.text);
+        }
+        let line = self.line;
+        let mac = &self.macros[name];
+        let expansion: Vec<Token> = mac
+            .body
+            .iter()
+            .map(|token| {
+                let text = match mac.params.iter().position(|param| *param == token.text) {
+                    Some(idx) => args[idx].clone(),
+                    None => token.text.clone(),
+                };
+                Token { text, line }
+            })
+            .collect();
+        for token in expansion.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    // Octo's :calc has no operator precedence, everything is evaluated right to left
+    fn calc_body(&mut self) -> Result<f64, OctoError> {
+        let value = self.calc_expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn calc_expression(&mut self) -> Result<f64, OctoError> {
+        let left = self.calc_term()?;
+        if self.peek_is("}") || self.peek_is(")") {
+            return Ok(left);
+        }
+        let operator = self.take()?.text;
+        let right = self.calc_expression()?;
+        let (a, b) = (left, right);
+        let value = match operator.as_str() {
+            "+" => a + b,
+            "-" => a - b,
+            "*" => a * b,
+            "/" if b == 0.0 => return self.error("Division by zero"),
+            "/" => a / b,
+            "%" if b == 0.0 => return self.error("Division by zero"),
+            "%" => a % b,
+            "&" => (a as i64 & b as i64) as f64,
+            "|" => (a as i64 | b as i64) as f64,
+            "^" => (a as i64 ^ b as i64) as f64,
+            "<<" => (a as i64).checked_shl(b as u32).unwrap_or(0) as f64,
+            ">>" => (a as i64).checked_shr(b as u32).unwrap_or(0) as f64,
+            "pow" => a.powf(b),
+            "min" => a.min(b),
+            "max" => a.max(b),
+            "<" => (a < b) as u8 as f64,
+            "<=" => (a <= b) as u8 as f64,
+            ">" => (a > b) as u8 as f64,
+            ">=" => (a >= b) as u8 as f64,
+            "==" => (a == b) as u8 as f64,
+            "!=" => (a != b) as u8 as f64,
+            _ => return self.error(format!("Unknown operator '{}'", operator)),
+        };
+        Ok(value)
+    }
+
+    fn calc_term(&mut self) -> Result<f64, OctoError> {
+        let token = self.take()?.text;
+        let value = match token.as_str() {
+            "(" => {
+                let value = self.calc_expression()?;
+                self.expect(")")?;
+                value
+            }
+            "-" => -self.calc_term()?,
+            "~" => !(self.calc_term()? as i64) as f64,
+            "!" => (self.calc_term()? == 0.0) as u8 as f64,
+            "sin" => self.calc_term()?.sin(),
+            "cos" => self.calc_term()?.cos(),
+            "tan" => self.calc_term()?.tan(),
+            "exp" => self.calc_term()?.exp(),
+            "log" => self.calc_term()?.ln(),
+            "abs" => self.calc_term()?.abs(),
+            "sqrt" => self.calc_term()?.sqrt(),
+            "sign" => self.calc_term()?.signum(),
+            "ceil" => self.calc_term()?.ceil(),
+            "floor" => self.calc_term()?.floor(),
+            // The byte already compiled at an address
+            "@" => {
+                let addr = self.calc_term()? as i64;
+                match self.memory.get(addr as usize) {
+                    Some(byte) if addr >= 0 => *byte as f64,
+                    _ => return self.error(format!("Address {} is out of memory", addr)),
+                }
+            }
+            "HERE" => self.here as f64,
+            "PI" => std::f64::consts::PI,
+            "E" => std::f64::consts::E,
+            _ => match self.known_value(&token) {
+                Some(value) => value,
+                None => return self.error(format!("Undefined name '{}'", token)),
+            },
+        };
+        Ok(value)
+    }
+
+    // Numbers, constants, labels that are already defined and { calc } expressions
+    fn value(&mut self) -> Result<f64, OctoError> {
+        let token = self.take()?.text;
+        if token == "{" {
+            return self.calc_body();
+        }
+        match self.known_value(&token) {
+            Some(value) => Ok(value),
+            None => self.error(format!("Undefined name '{}'", token)),
+        }
+    }
+
+    fn known_value(&self, token: &str) -> Option<f64> {
+        parse_number(token)
+            .or_else(|| self.constants.get(token).copied())
+            .or_else(|| self.labels.get(token).map(|addr| *addr as f64))
+    }
+
+    // Addresses may refer to labels defined further down
+    fn target(&mut self) -> Result<Target, OctoError> {
+        if self.peek_is("{") {
+            return Ok(Target::Known(self.value()? as i64));
+        }
+        let token = self.take()?.text;
+        self.resolve(&token)
+    }
+
+    fn resolve(&self, token: &str) -> Result<Target, OctoError> {
+        match self.known_value(token) {
+            Some(value) => Ok(Target::Known(value as i64)),
+            None if is_name(token) => Ok(Target::Label(token.to_string())),
+            None => self.error(format!("Expected an address, got '{}'", token)),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, OctoError> {
+        let value = self.value()?;
+        self.check_byte(value)
+    }
+
+    // Negative bytes are stored as two's complement
+    fn check_byte(&self, value: f64) -> Result<u8, OctoError> {
+        let value = value as i64;
+        if (-0x80..=0xFF).contains(&value) {
+            Ok(value as u8)
+        } else {
+            self.error(format!("{} doesn't fit in a byte", value))
+        }
+    }
+
+    fn nibble(&mut self) -> Result<u8, OctoError> {
+        let value = self.value()? as i64;
+        if (0..=0xF).contains(&value) {
+            Ok(value as u8)
+        } else {
+            self.error(format!("{} doesn't fit in a nibble", value))
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, OctoError> {
+        let token = self.take()?.text;
+        match self.lookup_register(&token) {
+            Some(register) => Ok(register),
+            None => self.error(format!("Expected a register, got '{}'", token)),
+        }
+    }
+
+    fn lookup_register(&self, token: &str) -> Option<u8> {
+        parse_register(token).or_else(|| self.aliases.get(token).copied())
+    }
+
+    fn name(&mut self) -> Result<String, OctoError> {
+        let token = self.take()?.text;
+        if is_name(&token) && parse_register(&token).is_none() {
+            Ok(token)
+        } else {
+            self.error(format!("Invalid name '{}'", token))
+        }
+    }
+
+    fn check_unused(&self, name: &str) -> Result<(), OctoError> {
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            self.error(format!("The name '{}' is already defined", name))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn open_block(&mut self, block: Block) {
+        self.blocks.push((block, self.line));
+    }
+
+    fn emit_placeholder_jump(&mut self) -> Result<u16, OctoError> {
+        let addr = self.here as u16;
+        self.emit_op(OpCode::JP(0))?;
+        Ok(addr)
+    }
+
+    fn emit_address_op(
+        &mut self,
+        make: fn(u16) -> OpCode,
+        target: Target,
+    ) -> Result<(), OctoError> {
+        let addr = self.here as u16;
+        self.emit_op(make(0))?;
+        self.resolve_target(addr, FixupKind::Address, target)
+    }
+
+    fn resolve_target(
+        &mut self,
+        addr: u16,
+        kind: FixupKind,
+        target: Target,
+    ) -> Result<(), OctoError> {
+        match target {
+            Target::Known(value) => self.patch(addr, kind, value),
+            Target::Label(name) => {
+                self.fixups.push(Fixup {
+                    addr,
+                    kind,
+                    name,
+                    line: self.line,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn patch(&mut self, addr: u16, kind: FixupKind, target: i64) -> Result<(), OctoError> {
+        let max = match kind {
+            FixupKind::Address | FixupKind::HighNibble => ADDR_MAX,
+            FixupKind::Long | FixupKind::HighByte | FixupKind::LowByte => LONG_ADDR_MAX,
+        };
+        if !(0..=max).contains(&target) {
+            return self.error(format!("Address {:#X} is out of range", target));
+        }
+
+        let addr = addr as usize;
+        let [high, low] = (target as u16).to_be_bytes();
+        match kind {
+            FixupKind::Address => {
+                self.memory[addr] |= high;
+                self.memory[addr + 1] = low;
+            }
+            FixupKind::Long => {
+                self.memory[addr] = high;
+                self.memory[addr + 1] = low;
+            }
+            FixupKind::HighNibble => self.memory[addr] |= high,
+            FixupKind::HighByte => self.memory[addr] = high,
+            FixupKind::LowByte => self.memory[addr] = low,
+        }
+        Ok(())
+    }
+
+    fn emit_op(&mut self, opcode: OpCode) -> Result<(), OctoError> {
+        self.emit(&opcode.to_bytes().to_be_bytes())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), OctoError> {
+        if self.here + bytes.len() > MEM_SIZE {
+            return self.error("Program doesn't fit in memory");
+        }
+        self.memory[self.here..self.here + bytes.len()].copy_from_slice(bytes);
+        self.here += bytes.len();
+        self.rom_end = self.rom_end.max(self.here);
+        Ok(())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.pop_front()?;
+        self.line = token.line;
+        Some(token)
+    }
+
+    fn take(&mut self) -> Result<Token, OctoError> {
+        match self.next() {
+            Some(token) => Ok(token),
+            None => self.error("Unexpected end of file"),
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), OctoError> {
+        let token = self.take()?;
+        if token.text == text {
+            Ok(())
+        } else {
+            self.error(format!("Expected '{}', got '{}'", text, token.text))
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().map(|token| token.text.as_str()) == Some(text)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, OctoError> {
+        Err(OctoError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+}
+
+// Decimal, 0x hex or 0b binary, optionally negative. Decimals may have a fraction for :calc
+fn parse_number(token: &str) -> Option<f64> {
+    let (sign, digits) = match token.strip_prefix('-') {
+        Some(digits) => (-1.0, digits),
+        None => (1.0, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(sign * value)
+}
+
+fn parse_register(token: &str) -> Option<u8> {
+    let index = token
+        .strip_prefix('v')
+        .or_else(|| token.strip_prefix('V'))?;
+    if index.len() == 1 {
+        u8::from_str_radix(index, 16).ok()
+    } else {
+        None
+    }
+}
+
+fn is_name(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, OctoError};
+    use crate::{
+        headless::{self, Limit},
+        quirks::Quirks,
+        timing::Pacing,
+        vm::{Engine, VM},
+    };
+
+    fn run(source: &str, instructions: u64) -> VM {
+        let rom = compile(source).expect("Valid program");
+        let mut vm = VM::with_seed(&rom, Quirks::default(), 0);
+        let summary = headless::run(
+            &mut vm,
+            Engine::Interpreter,
+            Pacing::Rate(1000),
+            Limit::Instructions(instructions),
+            &[],
+        );
+        assert_eq!(summary.fault, None);
+        vm
+    }
+
+    #[test]
+    fn statements_test() {
+        let source = "
+            : main
+                v0 := 5        # LD V0, 0x05
+                v1 := v0
+                v1 += 0x10
+                v1 -= 1        # ADD V1, 0xFF
+                v2 =- v1
+                v3 >>= v3
+                i := sprite
+                i := long sprite
+                sprite v0 v1 4
+                bcd v1
+                save v3
+                load v0 - v2
+                delay := v0
+                v4 := key
+                sub
+            : sprite
+                0xF0 -1
+            : sub
+                return
+        ";
+        let rom = compile(source).expect("Valid program");
+        assert_eq!(
+            rom,
+            vec![
+                0x60, 0x05, 0x81, 0x00, 0x71, 0x10, 0x71, 0xFF, 0x82, 0x17, 0x83, 0x36, 0xA2, 0x20,
+                0xF0, 0x00, 0x02, 0x20, 0xD0, 0x14, 0xF1, 0x33, 0xF3, 0x55, 0x50, 0x23, 0xF0, 0x15,
+                0xF4, 0x0A, 0x22, 0x22, 0xF0, 0xFF, 0x00, 0xEE,
+            ]
+        );
+    }
+
+    #[test]
+    fn main_jump_test() {
+        // Main isn't first, so execution jumps over the data
+        let rom = compile("0xAA : main clear").expect("Valid program");
+        assert_eq!(rom, vec![0x12, 0x03, 0xAA, 0x00, 0xE0]);
+    }
+
+    #[test]
+    fn control_flow_test() {
+        // Sums 1 to 10 into v1, then counts how often v1 is above 50 in v2
+        let source = "
+            :alias sum v1
+            : main
+                v0 := 1
+                loop
+                    sum += v0
+                    v0 += 1
+                    while v0 != 11
+                again
+                if sum > 50 begin
+                    v2 += 1
+                else
+                    v3 += 1
+                end
+                if sum <= 55 then v4 := 1
+                if sum < 55 then v5 := 1
+                if sum >= 0x40 then v6 := 1
+                loop again
+        ";
+        let vm = run(source, 200);
+        let registers = vm.get_registers();
+        assert_eq!(registers[0x1], 55);
+        assert_eq!(registers[0x2], 1);
+        assert_eq!(registers[0x3], 0);
+        assert_eq!(registers[0x4], 1);
+        assert_eq!(registers[0x5], 0);
+        assert_eq!(registers[0x6], 0);
+    }
+
+    #[test]
+    fn directives_test() {
+        let source = "
+            :const SPEED 3
+            :calc DOUBLE { SPEED * 2 + 1 }
+            :macro add-twice reg amount { reg += amount reg += amount }
+            : main
+                v0 := DOUBLE
+                add-twice v0 SPEED
+                :unpack 0xA data
+                :unpack long data
+                jump forward
+            :org 0x300
+            : forward
+                loop again
+            : data
+        ";
+        let rom = compile(source).expect("Valid program");
+        // SPEED * (2 + 1), right to left
+        assert_eq!(&rom[..2], &[0x60, 0x09]);
+        assert_eq!(&rom[2..6], &[0x70, 0x03, 0x70, 0x03]);
+        assert_eq!(&rom[6..10], &[0x60, 0xA3, 0x61, 0x02]);
+        assert_eq!(&rom[10..14], &[0x60, 0x03, 0x61, 0x02]);
+        assert_eq!(&rom[14..16], &[0x13, 0x00]);
+        assert_eq!(rom.len(), 0x102);
+        assert_eq!(&rom[0x100..], &[0x13, 0x00]);
+    }
+
+    #[test]
+    fn plane_test() {
+        assert_eq!(
+            compile(": main plane 3 plane 15"),
+            Ok(vec![0xF3, 0x01, 0xFF, 0x01])
+        );
+        assert!(compile(": main plane 16").is_err());
+    }
+
+    #[test]
+    fn error_test() {
+        let error = |line: usize, message: &str| {
+            Err(OctoError {
+                line,
+                message: message.to_string(),
+            })
+        };
+        assert_eq!(
+            compile(": main\n  v0 := 300\n"),
+            error(2, "300 doesn't fit in a byte")
+        );
+        assert_eq!(
+            compile(": main\n\n  jump nowhere\n"),
+            error(3, "Undefined name 'nowhere'")
+        );
+        assert_eq!(
+            compile(": main\n  loop\n  v0 += 1\n"),
+            error(2, "This 'loop' has no matching 'again'")
+        );
+        assert_eq!(
+            compile("clear"),
+            error(1, "This program has no 'main' label")
+        );
+        assert_eq!(
+            compile(": main : main"),
+            error(1, "The name 'main' is already defined")
+        );
+        assert_eq!(
+            compile(":macro forever { forever }\n: main forever"),
+            error(2, "Too many expansions of macro 'forever'")
+        );
+        assert_eq!(
+            compile(": main\n  v0 := "),
+            error(2, "Unexpected end of file")
+        );
+    }
+}
@@ -1,12 +1,27 @@
+use std::fmt;
+
+// Instructions take one word, LD I, LONG is followed by its address in a second one
+pub const INSTRUCTION_SIZE: u16 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OpCode {
     NOP(),
+    SCDN(u8),
+    SCUN(u8),
     CLS(),
     RET(),
+    SCR(),
+    SCL(),
+    EXIT(),
+    LOW(),
+    HIGH(),
     JP(u16),
     CALL(u16),
     SEVB(u8, u8),
     SNEVB(u8, u8),
     SEVV(u8, u8),
+    LDIVV(u8, u8),
+    LDVVI(u8, u8),
     LDVB(u8, u8),
     ADDVB(u8, u8),
     LDVV(u8, u8),
@@ -25,30 +40,46 @@ pub enum OpCode {
     DRWVVN(u8, u8, u8),
     SKPV(u8),
     SKNPV(u8),
+    LDIL(),
+    PLANEN(u8),
+    AUDIO(),
     LDVDT(u8),
     LDVK(u8),
     LDDTV(u8),
     LDSTV(u8),
     ADDIV(u8),
     LDFV(u8),
+    LDHFV(u8),
     LDBV(u8),
+    LDPV(u8),
     LDIV(u8),
     LDVI(u8),
+    LDRV(u8),
+    LDVR(u8),
 }
 
 impl OpCode {
-    pub fn from_bytes(bytes: u16) -> Self {
+    pub fn from_bytes(bytes: u16) -> Option<Self> {
         use OpCode::*;
 
-        match Self::split_bytes(bytes) {
+        let opcode = match Self::split_bytes(bytes) {
             (0x0, 0x0, 0x0, 0x0) => NOP(),
+            (0x0, 0x0, 0xC, n) => SCDN(n),
+            (0x0, 0x0, 0xD, n) => SCUN(n),
             (0x0, 0x0, 0xE, 0x0) => CLS(),
             (0x0, 0x0, 0xE, 0xE) => RET(),
+            (0x0, 0x0, 0xF, 0xB) => SCR(),
+            (0x0, 0x0, 0xF, 0xC) => SCL(),
+            (0x0, 0x0, 0xF, 0xD) => EXIT(),
+            (0x0, 0x0, 0xF, 0xE) => LOW(),
+            (0x0, 0x0, 0xF, 0xF) => HIGH(),
             (0x1, _, _, _) => JP(Self::get_addr(bytes)),
             (0x2, _, _, _) => CALL(Self::get_addr(bytes)),
             (0x3, x, _, _) => SEVB(x, Self::get_byte(bytes)),
             (0x4, x, _, _) => SNEVB(x, Self::get_byte(bytes)),
             (0x5, x, y, 0x0) => SEVV(x, y),
+            (0x5, x, y, 0x2) => LDIVV(x, y),
+            (0x5, x, y, 0x3) => LDVVI(x, y),
             (0x6, x, _, _) => LDVB(x, Self::get_byte(bytes)),
             (0x7, x, _, _) => ADDVB(x, Self::get_byte(bytes)),
             (0x8, x, y, 0x0) => LDVV(x, y),
@@ -67,19 +98,109 @@ impl OpCode {
             (0xD, x, y, n) => DRWVVN(x, y, n),
             (0xE, x, 0x9, 0xE) => SKPV(x),
             (0xE, x, 0xA, 0x1) => SKNPV(x),
+            (0xF, 0x0, 0x0, 0x0) => LDIL(),
+            (0xF, n, 0x0, 0x1) => PLANEN(n),
+            (0xF, 0x0, 0x0, 0x2) => AUDIO(),
             (0xF, x, 0x0, 0x7) => LDVDT(x),
             (0xF, x, 0x0, 0xA) => LDVK(x),
             (0xF, x, 0x1, 0x5) => LDDTV(x),
             (0xF, x, 0x1, 0x8) => LDSTV(x),
             (0xF, x, 0x1, 0xE) => ADDIV(x),
             (0xF, x, 0x2, 0x9) => LDFV(x),
+            (0xF, x, 0x3, 0x0) => LDHFV(x),
             (0xF, x, 0x3, 0x3) => LDBV(x),
+            (0xF, x, 0x3, 0xA) => LDPV(x),
             (0xF, x, 0x5, 0x5) => LDIV(x),
             (0xF, x, 0x6, 0x5) => LDVI(x),
-            _ => panic!("Unknown opcode: {:#04x}", bytes),
+            (0xF, x, 0x7, 0x5) => LDRV(x),
+            (0xF, x, 0x8, 0x5) => LDVR(x),
+            _ => return None,
+        };
+
+        Some(opcode)
+    }
+
+    // The inverse of from_bytes
+    pub fn to_bytes(&self) -> u16 {
+        use OpCode::*;
+
+        match *self {
+            NOP() => 0x0000,
+            SCDN(n) => Self::join_bytes(0x0, 0x0, 0xC, n),
+            SCUN(n) => Self::join_bytes(0x0, 0x0, 0xD, n),
+            CLS() => 0x00E0,
+            RET() => 0x00EE,
+            SCR() => 0x00FB,
+            SCL() => 0x00FC,
+            EXIT() => 0x00FD,
+            LOW() => 0x00FE,
+            HIGH() => 0x00FF,
+            JP(addr) => 0x1000 | Self::get_addr(addr),
+            CALL(addr) => 0x2000 | Self::get_addr(addr),
+            SEVB(x, byte) => Self::join_byte(0x3, x, byte),
+            SNEVB(x, byte) => Self::join_byte(0x4, x, byte),
+            SEVV(x, y) => Self::join_bytes(0x5, x, y, 0x0),
+            LDIVV(x, y) => Self::join_bytes(0x5, x, y, 0x2),
+            LDVVI(x, y) => Self::join_bytes(0x5, x, y, 0x3),
+            LDVB(x, byte) => Self::join_byte(0x6, x, byte),
+            ADDVB(x, byte) => Self::join_byte(0x7, x, byte),
+            LDVV(x, y) => Self::join_bytes(0x8, x, y, 0x0),
+            ORVV(x, y) => Self::join_bytes(0x8, x, y, 0x1),
+            ANDVV(x, y) => Self::join_bytes(0x8, x, y, 0x2),
+            XORVV(x, y) => Self::join_bytes(0x8, x, y, 0x3),
+            ADDVV(x, y) => Self::join_bytes(0x8, x, y, 0x4),
+            SUBVV(x, y) => Self::join_bytes(0x8, x, y, 0x5),
+            SHRVV(x, y) => Self::join_bytes(0x8, x, y, 0x6),
+            SUBNVV(x, y) => Self::join_bytes(0x8, x, y, 0x7),
+            SHLVV(x, y) => Self::join_bytes(0x8, x, y, 0xE),
+            SNEVV(x, y) => Self::join_bytes(0x9, x, y, 0x0),
+            LDIA(addr) => 0xA000 | Self::get_addr(addr),
+            JPVA(addr) => 0xB000 | Self::get_addr(addr),
+            RNDVB(x, byte) => Self::join_byte(0xC, x, byte),
+            DRWVVN(x, y, n) => Self::join_bytes(0xD, x, y, n),
+            SKPV(x) => Self::join_bytes(0xE, x, 0x9, 0xE),
+            SKNPV(x) => Self::join_bytes(0xE, x, 0xA, 0x1),
+            LDIL() => 0xF000,
+            PLANEN(n) => Self::join_bytes(0xF, n, 0x0, 0x1),
+            AUDIO() => 0xF002,
+            LDVDT(x) => Self::join_bytes(0xF, x, 0x0, 0x7),
+            LDVK(x) => Self::join_bytes(0xF, x, 0x0, 0xA),
+            LDDTV(x) => Self::join_bytes(0xF, x, 0x1, 0x5),
+            LDSTV(x) => Self::join_bytes(0xF, x, 0x1, 0x8),
+            ADDIV(x) => Self::join_bytes(0xF, x, 0x1, 0xE),
+            LDFV(x) => Self::join_bytes(0xF, x, 0x2, 0x9),
+            LDHFV(x) => Self::join_bytes(0xF, x, 0x3, 0x0),
+            LDBV(x) => Self::join_bytes(0xF, x, 0x3, 0x3),
+            LDPV(x) => Self::join_bytes(0xF, x, 0x3, 0xA),
+            LDIV(x) => Self::join_bytes(0xF, x, 0x5, 0x5),
+            LDVI(x) => Self::join_bytes(0xF, x, 0x6, 0x5),
+            LDRV(x) => Self::join_bytes(0xF, x, 0x7, 0x5),
+            LDVR(x) => Self::join_bytes(0xF, x, 0x8, 0x5),
+        }
+    }
+
+    // In bytes, with the address that follows LD I, LONG
+    pub fn size(&self) -> u16 {
+        match self {
+            OpCode::LDIL() => INSTRUCTION_SIZE * 2,
+            _ => INSTRUCTION_SIZE,
         }
     }
 
+    #[inline]
+    fn join_bytes(a: u8, b: u8, c: u8, d: u8) -> u16 {
+        // Nibbles out of range are truncated, like addresses and bytes are
+        ((a & 0xF) as u16) << 12
+            | ((b & 0xF) as u16) << 8
+            | ((c & 0xF) as u16) << 4
+            | (d & 0xF) as u16
+    }
+
+    #[inline]
+    fn join_byte(a: u8, x: u8, byte: u8) -> u16 {
+        Self::join_bytes(a, x, 0x0, 0x0) | byte as u16
+    }
+
     #[inline]
     fn split_bytes(bytes: u16) -> (u8, u8, u8, u8) {
         (
@@ -114,3 +235,138 @@ impl OpCode {
         (bytes & 0x00FF) as u8
     }
 }
+
+// Cowgod's mnemonics (http://devernay.free.fr/hacks/chip8/C8TECH10.HTM),
+// with the SCHIP and XO-CHIP extensions written the way Octo's documentation does
+impl fmt::Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use OpCode::*;
+
+        match *self {
+            NOP() => write!(f, "SYS 0x000"),
+            SCDN(n) => write!(f, "SCD {}", n),
+            SCUN(n) => write!(f, "SCU {}", n),
+            CLS() => write!(f, "CLS"),
+            RET() => write!(f, "RET"),
+            SCR() => write!(f, "SCR"),
+            SCL() => write!(f, "SCL"),
+            EXIT() => write!(f, "EXIT"),
+            LOW() => write!(f, "LOW"),
+            HIGH() => write!(f, "HIGH"),
+            JP(addr) => write!(f, "JP {:#05X}", addr),
+            CALL(addr) => write!(f, "CALL {:#05X}", addr),
+            SEVB(x, byte) => write!(f, "SE V{:X}, {:#04X}", x, byte),
+            SNEVB(x, byte) => write!(f, "SNE V{:X}, {:#04X}", x, byte),
+            SEVV(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            LDIVV(x, y) => write!(f, "LD [I], V{:X}-V{:X}", x, y),
+            LDVVI(x, y) => write!(f, "LD V{:X}-V{:X}, [I]", x, y),
+            LDVB(x, byte) => write!(f, "LD V{:X}, {:#04X}", x, byte),
+            ADDVB(x, byte) => write!(f, "ADD V{:X}, {:#04X}", x, byte),
+            LDVV(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            ORVV(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            ANDVV(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            XORVV(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            ADDVV(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            SUBVV(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            // Vy is always printed, since some quirks profiles read it
+            SHRVV(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            SUBNVV(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            SHLVV(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SNEVV(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LDIA(addr) => write!(f, "LD I, {:#05X}", addr),
+            JPVA(addr) => write!(f, "JP V0, {:#05X}", addr),
+            RNDVB(x, byte) => write!(f, "RND V{:X}, {:#04X}", x, byte),
+            DRWVVN(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            SKPV(x) => write!(f, "SKP V{:X}", x),
+            SKNPV(x) => write!(f, "SKNP V{:X}", x),
+            // The address is the next word, which isn't part of the opcode
+            LDIL() => write!(f, "LD I, LONG"),
+            PLANEN(n) => write!(f, "PLANE {}", n),
+            AUDIO() => write!(f, "AUDIO"),
+            LDVDT(x) => write!(f, "LD V{:X}, DT", x),
+            LDVK(x) => write!(f, "LD V{:X}, K", x),
+            LDDTV(x) => write!(f, "LD DT, V{:X}", x),
+            LDSTV(x) => write!(f, "LD ST, V{:X}", x),
+            ADDIV(x) => write!(f, "ADD I, V{:X}", x),
+            LDFV(x) => write!(f, "LD F, V{:X}", x),
+            LDHFV(x) => write!(f, "LD HF, V{:X}", x),
+            LDBV(x) => write!(f, "LD B, V{:X}", x),
+            LDPV(x) => write!(f, "LD PITCH, V{:X}", x),
+            LDIV(x) => write!(f, "LD [I], V{:X}", x),
+            LDVI(x) => write!(f, "LD V{:X}, [I]", x),
+            LDRV(x) => write!(f, "LD R, V{:X}", x),
+            LDVR(x) => write!(f, "LD V{:X}, R", x),
+        }
+    }
+}
+
+// One instruction the way the debugger and the trace print it
+pub struct Listing {
+    // Hex words, both of them for LD I, LONG
+    pub raw: String,
+    // With LD I, LONG's address, ??? for words that don't decode
+    pub mnemonic: String,
+    pub size: u16,
+}
+
+impl Listing {
+    // read_word returns the big endian word at an address
+    pub fn read(address: u16, read_word: impl Fn(u16) -> u16) -> Self {
+        let word = read_word(address);
+        match OpCode::from_bytes(word) {
+            Some(OpCode::LDIL()) => {
+                let long = read_word(address.wrapping_add(INSTRUCTION_SIZE));
+                Self {
+                    raw: format!("{:04X}{:04X}", word, long),
+                    mnemonic: format!("{} {:#06X}", OpCode::LDIL(), long),
+                    size: OpCode::LDIL().size(),
+                }
+            }
+            Some(opcode) => Self {
+                raw: format!("{:04X}", word),
+                mnemonic: opcode.to_string(),
+                size: opcode.size(),
+            },
+            None => Self {
+                raw: format!("{:04X}", word),
+                mnemonic: "???".to_string(),
+                size: INSTRUCTION_SIZE,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OpCode;
+    use proptest::prelude::*;
+
+    fn mnemonic(bytes: u16) -> String {
+        OpCode::from_bytes(bytes).expect("Valid opcode").to_string()
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(mnemonic(0x00E0), "CLS");
+        assert_eq!(mnemonic(0x00C4), "SCD 4");
+        assert_eq!(mnemonic(0x1A2B), "JP 0xA2B");
+        assert_eq!(mnemonic(0x2004), "CALL 0x004");
+        assert_eq!(mnemonic(0x6120), "LD V1, 0x20");
+        assert_eq!(mnemonic(0x8AB6), "SHR VA, VB");
+        assert_eq!(mnemonic(0xB300), "JP V0, 0x300");
+        assert_eq!(mnemonic(0xD015), "DRW V0, V1, 5");
+        assert_eq!(mnemonic(0x5232), "LD [I], V2-V3");
+        assert_eq!(mnemonic(0xF000), "LD I, LONG");
+        assert_eq!(mnemonic(0xF201), "PLANE 2");
+        assert_eq!(mnemonic(0xFE65), "LD VE, [I]");
+    }
+
+    proptest! {
+        #[test]
+        fn encode_proptest(word in any::<u16>()) {
+            if let Some(opcode) = OpCode::from_bytes(word) {
+                prop_assert_eq!(opcode.to_bytes(), word);
+            }
+        }
+    }
+}
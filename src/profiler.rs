@@ -0,0 +1,483 @@
+// Counts where a rom spends its instructions, see the reports at the bottom
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use crate::memory::{MEM_REGION_RAM, MEM_SIZE};
+use crate::opcode::{OpCode, INSTRUCTION_SIZE};
+use crate::vm::VM;
+
+const HOTSPOT_NUM: usize = 20;
+// Every u16 is a possible opcode word
+const WORD_NUM: usize = 0x10000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CallStats {
+    pub calls: u64,
+    // Instructions from the CALL to the RET, both included
+    pub total: u64,
+    // The same, without the instructions of the subroutines it called
+    pub own: u64,
+}
+
+// Coverage of a single byte, code wins when a byte was both executed and read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Coverage {
+    Code,
+    Data,
+    Untouched,
+}
+
+// A subroutine that hasn't returned yet
+struct Frame {
+    address: u16,
+    start: u64,
+    callee_instructions: u64,
+}
+
+pub struct Profiler {
+    instructions: u64,
+    address_counts: Vec<u64>,
+    // The last word executed at each address, for the mnemonics in the reports
+    address_words: Vec<u16>,
+    word_counts: Vec<u64>,
+    executed: Vec<bool>,
+    // Marked by Memory while the profiler is attached to a VM
+    data_reads: Vec<bool>,
+    frames: Vec<Frame>,
+    subroutines: BTreeMap<u16, CallStats>,
+    // Caller to callee, None is whatever ran before the first CALL
+    calls: BTreeMap<(Option<u16>, u16), u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            instructions: 0,
+            address_counts: vec![0; MEM_SIZE],
+            address_words: vec![0; MEM_SIZE],
+            word_counts: vec![0; WORD_NUM],
+            executed: vec![false; MEM_SIZE],
+            data_reads: vec![false; MEM_SIZE],
+            frames: Vec::new(),
+            subroutines: BTreeMap::new(),
+            calls: BTreeMap::new(),
+        }
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn count_at(&self, address: u16) -> u64 {
+        self.address_counts[address as usize]
+    }
+
+    pub fn subroutine(&self, address: u16) -> Option<CallStats> {
+        self.subroutines.get(&address).copied()
+    }
+
+    pub fn coverage_at(&self, address: u16) -> Coverage {
+        if self.executed[address as usize] {
+            Coverage::Code
+        } else if self.data_reads[address as usize] {
+            Coverage::Data
+        } else {
+            Coverage::Untouched
+        }
+    }
+
+    // Executions per OpCode variant, named as in the OpCode enum
+    pub fn opcode_counts(&self) -> BTreeMap<String, u64> {
+        let mut counts = BTreeMap::new();
+        for (word, count) in self.word_counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            if let Some(opcode) = OpCode::from_bytes(word as u16) {
+                *counts.entry(variant_name(opcode)).or_insert(0) += count;
+            }
+        }
+        counts
+    }
+
+    pub(crate) fn swap_data_reads(&mut self, data_reads: &mut Vec<bool>) {
+        std::mem::swap(&mut self.data_reads, data_reads);
+    }
+
+    // Called by the VM after each instruction, with the stack depth from before it ran
+    pub(crate) fn record(&mut self, pc: u16, opcode: OpCode, depth: usize, vm: &VM) {
+        let address = pc as usize;
+        let word = opcode.to_bytes();
+        self.instructions += 1;
+        self.address_counts[address] += 1;
+        self.address_words[address] = word;
+        self.word_counts[word as usize] += 1;
+        let size = opcode.size() as usize;
+        for executed in self.executed.iter_mut().skip(address).take(size) {
+            *executed = true;
+        }
+
+        // Faulting CALLs and RETs leave the stack as it was
+        let new_depth = vm.get_stack().len();
+        match opcode {
+            OpCode::CALL(_) if new_depth > depth => {
+                let callee = vm.get_program_counter();
+                let caller = self.frames.last().map(|frame| frame.address);
+                *self.calls.entry((caller, callee)).or_insert(0) += 1;
+                self.frames.push(Frame {
+                    address: callee,
+                    start: self.instructions - 1,
+                    callee_instructions: 0,
+                });
+            }
+            OpCode::RET() if new_depth < depth => self.finish_call(),
+            _ => (),
+        }
+    }
+
+    // A RET without a matching CALL came from before profiling started, and is ignored
+    fn finish_call(&mut self) {
+        let frame = match self.frames.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let total = self.instructions - frame.start;
+        let stats = self.subroutines.entry(frame.address).or_default();
+        stats.calls += 1;
+        stats.total += total;
+        stats.own += total - frame.callee_instructions;
+        if let Some(parent) = self.frames.last_mut() {
+            parent.callee_instructions += total;
+        }
+    }
+
+    fn hotspots(&self) -> Vec<(u16, u64)> {
+        let mut hotspots: Vec<(u16, u64)> = self
+            .address_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(address, count)| (address as u16, *count))
+            .collect();
+        // Busiest first, lower addresses first on ties so reports are stable
+        hotspots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hotspots
+    }
+
+    fn mnemonic_at(&self, address: u16) -> String {
+        match OpCode::from_bytes(self.address_words[address as usize]) {
+            Some(opcode) => opcode.to_string(),
+            None => "???".to_string(),
+        }
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.instructions == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.instructions as f64
+        }
+    }
+
+    // Code, data and untouched byte counts over the rom
+    fn rom_coverage(&self, rom: &[u8]) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for address in rom_addresses(rom) {
+            match self.coverage_at(address) {
+                Coverage::Code => counts.0 += 1,
+                Coverage::Data => counts.1 += 1,
+                Coverage::Untouched => counts.2 += 1,
+            }
+        }
+        counts
+    }
+
+    fn rom_ranges(&self, rom: &[u8], coverage: Coverage) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for address in rom_addresses(rom).filter(|address| self.coverage_at(*address) == coverage) {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == address => *end = address,
+                _ => ranges.push((address, address)),
+            }
+        }
+        ranges
+    }
+
+    // Subroutines with the most instructions first
+    fn sorted_subroutines(&self) -> Vec<(u16, CallStats)> {
+        let mut subroutines: Vec<(u16, CallStats)> = self
+            .subroutines
+            .iter()
+            .map(|(address, stats)| (*address, *stats))
+            .collect();
+        subroutines.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(&b.0)));
+        subroutines
+    }
+
+    pub fn text_report(&self, rom: &[u8]) -> String {
+        let mut text = format!("Instructions: {}\n", self.instructions);
+
+        let _ = writeln!(text, "\nHotspots:");
+        for (address, count) in self.hotspots().into_iter().take(HOTSPOT_NUM) {
+            let _ = writeln!(
+                text,
+                "  {:#06X} {:>12} {:>6.2}%  {}",
+                address,
+                count,
+                self.percent(count),
+                self.mnemonic_at(address)
+            );
+        }
+
+        let _ = writeln!(text, "\nOpcodes:");
+        let mut opcodes: Vec<(String, u64)> = self.opcode_counts().into_iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (name, count) in opcodes {
+            let _ = writeln!(
+                text,
+                "  {:<8} {:>12} {:>6.2}%",
+                name,
+                count,
+                self.percent(count)
+            );
+        }
+
+        // Calls that haven't returned yet aren't counted
+        let _ = writeln!(
+            text,
+            "\nSubroutines:              calls        total          own"
+        );
+        for (address, stats) in self.sorted_subroutines() {
+            let _ = writeln!(
+                text,
+                "  {:#06X} {:>22} {:>12} {:>12} {:>6.2}%",
+                address,
+                stats.calls,
+                stats.total,
+                stats.own,
+                self.percent(stats.total)
+            );
+        }
+
+        let _ = writeln!(text, "\nCall graph:");
+        for ((caller, callee), count) in self.calls.iter() {
+            let caller = match caller {
+                Some(caller) => format!("{:#06X}", caller),
+                None => "entry".to_string(),
+            };
+            let _ = writeln!(text, "  {:>6} -> {:#06X} {:>12}", caller, callee, count);
+        }
+
+        let (code, data, untouched) = self.rom_coverage(rom);
+        let _ = writeln!(
+            text,
+            "\nRom coverage: {} bytes, {} code, {} data, {} untouched",
+            rom.len(),
+            code,
+            data,
+            untouched
+        );
+        text
+    }
+
+    pub fn json_report(&self, rom: &[u8]) -> String {
+        let addresses: Vec<String> = self
+            .address_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(address, count)| format!("{{\"address\": {}, \"count\": {}}}", address, count))
+            .collect();
+        let opcodes: Vec<String> = self
+            .opcode_counts()
+            .iter()
+            .map(|(name, count)| format!("\"{}\": {}", name, count))
+            .collect();
+        let subroutines: Vec<String> = self
+            .subroutines
+            .iter()
+            .map(|(address, stats)| {
+                format!(
+                    "{{\"address\": {}, \"calls\": {}, \"total\": {}, \"own\": {}}}",
+                    address, stats.calls, stats.total, stats.own
+                )
+            })
+            .collect();
+        let calls: Vec<String> = self
+            .calls
+            .iter()
+            .map(|((caller, callee), count)| {
+                let caller = caller.map_or("null".to_string(), |caller| caller.to_string());
+                format!(
+                    "{{\"caller\": {}, \"callee\": {}, \"count\": {}}}",
+                    caller, callee, count
+                )
+            })
+            .collect();
+        let ranges = |coverage| {
+            self.rom_ranges(rom, coverage)
+                .iter()
+                .map(|(start, end)| format!("[{}, {}]", start, end))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let (code, data, untouched) = self.rom_coverage(rom);
+
+        format!(
+            concat!(
+                "{{\n",
+                "  \"instructions\": {},\n",
+                "  \"addresses\": [{}],\n",
+                "  \"opcodes\": {{{}}},\n",
+                "  \"subroutines\": [{}],\n",
+                "  \"calls\": [{}],\n",
+                "  \"coverage\": {{\"rom_bytes\": {}, \"code\": {}, \"data\": {}, \"untouched\": {}, ",
+                "\"code_ranges\": [{}], \"data_ranges\": [{}]}}\n",
+                "}}\n"
+            ),
+            self.instructions,
+            addresses.join(", "),
+            opcodes.join(", "),
+            subroutines.join(", "),
+            calls.join(", "),
+            rom.len(),
+            code,
+            data,
+            untouched,
+            ranges(Coverage::Code),
+            ranges(Coverage::Data)
+        )
+    }
+
+    // One word per line: address, bytes, coverage (C code, D data, . untouched), count, mnemonic
+    pub fn annotated_listing(&self, rom: &[u8]) -> String {
+        let rom = &rom[..rom.len().min(MEM_SIZE - MEM_REGION_RAM as usize)];
+        let mut text = String::new();
+        for (offset, bytes) in rom.chunks(INSTRUCTION_SIZE as usize).enumerate() {
+            let address = MEM_REGION_RAM + offset as u16 * INSTRUCTION_SIZE;
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let marks: String = (0..bytes.len() as u16)
+                .map(|idx| match self.coverage_at(address + idx) {
+                    Coverage::Code => 'C',
+                    Coverage::Data => 'D',
+                    Coverage::Untouched => '.',
+                })
+                .collect();
+            let count = self.count_at(address);
+            let (count, mnemonic) = if count > 0 {
+                (count.to_string(), self.mnemonic_at(address))
+            } else {
+                (String::new(), String::new())
+            };
+            let line = format!(
+                "{:#06X}  {:<5}  {:<2} {:>12}  {}",
+                address,
+                hex.join(" "),
+                marks,
+                count,
+                mnemonic
+            );
+            let _ = writeln!(text, "{}", line.trim_end());
+        }
+        text
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Roms are loaded at MEM_REGION_RAM, anything past the address space is dropped
+fn rom_addresses(rom: &[u8]) -> impl Iterator<Item = u16> {
+    let end = (MEM_REGION_RAM as usize + rom.len()).min(MEM_SIZE);
+    (MEM_REGION_RAM as usize..end).map(|address| address as u16)
+}
+
+fn variant_name(opcode: OpCode) -> String {
+    let name = format!("{:?}", opcode);
+    match name.split_once('(') {
+        Some((variant, _)) => variant.to_string(),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallStats, Coverage, Profiler};
+    use crate::{keypad::KeypadState, quirks::Quirks, vm::VM};
+
+    fn profile(rom: &[u8], ticks: usize) -> Profiler {
+        let mut vm = VM::with_seed(rom, Quirks::default(), 0);
+        vm.set_profiler(Some(Profiler::new()));
+        let keypad = KeypadState::new();
+        for _ in 0..ticks {
+            let _ = vm.tick(&keypad);
+        }
+        vm.take_profiler().expect("Attached profiler")
+    }
+
+    #[test]
+    fn profile_test() {
+        let rom = [
+            0xA2, 0x10, // 0x200 LD I, 0x210
+            0x22, 0x08, // 0x202 CALL 0x208
+            0x12, 0x04, // 0x204 JP 0x204
+            0x00, 0x00, // 0x206 unused
+            0xF1, 0x65, // 0x208 LD V1, [I]
+            0x22, 0x0E, // 0x20A CALL 0x20E
+            0x00, 0xEE, // 0x20C RET
+            0x00, 0xEE, // 0x20E RET
+            0x12, 0x34, // 0x210 data
+        ];
+        let profiler = profile(&rom, 10);
+        assert_eq!(profiler.instructions(), 10);
+        assert_eq!(profiler.count_at(0x204), 4);
+        assert_eq!(profiler.count_at(0x206), 0);
+
+        let counts = profiler.opcode_counts();
+        assert_eq!(counts.get("CALL"), Some(&2));
+        assert_eq!(counts.get("RET"), Some(&2));
+        assert_eq!(counts.get("JP"), Some(&4));
+
+        // CALL, LD, CALL, [CALL, RET], RET
+        assert_eq!(
+            profiler.subroutine(0x20E),
+            Some(CallStats {
+                calls: 1,
+                total: 2,
+                own: 2
+            })
+        );
+        assert_eq!(
+            profiler.subroutine(0x208),
+            Some(CallStats {
+                calls: 1,
+                total: 5,
+                own: 3
+            })
+        );
+
+        assert_eq!(profiler.coverage_at(0x208), Coverage::Code);
+        assert_eq!(profiler.coverage_at(0x210), Coverage::Data);
+        assert_eq!(profiler.coverage_at(0x211), Coverage::Data);
+        assert_eq!(profiler.coverage_at(0x206), Coverage::Untouched);
+
+        let report = profiler.text_report(&rom);
+        assert!(report.contains("0x0204            4  40.00%  JP 0x204"));
+        assert!(report.contains("entry -> 0x0208            1"));
+        assert!(report.contains("Rom coverage: 18 bytes, 14 code, 2 data, 2 untouched"));
+        let json = profiler.json_report(&rom);
+        assert!(json
+            .contains("\"code_ranges\": [[512, 517], [520, 527]], \"data_ranges\": [[528, 529]]"));
+        assert!(json.contains("{\"caller\": 520, \"callee\": 526, \"count\": 1}"));
+
+        let listing = profiler.annotated_listing(&rom);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[2], "0x0204  12 04  CC            4  JP 0x204");
+        assert_eq!(lines[3], "0x0206  00 00  ..");
+        assert_eq!(lines[8], "0x0210  12 34  DD");
+    }
+}
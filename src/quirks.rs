@@ -0,0 +1,121 @@
+// Behaviour differences between CHIP-8 interpreter variants
+// As per (https://github.com/Timendus/chip8-test-suite#quirks-test)
+
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexIncrement {
+    // Fx55/Fx65 leave I untouched (SCHIP)
+    Unchanged,
+    // I = I + x (CHIP-48)
+    X,
+    // I = I + x + 1 (COSMAC VIP, XO-CHIP)
+    XPlusOne,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    // 8xy6/8xyE shift Vy and store the result in Vx, instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    // How Fx55/Fx65 change I after the transfer
+    pub load_store_index: IndexIncrement,
+    // 8xy1/8xy2/8xy3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // Bnnn jumps to xnn + Vx, instead of nnn + V0
+    pub jump_uses_vx: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+}
+
+impl Quirks {
+    pub fn cosmac_vip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_index: IndexIncrement::XPlusOne,
+            logic_resets_vf: true,
+            jump_uses_vx: false,
+            clip_sprites: true,
+        }
+    }
+
+    pub fn chip48() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_index: IndexIncrement::X,
+            logic_resets_vf: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+        }
+    }
+
+    pub fn schip() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_index: IndexIncrement::Unchanged,
+            logic_resets_vf: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+        }
+    }
+
+    pub fn xochip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_index: IndexIncrement::XPlusOne,
+            logic_resets_vf: false,
+            jump_uses_vx: false,
+            clip_sprites: false,
+        }
+    }
+
+    // The names the binaries take for --quirks
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "vip" => Ok(Self::cosmac_vip()),
+            "chip48" => Ok(Self::chip48()),
+            "schip" => Ok(Self::schip()),
+            "xochip" => Ok(Self::xochip()),
+            _ => Err(format!("Unknown quirks profile '{}'", name)),
+        }
+    }
+
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.shift_uses_vy);
+        writer.write_u8(match self.load_store_index {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::X => 1,
+            IndexIncrement::XPlusOne => 2,
+        });
+        writer.write_bool(self.logic_resets_vf);
+        writer.write_bool(self.jump_uses_vx);
+        writer.write_bool(self.clip_sprites);
+    }
+
+    pub fn read_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        Ok(Self {
+            shift_uses_vy: reader.read_bool()?,
+            load_store_index: match reader.read_u8()? {
+                0 => IndexIncrement::Unchanged,
+                1 => IndexIncrement::X,
+                2 => IndexIncrement::XPlusOne,
+                _ => return Err(SaveStateError::InvalidValue("index increment quirk")),
+            },
+            logic_resets_vf: reader.read_bool()?,
+            jump_uses_vx: reader.read_bool()?,
+            clip_sprites: reader.read_bool()?,
+        })
+    }
+}
+
+// How this emulator ran every rom before quirks could be picked, the presets are opt-in
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_index: IndexIncrement::Unchanged,
+            logic_resets_vf: false,
+            jump_uses_vx: false,
+            clip_sprites: false,
+        }
+    }
+}
@@ -3,10 +3,27 @@ use winit::window::Window;
 
 use crate::display::ModificationData;
 
-#[repr(C)]
-#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-struct Vertex {
-    position: [f32; 2],
+use layout::{GfxParams, Vertex};
+
+// The Pod derives generate padding checks next to the structs that trip the dead code lint
+#[allow(dead_code)]
+mod layout {
+    use super::Palette;
+
+    #[repr(C)]
+    #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+    pub(super) struct Vertex {
+        pub(super) position: [f32; 2],
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+    pub(super) struct GfxParams {
+        pub(super) resolution: [u32; 2],
+        // The palette array is aligned to 16 bytes
+        pub(super) _padding: [u32; 2],
+        pub(super) palette: Palette,
+    }
 }
 
 impl Vertex {
@@ -23,6 +40,46 @@ impl Vertex {
     }
 }
 
+pub type Palette = [[f32; 4]; 4];
+
+// Indexed by the 2-bit XO-CHIP pixel value, colors are modulated by the screen gradient
+pub const DEFAULT_PALETTE: Palette = [
+    [0.0, 0.0, 0.0, 1.0],
+    [1.0, 1.0, 1.0, 1.0],
+    [0.5, 0.5, 0.5, 1.0],
+    [0.75, 0.75, 0.75, 1.0],
+];
+
+// Four RRGGBB hex colors separated by commas, for pixel values 0 to 3
+pub fn parse_palette(spec: &str) -> Result<Palette, String> {
+    let colors: Vec<&str> = spec.split(',').collect();
+    if colors.len() != DEFAULT_PALETTE.len() {
+        return Err(format!("Expected 4 colors, got '{}'", spec));
+    }
+    let mut palette = DEFAULT_PALETTE;
+    for (entry, color) in palette.iter_mut().zip(colors) {
+        if color.len() != 6 || !color.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Invalid color '{}'", color));
+        }
+        let rgb =
+            u32::from_str_radix(color, 16).map_err(|_| format!("Invalid color '{}'", color))?;
+        for (channel, shift) in entry.iter_mut().zip([16, 8, 0].iter()) {
+            *channel = (rgb >> shift & 0xFF) as f32 / 255.0;
+        }
+    }
+    Ok(palette)
+}
+
+impl GfxParams {
+    fn new(mode: (usize, usize), palette: Palette) -> Self {
+        Self {
+            resolution: [mode.0 as u32, mode.1 as u32],
+            _padding: [0; 2],
+            palette,
+        }
+    }
+}
+
 const VERTICES: &[Vertex] = &[
     Vertex {
         position: [1.0, 1.0],
@@ -54,11 +111,13 @@ pub struct Renderer {
     vertex_buffer: wgpu::Buffer,
     num_vertices: u32,
     gfx_state_buffer: wgpu::Buffer,
+    gfx_params_buffer: wgpu::Buffer,
+    gfx_params: GfxParams,
     gfx_state_bind_group: wgpu::BindGroup,
 }
 
 impl Renderer {
-    pub async fn new(window: &Window) -> Self {
+    pub async fn new(window: &Window, palette: Palette) -> Self {
         let size = window.inner_size();
         let instance = wgpu::Instance::new(wgpu::Backends::all());
         let surface = unsafe { instance.create_surface(window) };
@@ -100,31 +159,54 @@ impl Renderer {
         let num_vertices = VERTICES.len() as u32;
         let gfx_state_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Gfx State Buffer"),
-            // @NOTE We should allocate enough bytes to fit the largest possible resolution mode
-            // @TODO The current resolution mode needs to be communicated to the gpu
+            // @NOTE We should allocate enough bytes to fit both planes of the largest resolution mode
             contents: bytemuck::cast_slice(&[0_u8; 2048]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
+        let gfx_params = GfxParams::new((64, 32), palette);
+        let gfx_params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Gfx Params Buffer"),
+            contents: bytemuck::cast_slice(&[gfx_params]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
         let gfx_state_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
                 label: Some("Gfx State Bind Group Layout"),
             });
         let gfx_state_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &gfx_state_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: gfx_state_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: gfx_state_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: gfx_params_buffer.as_entire_binding(),
+                },
+            ],
             label: Some("Gfx State Bind Group"),
         });
         let render_pipeline_layout =
@@ -177,6 +259,8 @@ impl Renderer {
             vertex_buffer,
             num_vertices,
             gfx_state_buffer,
+            gfx_params_buffer,
+            gfx_params,
             gfx_state_bind_group,
         }
     }
@@ -236,11 +320,46 @@ impl Renderer {
         Ok(())
     }
 
-    pub fn write_display_modifications(&self, modification_data: ModificationData) {
+    pub fn write_display_modifications(&mut self, modification_data: ModificationData) {
         self.queue.write_buffer(
             &self.gfx_state_buffer,
             modification_data.offset as BufferAddress,
             bytemuck::cast_slice(modification_data.data),
         );
+        self.gfx_params.resolution = [
+            modification_data.mode.0 as u32,
+            modification_data.mode.1 as u32,
+        ];
+        self.write_params();
+    }
+
+    fn write_params(&self) {
+        self.queue.write_buffer(
+            &self.gfx_params_buffer,
+            0,
+            bytemuck::cast_slice(&[self.gfx_params]),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_palette, DEFAULT_PALETTE};
+
+    #[test]
+    fn parse_palette_test() {
+        let palette = parse_palette("000000,ffffff,808080,FF0040").unwrap();
+        assert_eq!(palette[0], DEFAULT_PALETTE[0]);
+        assert_eq!(palette[1], DEFAULT_PALETTE[1]);
+        assert_eq!(
+            palette[2],
+            [128.0 / 255.0, 128.0 / 255.0, 128.0 / 255.0, 1.0]
+        );
+        assert_eq!(palette[3], [1.0, 0.0, 64.0 / 255.0, 1.0]);
+
+        assert!(parse_palette("000000,ffffff,808080").is_err());
+        assert!(parse_palette("000000,ffffff,808080,c0c0c0,000000").is_err());
+        assert!(parse_palette("000000,ffffff,808080,+c0c0c").is_err());
+        assert!(parse_palette("000000,ffffff,808080,c0c0c").is_err());
     }
 }
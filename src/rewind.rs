@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+
+// Gaps shorter than this between changed bytes are stored inline rather than starting a new run
+const MIN_SKIP: usize = 8;
+
+// Ring buffer of save states, one per frame
+// Only the newest state is kept whole, every older one is stored as a delta
+// that turns the state after it back into it, so dropping the oldest frame is free
+pub struct Rewind {
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    capacity: usize,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            latest: None,
+            deltas: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(diff(&state, &previous));
+            while self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(state);
+    }
+
+    // Steps back one frame and returns the state there, the oldest frame is returned repeatedly
+    pub fn step_back(&mut self) -> Option<&[u8]> {
+        if let Some(delta) = self.deltas.pop_back() {
+            let latest = self.latest.as_mut()?;
+            *latest = apply(latest, &delta);
+        }
+        self.latest.as_deref()
+    }
+
+    // Number of frames that can be restored, including the newest one
+    pub fn len(&self) -> usize {
+        self.latest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+}
+
+// Delta layout: target length (u32), then runs of
+// skip (u32), length (u32) and length bytes XORed with the base
+fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    delta.extend_from_slice(&(target.len() as u32).to_le_bytes());
+
+    let byte_at = |data: &[u8], idx: usize| data.get(idx).copied().unwrap_or(0);
+    let mut position = 0;
+    let mut idx = 0;
+    while idx < target.len() {
+        if byte_at(base, idx) == target[idx] {
+            idx += 1;
+            continue;
+        }
+
+        // Extend the run until MIN_SKIP unchanged bytes in a row
+        let start = idx;
+        let mut end = idx + 1;
+        let mut unchanged = 0;
+        while end + unchanged < target.len() && unchanged < MIN_SKIP {
+            if byte_at(base, end + unchanged) == target[end + unchanged] {
+                unchanged += 1;
+            } else {
+                end += unchanged + 1;
+                unchanged = 0;
+            }
+        }
+
+        delta.extend_from_slice(&((start - position) as u32).to_le_bytes());
+        delta.extend_from_slice(&((end - start) as u32).to_le_bytes());
+        delta.extend((start..end).map(|i| byte_at(base, i) ^ target[i]));
+        position = end;
+        idx = end;
+    }
+    delta
+}
+
+fn apply(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let read_u32 = |at: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&delta[at..at + 4]);
+        u32::from_le_bytes(bytes) as usize
+    };
+
+    let mut target = base.to_vec();
+    target.resize(read_u32(0), 0);
+
+    let mut cursor = 4;
+    let mut position = 0;
+    while cursor < delta.len() {
+        position += read_u32(cursor);
+        let len = read_u32(cursor + 4);
+        cursor += 8;
+        for (byte, change) in target[position..position + len]
+            .iter_mut()
+            .zip(&delta[cursor..cursor + len])
+        {
+            *byte ^= change;
+        }
+        position += len;
+        cursor += len;
+    }
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, diff, Rewind};
+    use proptest::prelude::*;
+
+    #[test]
+    fn delta_size_test() {
+        let base = vec![0u8; 4096];
+        let mut target = base.clone();
+        target[100] = 1;
+        target[105] = 2;
+        target[4000] = 3;
+
+        let delta = diff(&base, &target);
+        // Header and two runs, the close changes share one
+        assert_eq!(delta.len(), 4 + (8 + 6) + (8 + 1));
+        assert_eq!(apply(&base, &delta), target);
+    }
+
+    #[test]
+    fn ring_buffer_test() {
+        let mut rewind = Rewind::new(3);
+        assert!(rewind.step_back().is_none());
+
+        for frame in 0..5u8 {
+            rewind.push(vec![frame; 16]);
+        }
+        assert_eq!(rewind.len(), 3);
+
+        assert_eq!(rewind.step_back(), Some(&[3u8; 16][..]));
+        assert_eq!(rewind.step_back(), Some(&[2u8; 16][..]));
+        // Can't go further back than the oldest frame
+        assert_eq!(rewind.step_back(), Some(&[2u8; 16][..]));
+
+        // Recording carries on from the rewound frame
+        rewind.push(vec![7; 16]);
+        assert_eq!(rewind.step_back(), Some(&[2u8; 16][..]));
+
+        rewind.clear();
+        assert!(rewind.is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn delta_proptest(
+            base in prop::collection::vec(any::<u8>(), 0..256),
+            changes in prop::collection::vec((any::<usize>(), any::<u8>()), 0..32),
+            new_len in 0usize..300,
+        ) {
+            let mut target = base.clone();
+            target.resize(new_len, 0xAA);
+            for (idx, value) in changes {
+                if !target.is_empty() {
+                    let len = target.len();
+                    target[idx % len] = value;
+                }
+            }
+
+            prop_assert_eq!(apply(&base, &diff(&base, &target)), target.clone());
+            prop_assert_eq!(apply(&target, &diff(&target, &base)), base);
+        }
+    }
+}
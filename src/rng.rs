@@ -0,0 +1,87 @@
+use rand::{RngCore, SeedableRng};
+
+// SplitMix64 (https://prng.di.unimi.it/splitmix64.c)
+// The whole generator state is a single u64, so it can be stored in save states
+// and movies, and restoring it resumes the exact same sequence
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VmRng {
+    state: u64,
+}
+
+impl VmRng {
+    pub fn from_state(state: u64) -> Self {
+        Self { state }
+    }
+
+    pub fn get_state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        // The high bits are the best mixed ones
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+impl RngCore for VmRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeedableRng for VmRng {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        Self::from_state(u64::from_le_bytes(seed))
+    }
+
+    fn seed_from_u64(state: u64) -> Self {
+        Self::from_state(state)
+    }
+}
+
+// A fresh seed for runs that don't ask for a fixed one
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::VmRng;
+
+    #[test]
+    fn sequence_test() {
+        // Reference values of splitmix64 seeded with 0
+        let mut rng = VmRng::from_state(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+
+        // Resuming from a stored state continues the same sequence
+        let mut resumed = VmRng::from_state(rng.get_state());
+        assert_eq!(rng.next_u8(), resumed.next_u8());
+        assert_eq!(rng, resumed);
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+
+// Binary snapshot format, all values are little endian
+//
+// Header:
+//   magic     4 bytes  "C8SS"
+//   version   u16
+//   length    u32      payload length
+//   checksum  u32      CRC-32 of the payload
+// Payload:
+//   whatever the VM writes, in a fixed order (see VM::save_state)
+//
+// Other files (movies) share the container with their own magic and version
+pub const SAVE_STATE_VERSION: u16 = 2;
+
+const MAGIC: [u8; 4] = *b"C8SS";
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 4 + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveStateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    ChecksumMismatch,
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SaveStateError::BadMagic => write!(f, "Unrecognized file format"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported format version {}", version)
+            }
+            SaveStateError::Truncated => write!(f, "File is truncated"),
+            SaveStateError::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            SaveStateError::InvalidValue(what) => write!(f, "Invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+pub struct StateWriter {
+    payload: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self {
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.payload.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.payload.extend_from_slice(bytes);
+    }
+
+    // Prepends the header
+    pub fn finish(self) -> Vec<u8> {
+        self.finish_as(MAGIC, SAVE_STATE_VERSION)
+    }
+
+    pub fn finish_as(self, magic: [u8; 4], version: u16) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        data.extend_from_slice(&magic);
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&crc32(&self.payload).to_le_bytes());
+        data.extend_from_slice(&self.payload);
+        data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    payload: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // Validates the header, so only the payload is left to read
+    pub fn new(data: &'a [u8]) -> Result<Self, SaveStateError> {
+        Self::new_as(data, MAGIC, SAVE_STATE_VERSION)
+    }
+
+    pub fn new_as(
+        data: &'a [u8],
+        magic: [u8; 4],
+        expected_version: u16,
+    ) -> Result<Self, SaveStateError> {
+        if data.len() < magic.len() || data[..magic.len()] != magic {
+            return Err(SaveStateError::BadMagic);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(SaveStateError::Truncated);
+        }
+
+        let mut header = StateReader {
+            payload: &data[MAGIC.len()..HEADER_SIZE],
+            position: 0,
+        };
+        let version = header.read_u16()?;
+        let length = header.read_u32()? as usize;
+        let checksum = header.read_u32()?;
+
+        if version != expected_version {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        let payload = &data[HEADER_SIZE..];
+        if payload.len() != length {
+            return Err(SaveStateError::Truncated);
+        }
+        if crc32(payload) != checksum {
+            return Err(SaveStateError::ChecksumMismatch);
+        }
+
+        Ok(Self {
+            payload,
+            position: 0,
+        })
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::InvalidValue("flag")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.read_bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.payload.len())
+            .ok_or(SaveStateError::Truncated)?;
+        let bytes = &self.payload[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    // Leftover bytes mean the payload doesn't match what this version writes
+    pub fn finish(self) -> Result<(), SaveStateError> {
+        if self.position == self.payload.len() {
+            Ok(())
+        } else {
+            Err(SaveStateError::InvalidValue("payload length"))
+        }
+    }
+}
+
+// CRC-32 (IEEE), table driven
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC32_TABLE: [u32; 256] = make_crc32_table();
+
+const fn make_crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = idx as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, SaveStateError, StateReader, StateWriter, HEADER_SIZE};
+
+    fn sample_state() -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0xAB);
+        writer.write_bool(true);
+        writer.write_u16(0x1234);
+        writer.write_u64(0xDEAD_BEEF_0BAD_F00D);
+        writer.finish()
+    }
+
+    #[test]
+    fn crc32_test() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let data = sample_state();
+        let mut reader = StateReader::new(&data).expect("Valid state");
+        assert_eq!(reader.read_u8(), Ok(0xAB));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x1234));
+        assert_eq!(reader.read_u64(), Ok(0xDEAD_BEEF_0BAD_F00D));
+        assert_eq!(reader.read_u8(), Err(SaveStateError::Truncated));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn header_test() {
+        let data = sample_state();
+
+        assert_eq!(
+            StateReader::new(b"NOPE").err(),
+            Some(SaveStateError::BadMagic)
+        );
+        assert_eq!(
+            StateReader::new(&data[..data.len() - 1]).err(),
+            Some(SaveStateError::Truncated)
+        );
+
+        let mut newer = data.clone();
+        newer[4] = 0xFF;
+        assert_eq!(
+            StateReader::new(&newer).err(),
+            Some(SaveStateError::UnsupportedVersion(0xFF))
+        );
+
+        let mut corrupted = data;
+        corrupted[HEADER_SIZE] ^= 1;
+        assert_eq!(
+            StateReader::new(&corrupted).err(),
+            Some(SaveStateError::ChecksumMismatch)
+        );
+    }
+}
@@ -22,20 +22,25 @@ fn main(
 // Fragment shader
 
 // wgsl cant seem to do compile time math
-// @TODO feed some of those as uniforms at some point
-let GFX_WIDTH_DEFAULT: u32 = 64u;
-let GFX_HEIGHT_DEFAULT: u32 = 32u;
-
 let STORAGE_BITS: u32 = 32u; // sizeof(u32) * 8;
-let PACKED_WIDTH: u32  = 2u; // (GFX_WIDTH_DEFAULT / STORAGE_BITS)
+
+let PLANE_SIZE: u32 = 256u; // (GFX_WIDTH_HIGH / STORAGE_BITS) * GFX_HEIGHT_HIGH
 
 [[block]]
 struct GfxState {
-    pixels: array<u32, 64>; // (PACKED_WIDTH * GFX_HEIGHT_DEFAULT)
+    pixels: array<u32, 512>; // PLANE_SIZE * PLANE_NUM
 };
 [[group(0), binding(0)]]
 var<uniform> gfx_state: GfxState;
 
+[[block]]
+struct GfxParams {
+    resolution: vec2<u32>;
+    palette: array<vec4<f32>, 4>;
+};
+[[group(0), binding(1)]]
+var<uniform> gfx_params: GfxParams;
+
 struct DataPos {
     col: u32;
     nibble: u32;
@@ -43,8 +48,9 @@ struct DataPos {
 
 fn get_bucket(x: u32, y: u32) -> DataPos {
     var real_x = u32(x / STORAGE_BITS);
+    var packed_width = gfx_params.resolution.x / STORAGE_BITS;
     var pos: DataPos;
-    pos.col = (y * PACKED_WIDTH) + real_x;
+    pos.col = (y * packed_width) + real_x;
     pos.nibble = (STORAGE_BITS * (real_x + 1u)) - x - 1u;
     return pos;
 }
@@ -54,8 +60,8 @@ fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
     var st = (in.position + vec2<f32>(1.0, 1.0)) * 0.5;
     var color = vec3<f32>(st.x, 0.0, st.y); // colorize!
 
-    var width = 64.0;
-    var height = 32.0;
+    var width = f32(gfx_params.resolution.x);
+    var height = f32(gfx_params.resolution.y);
 
     var x: u32 = u32(floor(width * st.x));
     var y: u32 = u32(floor(height * (1.0 - st.y)));
@@ -64,7 +70,12 @@ fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
     var mask = 1u << bucket.nibble;
     var col = bucket.col;
 
-    color = color * f32(clamp(gfx_state.pixels[col] & mask, 0u, 1u));
+    // 2-bit palette index, plane 0 is the low bit
+    var low = clamp(gfx_state.pixels[col] & mask, 0u, 1u);
+    var high = clamp(gfx_state.pixels[col + PLANE_SIZE] & mask, 0u, 1u);
+    var index = low | (high << 1u);
+
+    color = color * gfx_params.palette[index].rgb;
 
     return vec4<f32>(color, 1.0);
 }
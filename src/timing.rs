@@ -1,72 +1,186 @@
-use std::{
-    cmp,
-    time::{Duration, Instant},
-};
+use std::time::{Duration, Instant};
 
 use spin_sleep::SpinSleeper;
 
+use crate::cli::parse_tickrate;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// Frames per second everywhere, which also clocks the delay and sound timers
+pub const FRAME_RATE: u64 = 60;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+// Frames that are run back to back to catch up after a hitch, anything older is dropped
+const MAX_FRAMES_BEHIND: u64 = 4;
+
+// What decides how many instructions a frame runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pacing {
+    // Instructions per second, never 0
+    Rate(u64),
+    // COSMAC VIP machine cycles, see cycles.rs
+    VipCycles,
+}
+
+impl Pacing {
+    // What the binaries take for --tickrate, vip runs by VIP cycles
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "vip" => Ok(Pacing::VipCycles),
+            _ => parse_tickrate(value).map(Pacing::Rate),
+        }
+    }
+
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        match *self {
+            Pacing::Rate(tickrate) => {
+                writer.write_u8(0);
+                writer.write_u64(tickrate);
+            }
+            Pacing::VipCycles => writer.write_u8(1),
+        }
+    }
+
+    pub fn read_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        match reader.read_u8()? {
+            0 => match reader.read_u64()? {
+                0 => Err(SaveStateError::InvalidValue("tickrate")),
+                tickrate => Ok(Pacing::Rate(tickrate)),
+            },
+            1 => Ok(Pacing::VipCycles),
+            _ => Err(SaveStateError::InvalidValue("pacing")),
+        }
+    }
+}
+
 pub struct Timing {
     pub tickrate: u64,
     pub framerate: u64,
-    last_tick: Instant,
-    last_frame: Instant,
+    // Frame deadlines are counted in nanoseconds from here, so their rounding never adds up
+    epoch: Instant,
+    frames: u64,
+    // Leftover ticks (in 1/framerate units) carried over to the next frame
+    tick_remainder: u64,
     sleeper: SpinSleeper,
 }
 
 impl Timing {
     pub fn new(tickrate: u64, framerate: u64) -> Self {
-        let now = Instant::now();
         Self {
             tickrate,
             framerate,
-            last_tick: now,
-            last_frame: now,
+            epoch: Instant::now(),
+            frames: 0,
+            tick_remainder: 0,
             sleeper: SpinSleeper::default(),
         }
     }
 
-    pub fn should_tick(&self) -> bool {
-        self.calc_next_tick() == 0
-    }
     pub fn should_draw(&self) -> bool {
-        self.calc_next_frame() == 0
+        self.calc_next_frame() == Duration::ZERO
     }
 
-    pub fn mark_tick(&mut self) {
-        self.last_tick = Instant::now();
-    }
     pub fn mark_draw(&mut self) {
-        self.last_frame = Instant::now();
+        self.mark_draw_at(Instant::now());
+    }
+
+    // Instructions to execute this frame, the fractional part is carried over
+    // so that the average rate matches the tickrate exactly
+    pub fn take_frame_ticks(&mut self) -> u64 {
+        // Split up front, so no tickrate can overflow
+        let owed = self.tick_remainder + self.tickrate % self.framerate;
+        self.tick_remainder = owed % self.framerate;
+        self.tickrate / self.framerate + owed / self.framerate
+    }
+
+    // Movies rely on every run splitting instructions into frames the same way
+    pub fn reset_frame_ticks(&mut self) {
+        self.tick_remainder = 0;
     }
 
     pub fn try_sleep(&self) {
-        let sleep_for = self.calc_sleep_duration();
-        if sleep_for > 0 {
+        let sleep_for = self.calc_next_frame();
+        if sleep_for > Duration::ZERO {
             // accounts for platform dependent sleep resolution
-            self.sleeper.sleep(Duration::from_millis(sleep_for));
+            self.sleeper.sleep(sleep_for);
         }
     }
 
-    fn calc_next_tick(&self) -> u64 {
-        calc_next_timeout(&self.last_tick, 1000 / self.tickrate)
+    fn mark_draw_at(&mut self, now: Instant) {
+        // Late frames stay owed and run back to back, unless we fell too far behind
+        self.frames += 1;
+        let behind = now.saturating_duration_since(self.frame_deadline(self.frames));
+        if behind > self.frame_offset(MAX_FRAMES_BEHIND) {
+            self.epoch = now;
+            self.frames = 0;
+        }
     }
 
-    fn calc_next_frame(&self) -> u64 {
-        calc_next_timeout(&self.last_frame, 1000 / self.framerate)
+    fn calc_next_frame(&self) -> Duration {
+        self.frame_deadline(self.frames + 1)
+            .saturating_duration_since(Instant::now())
     }
 
-    fn calc_sleep_duration(&self) -> u64 {
-        cmp::min(self.calc_next_frame(), self.calc_next_tick())
+    fn frame_deadline(&self, frame: u64) -> Instant {
+        self.epoch + self.frame_offset(frame)
+    }
+
+    fn frame_offset(&self, frame: u64) -> Duration {
+        let nanos = frame as u128 * NANOS_PER_SEC / self.framerate as u128;
+        Duration::from_nanos(nanos as u64)
     }
 }
 
-#[inline]
-fn calc_next_timeout(last: &Instant, timeout: u64) -> u64 {
-    // Thats 5849424 centuries of sleeping, give or take
-    let elapsed = last.elapsed().as_millis() as u64;
-    if timeout > elapsed {
-        timeout - elapsed
-    } else {
-        0
+#[cfg(test)]
+mod tests {
+    use super::{Pacing, Timing};
+    use std::time::Duration;
+
+    #[test]
+    fn pacing_test() {
+        assert_eq!(Pacing::parse("vip"), Ok(Pacing::VipCycles));
+        assert_eq!(Pacing::parse("0x3E8"), Ok(Pacing::Rate(1000)));
+        assert!(Pacing::parse("0").is_err());
+        assert!(Pacing::parse("VIP").is_err());
+    }
+
+    #[test]
+    fn frame_ticks_test() {
+        let mut timing = Timing::new(100, 60);
+        let ticks: u64 = (0..60).map(|_| timing.take_frame_ticks()).sum();
+        assert_eq!(ticks, 100);
+
+        timing.tickrate = 1000;
+        let ticks: Vec<u64> = (0..3).map(|_| timing.take_frame_ticks()).collect();
+        assert_eq!(ticks, vec![16, 17, 17]);
+
+        timing.tickrate = 5_000_000;
+        let ticks: u64 = (0..60).map(|_| timing.take_frame_ticks()).sum();
+        assert_eq!(ticks, 5_000_000);
+
+        timing.tickrate = u64::MAX;
+        let ticks: Vec<u64> = (0..3).map(|_| timing.take_frame_ticks()).collect();
+        assert_eq!(ticks, vec![u64::MAX / 60; 3]);
+        assert_eq!(timing.tick_remainder, u64::MAX % 60 * 3);
+    }
+
+    #[test]
+    fn frame_deadline_test() {
+        let mut timing = Timing::new(700, 60);
+        let epoch = timing.epoch;
+        assert_eq!(timing.frame_offset(1), Duration::from_nanos(16_666_666));
+        // Exact after any number of frames, where adding up 16.666666ms would drift
+        assert_eq!(timing.frame_offset(3), Duration::from_millis(50));
+        assert_eq!(timing.frame_offset(60 * 3600), Duration::from_secs(3600));
+
+        // A frame late, the next one is already due
+        timing.mark_draw_at(epoch + timing.frame_offset(2));
+        assert_eq!(timing.frames, 1);
+        assert_eq!(timing.epoch, epoch);
+
+        // Too far behind to catch up, the clock starts over
+        let now = epoch + Duration::from_secs(1);
+        timing.mark_draw_at(now);
+        assert_eq!(timing.frames, 0);
+        assert_eq!(timing.epoch, now);
     }
 }
@@ -0,0 +1,235 @@
+// One line per executed instruction, with the machine state from before it ran:
+//   CYCLE PC OPCODE MNEMONIC V0 .. VF I=.. SP=.. DT=.. ST=..
+// Columns are fixed width, so traces of two runs can be diffed line by line
+
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::cli::parse_number;
+use crate::opcode::Listing;
+use crate::vm::VM;
+
+// Writes to stdout instead of a file
+pub const STDOUT_PATH: &str = "-";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TraceFilter {
+    // Only instructions in this range are written, both ends included
+    pub range: Option<(u16, u16)>,
+    // Lines written before the trace stops
+    pub limit: Option<u64>,
+}
+
+pub struct Tracer {
+    output: Box<dyn Write + Send>,
+    filter: TraceFilter,
+    // Counts every instruction since tracing started, filtered out or not
+    cycle: u64,
+    lines: u64,
+    is_done: bool,
+}
+
+// START-END, numbers are decimal or 0x prefixed hex
+pub fn parse_range(spec: &str) -> Result<(u16, u16), String> {
+    let parse_address = |value: &str| {
+        let address = parse_number(value)?;
+        u16::try_from(address).map_err(|_| format!("Invalid address '{}'", value))
+    };
+    match spec.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse_address(start)?, parse_address(end)?);
+            if start > end {
+                return Err(format!("Range '{}' ends before it starts", spec));
+            }
+            Ok((start, end))
+        }
+        None => Err(format!("Expected START-END, got '{}'", spec)),
+    }
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write + Send>, filter: TraceFilter) -> Self {
+        Self {
+            output,
+            filter,
+            cycle: 0,
+            lines: 0,
+            is_done: false,
+        }
+    }
+
+    // STDOUT_PATH or a file, which is truncated
+    pub fn create(path: &str, filter: TraceFilter) -> io::Result<Self> {
+        let output: Box<dyn Write + Send> = if path == STDOUT_PATH {
+            Box::new(BufWriter::new(io::stdout()))
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        Ok(Self::new(output, filter))
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(err) = self.output.flush() {
+            eprintln!("Failed to write the trace: {}", err);
+        }
+    }
+
+    // Called by the VM before it fetches each instruction
+    pub(crate) fn record(&mut self, vm: &VM) {
+        let cycle = self.cycle;
+        self.cycle += 1;
+        if self.is_done {
+            return;
+        }
+        let pc = vm.get_program_counter();
+        if matches!(self.filter.range, Some((start, end)) if pc < start || pc > end) {
+            return;
+        }
+
+        let result = writeln!(self.output, "{}", format_line(cycle, vm));
+        self.lines += 1;
+        if let Err(err) = result {
+            eprintln!("Failed to write the trace, stopping it: {}", err);
+            self.is_done = true;
+        } else if self.filter.limit == Some(self.lines) {
+            self.is_done = true;
+            self.flush();
+        }
+    }
+}
+
+fn format_line(cycle: u64, vm: &VM) -> String {
+    let pc = vm.get_program_counter();
+    let listing = Listing::read(pc, |address| vm.read_word(address));
+
+    let mut line = format!(
+        "{:010} {:04X} {:<8} {:<24}",
+        cycle, pc, listing.raw, listing.mnemonic
+    );
+    for value in vm.get_registers() {
+        let _ = write!(line, " {:02X}", value);
+    }
+    let _ = write!(
+        line,
+        "  I={:04X} SP={:02} DT={:02X} ST={:02X}",
+        vm.get_index_register(),
+        vm.get_stack().len(),
+        vm.get_delay_timer(),
+        vm.get_sound_timer()
+    );
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range, TraceFilter, Tracer};
+    use crate::{keypad::KeypadState, quirks::Quirks, vm::VM};
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    // Lets the test read back what the VM wrote
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(rom: &[u8], filter: TraceFilter, ticks: usize) -> Vec<String> {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_seed(rom, Quirks::default(), 0);
+        vm.set_tracer(Some(Tracer::new(Box::new(buffer.clone()), filter)));
+        let keypad = KeypadState::new();
+        for _ in 0..ticks {
+            let _ = vm.tick(&keypad);
+        }
+        let output = buffer.0.lock().unwrap().clone();
+        String::from_utf8(output)
+            .expect("Text trace")
+            .lines()
+            .map(|line| line.trim_end().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn trace_test() {
+        let rom = [
+            0x60, 0x2A, // 0x200 LD V0, 0x2A
+            0xF0, 0x00, 0x12, 0x34, // 0x202 LD I, LONG 0x1234
+            0x22, 0x0A, // 0x206 CALL 0x20A
+            0x12, 0x08, // 0x208 JP 0x208
+            0x00, 0xEE, // 0x20A RET
+        ];
+        let lines = trace(&rom, TraceFilter::default(), 6);
+        let line = |start: &str, registers: &str, end: &str| {
+            format!(
+                "{} {} 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00  {}",
+                start, registers, end
+            )
+        };
+        assert_eq!(
+            lines,
+            vec![
+                line(
+                    "0000000000 0200 602A     LD V0, 0x2A             ",
+                    "00",
+                    "I=0000 SP=00 DT=00 ST=00"
+                ),
+                line(
+                    "0000000001 0202 F0001234 LD I, LONG 0x1234       ",
+                    "2A",
+                    "I=0000 SP=00 DT=00 ST=00"
+                ),
+                line(
+                    "0000000002 0206 220A     CALL 0x20A              ",
+                    "2A",
+                    "I=1234 SP=00 DT=00 ST=00"
+                ),
+                line(
+                    "0000000003 020A 00EE     RET                     ",
+                    "2A",
+                    "I=1234 SP=01 DT=00 ST=00"
+                ),
+                line(
+                    "0000000004 0208 1208     JP 0x208                ",
+                    "2A",
+                    "I=1234 SP=00 DT=00 ST=00"
+                ),
+                line(
+                    "0000000005 0208 1208     JP 0x208                ",
+                    "2A",
+                    "I=1234 SP=00 DT=00 ST=00"
+                ),
+            ]
+        );
+
+        // Cycles keep counting outside of the range
+        let filter = TraceFilter {
+            range: Some((0x206, 0x20A)),
+            limit: Some(2),
+        };
+        let cycles: Vec<String> = trace(&rom, filter, 6)
+            .iter()
+            .map(|line| line[..10].to_string())
+            .collect();
+        assert_eq!(cycles, vec!["0000000002", "0000000003"]);
+    }
+
+    #[test]
+    fn parse_range_test() {
+        assert_eq!(parse_range("0x200-0x2FF"), Ok((0x200, 0x2FF)));
+        assert_eq!(parse_range("512-512"), Ok((0x200, 0x200)));
+        assert!(parse_range("0x300-0x200").is_err());
+        assert!(parse_range("0x200").is_err());
+        assert!(parse_range("0x200-0x10000").is_err());
+    }
+}
@@ -1,14 +1,33 @@
-use rand::Rng;
+mod blocks;
 
-use crate::display::{DisplayState, ModificationData};
+use crate::cycles::CycleBudget;
+use crate::display::{DisplayState, ModificationData, PLANE_NUM};
+use crate::fault::VmFault;
 use crate::keypad::KeypadState;
 use crate::memory::{Memory, Stack};
-use crate::opcode::OpCode;
+use crate::opcode::{OpCode, INSTRUCTION_SIZE};
+use crate::profiler::Profiler;
+use crate::quirks::{IndexIncrement, Quirks};
+use crate::rng::{self, VmRng};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::trace::Tracer;
+use crate::watchpoint::Watchpoints;
 
 // Registers
 pub const REGISTER_NUM: usize = 16;
+// SCHIP user flags (HP48 RPL)
+pub const RPL_FLAGS_NUM: usize = 8;
 
-const INSTRUCTION_SIZE: u16 = 2;
+// Only the lowest nibble of Vx selects a key
+const KEY_MASK: usize = 0xF;
+
+// XO-CHIP F000 NNNN, the only instruction spanning two words
+const LONG_INSTRUCTION_PREFIX: u16 = 0xF000;
+
+// XO-CHIP audio pitch register default, which plays back at 4000Hz
+const DEFAULT_PITCH: u8 = 64;
+// XO-CHIP audio pattern buffer, 128 1-bit samples
+pub const AUDIO_PATTERN_SIZE: usize = 16;
 
 // Fontset
 const FONTSET: [u8; 5 * 16] = [
@@ -30,6 +49,26 @@ const FONTSET: [u8; 5 * 16] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+// SCHIP large fontset (8x10), A-F as per Octo
+const FONTSET_LARGE: [u8; 10 * 16] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFE, 0xFF, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xC3, 0xFF, 0xFE, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
 pub struct VM {
     memory: Memory,
     registers: [u8; REGISTER_NUM],
@@ -39,28 +78,65 @@ pub struct VM {
     sound_timer: u8,
     stack: Stack,
     display: DisplayState,
-    //
-    rng: rand::rngs::ThreadRng,
+    rpl_flags: [u8; RPL_FLAGS_NUM],
+    pitch: u8,
+    audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    quirks: Quirks,
+    fault: Option<VmFault>,
+    // Seeded once per VM, so runs can be reproduced
+    rng: VmRng,
+    // Cycles owed to the next frame when running by VIP cycles, saved along with the rest
+    cycles: CycleBudget,
+    // Off unless a tool turns it on, like watchpoints it isn't saved
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    // Compiled blocks by start address, for Engine::Blocks
+    blocks: blocks::Blocks,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    Interpreter,
+    // Compiles straight runs of instructions into closures, see vm/blocks.rs
+    Blocks,
+}
+
+impl Engine {
+    // The names the binaries take for --engine
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "interpreter" => Ok(Engine::Interpreter),
+            "blocks" => Ok(Engine::Blocks),
+            _ => Err(format!("Unknown engine '{}'", name)),
+        }
+    }
 }
 
 #[derive(PartialEq)]
 enum InstructionResult {
     Nop,
     Next,
+    NextLong,
     Skip,
     Jump(u16),
+    Fault(VmFault),
 }
 
 impl VM {
     // Pub
 
-    pub fn new(rom_data: &[u8]) -> Self {
+    pub fn new(rom_data: &[u8], quirks: Quirks) -> Self {
+        Self::with_seed(rom_data, quirks, rng::random_seed())
+    }
+
+    pub fn with_seed(rom_data: &[u8], quirks: Quirks, seed: u64) -> Self {
         let mut memory = Memory::new();
         memory.load_font(&FONTSET);
+        memory.load_large_font(&FONTSET_LARGE);
         let program_counter = memory.load_rom(rom_data);
 
         let mut display_state = DisplayState::new();
-        display_state.clear(false); // Fill the first frame
+        display_state.clear_all(false); // Fill the first frame
 
         VM {
             memory,
@@ -71,15 +147,81 @@ impl VM {
             sound_timer: 0,
             stack: Stack::new(),
             display: display_state,
-            rng: rand::thread_rng(),
+            rpl_flags: [0; RPL_FLAGS_NUM],
+            pitch: DEFAULT_PITCH,
+            audio_pattern: None,
+            quirks,
+            fault: None,
+            rng: VmRng::from_state(seed),
+            cycles: CycleBudget::new(),
+            tracer: None,
+            profiler: None,
+            blocks: blocks::Blocks::new(),
         }
     }
 
-    pub fn tick(&mut self, keypad: &KeypadState) {
-        let opcode = OpCode::from_bytes(self.memory.get16(self.program_counter.into()));
+    pub fn tick(&mut self, keypad: &KeypadState) -> Result<(), VmFault> {
+        // A faulted VM stays halted on the faulting instruction
+        if let Some(fault) = self.fault {
+            return Err(fault);
+        }
 
-        self.execute(opcode, keypad);
+        if self.tracer.is_some() {
+            self.trace();
+        }
+        self.memory.watchpoints_mut().set_pc(self.program_counter);
+        let pc = self.program_counter;
+        let depth = self.stack.as_slice().len();
+        match self.fetch() {
+            Ok(opcode) => {
+                self.execute(opcode, keypad);
+                if self.profiler.is_some() {
+                    self.profile(pc, opcode, depth);
+                }
+            }
+            Err(fault) => self.fault = Some(fault),
+        }
+
+        match self.fault {
+            Some(fault) => Err(fault),
+            None => Ok(()),
+        }
+    }
+
+    // The same as ticks calls to tick, returns how many instructions ran without faulting
+    pub fn run(&mut self, engine: Engine, keypad: &KeypadState, ticks: u64) -> u64 {
+        // Tracing, profiling and watchpoints need every instruction to go through tick
+        let is_instrumented = self.tracer.is_some()
+            || self.profiler.is_some()
+            || !self.memory.watchpoints().is_empty();
+        match engine {
+            Engine::Blocks if !is_instrumented => self.run_blocks(keypad, ticks),
+            _ => {
+                let mut executed = 0;
+                while executed < ticks && self.tick(keypad).is_ok() {
+                    executed += 1;
+                }
+                executed
+            }
+        }
+    }
+
+    // One frame by COSMAC VIP cycles (see cycles.rs), step runs the next instruction
+    // and returns false to stop early. Returns the instructions executed
+    pub fn run_frame_cycles(&mut self, step: impl FnMut(&mut VM) -> bool) -> u64 {
+        let mut cycles = std::mem::take(&mut self.cycles);
+        let executed = cycles.run_frame(self, step);
+        self.cycles = cycles;
+        executed
+    }
+
+    // Owed cycles only mean something while the pacing stays the same
+    pub fn reset_cycles(&mut self) {
+        self.cycles = CycleBudget::new();
+    }
 
+    pub fn tick_timers(&mut self) {
+        // Driven at 60Hz, independently of the instruction rate
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
@@ -89,7 +231,15 @@ impl VM {
         }
     }
 
-    pub fn pop_display_modifications(&mut self) -> Option<ModificationData> {
+    pub fn is_halted(&self) -> bool {
+        self.fault.is_some()
+    }
+
+    pub fn get_fault(&self) -> Option<VmFault> {
+        self.fault
+    }
+
+    pub fn pop_display_modifications(&mut self) -> Option<ModificationData<'_>> {
         self.display.pop_modifications()
     }
 
@@ -97,20 +247,273 @@ impl VM {
         self.sound_timer > 0
     }
 
+    pub fn get_audio_pattern(&self) -> Option<&[u8; AUDIO_PATTERN_SIZE]> {
+        // None until the rom loads one, in which case the default beep is used
+        self.audio_pattern.as_ref()
+    }
+
+    pub fn get_pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    pub fn get_registers(&self) -> &[u8; REGISTER_NUM] {
+        &self.registers
+    }
+
+    pub fn get_index_register(&self) -> u16 {
+        self.index_register
+    }
+
+    pub fn get_program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn get_delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn get_sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn get_display(&self) -> &DisplayState {
+        &self.display
+    }
+
+    // Return addresses are the CALL instructions themselves, RET steps past them
+    pub fn get_stack(&self) -> &[u16] {
+        self.stack.as_slice()
+    }
+
+    // The instruction about to run, None if it would fault
+    pub fn peek_opcode(&self) -> Option<OpCode> {
+        let pc = self.check_program_counter().ok()?;
+        OpCode::from_bytes(self.memory.peek16(pc.into()))
+    }
+
+    // The whole address space is readable, MEM_SIZE covers every u16
+    // Neither of these trips a watchpoint
+    pub fn read_memory(&self, address: u16) -> u8 {
+        self.memory.peek8(address.into())
+    }
+
+    // Big endian, wraps around the end of memory
+    pub fn read_word(&self, address: u16) -> u16 {
+        (self.read_memory(address) as u16) << 8 | self.read_memory(address.wrapping_add(1)) as u16
+    }
+
+    pub fn write_memory(&mut self, address: u16, value: u8) {
+        self.memory.poke8(address.into(), value);
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        self.memory.watchpoints()
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        self.memory.watchpoints_mut()
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // On by default, turning it off decodes every instruction as it's fetched
+    pub fn set_instruction_cache(&mut self, is_enabled: bool) {
+        self.memory.set_decode_cache(is_enabled);
+    }
+
+    // Memory marks data reads for the profiler while it's attached
+    pub fn set_profiler(&mut self, mut profiler: Option<Profiler>) {
+        if let Some(old) = &mut self.profiler {
+            old.swap_data_reads(self.memory.data_reads_mut());
+        }
+        if let Some(new) = &mut profiler {
+            new.swap_data_reads(self.memory.data_reads_mut());
+        }
+        self.profiler = profiler;
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        let mut profiler = self.profiler.take();
+        if let Some(profiler) = &mut profiler {
+            profiler.swap_data_reads(self.memory.data_reads_mut());
+        }
+        profiler
+    }
+
+    pub fn set_register(&mut self, idx: usize, value: u8) {
+        self.registers[idx] = value;
+    }
+
+    pub fn set_index_register(&mut self, value: u16) {
+        self.index_register = value;
+    }
+
+    // Moving a halted VM somewhere else lets it run again
+    pub fn set_program_counter(&mut self, value: u16) {
+        self.program_counter = value;
+        self.fault = None;
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+
+    pub fn get_quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // Snapshot of the whole machine, see savestate.rs for the container format
+    // A fault isn't stored, restoring a halted VM just faults again on the next tick
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.memory.write_state(&mut writer);
+        writer.write_bytes(&self.registers);
+        writer.write_u16(self.index_register);
+        writer.write_u16(self.program_counter);
+        writer.write_u8(self.delay_timer);
+        writer.write_u8(self.sound_timer);
+        self.stack.write_state(&mut writer);
+        self.display.write_state(&mut writer);
+        writer.write_bytes(&self.rpl_flags);
+        writer.write_u8(self.pitch);
+        writer.write_bool(self.audio_pattern.is_some());
+        writer.write_bytes(&self.audio_pattern.unwrap_or_default());
+        self.quirks.write_state(&mut writer);
+        writer.write_u64(self.rng.get_state());
+        self.cycles.write_state(&mut writer);
+        writer.finish()
+    }
+
+    pub fn from_state(data: &[u8]) -> Result<Self, SaveStateError> {
+        let mut reader = StateReader::new(data)?;
+        let memory = Memory::read_state(&mut reader)?;
+        let mut registers = [0; REGISTER_NUM];
+        registers.copy_from_slice(reader.read_bytes(REGISTER_NUM)?);
+        let index_register = reader.read_u16()?;
+        let program_counter = reader.read_u16()?;
+        let delay_timer = reader.read_u8()?;
+        let sound_timer = reader.read_u8()?;
+        let stack = Stack::read_state(&mut reader)?;
+        let display = DisplayState::read_state(&mut reader)?;
+        let mut rpl_flags = [0; RPL_FLAGS_NUM];
+        rpl_flags.copy_from_slice(reader.read_bytes(RPL_FLAGS_NUM)?);
+        let pitch = reader.read_u8()?;
+        let has_audio_pattern = reader.read_bool()?;
+        let mut audio_pattern = [0; AUDIO_PATTERN_SIZE];
+        audio_pattern.copy_from_slice(reader.read_bytes(AUDIO_PATTERN_SIZE)?);
+        let quirks = Quirks::read_state(&mut reader)?;
+        let rng = VmRng::from_state(reader.read_u64()?);
+        let cycles = CycleBudget::read_state(&mut reader)?;
+        reader.finish()?;
+
+        Ok(VM {
+            memory,
+            registers,
+            index_register,
+            program_counter,
+            delay_timer,
+            sound_timer,
+            stack,
+            display,
+            rpl_flags,
+            pitch,
+            audio_pattern: if has_audio_pattern {
+                Some(audio_pattern)
+            } else {
+                None
+            },
+            quirks,
+            fault: None,
+            rng,
+            cycles,
+            tracer: None,
+            profiler: None,
+            blocks: blocks::Blocks::new(),
+        })
+    }
+
     // Priv
 
+    // Kept out of tick, which only pays for the check when tracing is off
+    #[cold]
+    #[inline(never)]
+    fn trace(&mut self) {
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.record(self);
+            self.tracer = Some(tracer);
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn profile(&mut self, pc: u16, opcode: OpCode, depth: usize) {
+        if let Some(mut profiler) = self.profiler.take() {
+            profiler.record(pc, opcode, depth, self);
+            self.profiler = Some(profiler);
+        }
+    }
+
+    #[inline]
+    fn fetch(&mut self) -> Result<OpCode, VmFault> {
+        let pc = self.check_program_counter()?;
+        self.memory
+            .fetch_opcode(pc.into())
+            .ok_or_else(|| VmFault::UnknownOpcode {
+                pc,
+                opcode: self.memory.peek16(pc.into()),
+            })
+    }
+
+    #[inline]
+    fn check_program_counter(&self) -> Result<u16, VmFault> {
+        self.check_instruction_address(self.program_counter)
+    }
+
+    #[inline]
+    fn check_instruction_address(&self, pc: u16) -> Result<u16, VmFault> {
+        // Instructions are word aligned
+        if pc & 1 != 0 {
+            return Err(VmFault::PcMisaligned { pc });
+        }
+        self.check_memory_range(pc.into(), INSTRUCTION_SIZE.into())?;
+        Ok(pc)
+    }
+
     #[inline]
     fn execute(&mut self, opcode: OpCode, keypad: &KeypadState) {
         use OpCode::*;
         let result = match opcode {
             NOP() => self.nop(),
+            SCDN(nibble) => self.scdn(nibble),
+            SCUN(nibble) => self.scun(nibble),
             CLS() => self.cls(),
             RET() => self.ret(),
+            SCR() => self.scr(),
+            SCL() => self.scl(),
+            EXIT() => self.exit(),
+            LOW() => self.low(),
+            HIGH() => self.high(),
             JP(addr) => self.jp(addr),
             CALL(addr) => self.call(addr),
             SEVB(x, byte) => self.sevb(x.into(), byte),
             SNEVB(x, byte) => self.snevb(x.into(), byte),
             SEVV(x, y) => self.sevv(x.into(), y.into()),
+            LDIVV(x, y) => self.ldivv(x.into(), y.into()),
+            LDVVI(x, y) => self.ldvvi(x.into(), y.into()),
             LDVB(x, byte) => self.ldvb(x.into(), byte),
             ADDVB(x, byte) => self.addvb(x.into(), byte),
             LDVV(x, y) => self.ldvv(x.into(), y.into()),
@@ -129,36 +532,134 @@ impl VM {
             DRWVVN(x, y, nibble) => self.drwvvn(x.into(), y.into(), nibble),
             SKPV(x) => self.skpv(x.into(), keypad),
             SKNPV(x) => self.sknpv(x.into(), keypad),
+            LDIL() => self.ldil(),
+            PLANEN(nibble) => self.planen(nibble),
+            AUDIO() => self.audio(),
             LDVDT(x) => self.ldvdt(x.into()),
             LDVK(x) => self.ldvk(x.into(), keypad),
             LDDTV(x) => self.lddtv(x.into()),
             LDSTV(x) => self.ldstv(x.into()),
             ADDIV(x) => self.addiv(x.into()),
             LDFV(x) => self.ldfv(x.into()),
+            LDHFV(x) => self.ldhfv(x.into()),
             LDBV(x) => self.ldbv(x.into()),
+            LDPV(x) => self.ldpv(x.into()),
             LDIV(x) => self.ldiv(x.into()),
             LDVI(x) => self.ldvi(x.into()),
+            LDRV(x) => self.ldrv(x.into()),
+            LDVR(x) => self.ldvr(x.into()),
         };
+        self.apply(result);
+    }
 
+    #[inline]
+    fn apply(&mut self, result: InstructionResult) {
         match result {
             InstructionResult::Nop => (),
-            InstructionResult::Next => self.program_counter += INSTRUCTION_SIZE,
-            InstructionResult::Skip => self.program_counter += INSTRUCTION_SIZE * 2,
+            InstructionResult::Next => self.advance(INSTRUCTION_SIZE),
+            InstructionResult::NextLong => self.advance(INSTRUCTION_SIZE * 2),
+            InstructionResult::Skip => {
+                self.advance(INSTRUCTION_SIZE + self.next_instruction_size())
+            }
             InstructionResult::Jump(addr) => self.program_counter = addr,
+            InstructionResult::Fault(fault) => self.fault = Some(fault),
         }
     }
 
+    #[inline]
+    fn advance(&mut self, offset: u16) {
+        let pc = self.program_counter;
+        match pc.checked_add(offset) {
+            Some(next) => self.program_counter = next,
+            None => {
+                self.fault = Some(VmFault::AddressOutOfRange {
+                    pc,
+                    address: pc as usize + offset as usize,
+                })
+            }
+        }
+    }
+
+    #[inline]
+    fn next_instruction_size(&self) -> u16 {
+        // Skips have to step over both words of a long instruction
+        let next_addr = self.program_counter as usize + INSTRUCTION_SIZE as usize;
+        if self
+            .memory
+            .is_valid_range(next_addr, INSTRUCTION_SIZE.into())
+            && self.memory.peek16(next_addr) == LONG_INSTRUCTION_PREFIX
+        {
+            INSTRUCTION_SIZE * 2
+        } else {
+            INSTRUCTION_SIZE
+        }
+    }
+
+    #[inline]
+    fn check_memory_range(&self, address: usize, len: usize) -> Result<(), VmFault> {
+        if self.memory.is_valid_range(address, len) {
+            Ok(())
+        } else {
+            Err(VmFault::AddressOutOfRange {
+                pc: self.program_counter,
+                address: address + len.saturating_sub(1),
+            })
+        }
+    }
+
+    #[inline]
+    fn shift_source(&self, vx_idx: usize, vy_idx: usize) -> usize {
+        if self.quirks.shift_uses_vy {
+            vy_idx
+        } else {
+            vx_idx
+        }
+    }
+
+    #[inline]
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.registers[0xF] = 0;
+        }
+    }
+
+    #[inline]
+    fn increment_index_after_load_store(&mut self, vx_idx: usize) {
+        let increment = match self.quirks.load_store_index {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::X => vx_idx as u16,
+            IndexIncrement::XPlusOne => vx_idx as u16 + 1,
+        };
+        self.index_register = self.index_register.wrapping_add(increment);
+    }
+
     #[inline]
     fn nop(&self) -> InstructionResult {
         // 0nnn - SYS addr
         // NOP on modern interpreters
-        InstructionResult::Nop
+        InstructionResult::Next
+    }
+
+    #[inline]
+    fn scdn(&mut self, nibble: u8) -> InstructionResult {
+        // 00Cn - SCD nibble
+        // Scroll display n lines down.
+        self.display.scroll_down(nibble.into());
+        InstructionResult::Next
+    }
+
+    #[inline]
+    fn scun(&mut self, nibble: u8) -> InstructionResult {
+        // 00Dn - SCU nibble
+        // Scroll display n lines up.
+        self.display.scroll_up(nibble.into());
+        InstructionResult::Next
     }
 
     #[inline]
     fn cls(&mut self) -> InstructionResult {
         // 00E0 - CLS
-        // Clear the display.
+        // Clear the display (selected planes only).
         self.display.clear(false);
         InstructionResult::Next
     }
@@ -167,7 +668,53 @@ impl VM {
     fn ret(&mut self) -> InstructionResult {
         // 00EE - RET
         // Return from a subroutine.
-        self.program_counter = self.stack.pop();
+        match self.stack.pop() {
+            Some(addr) => {
+                self.program_counter = addr;
+                InstructionResult::Next
+            }
+            None => InstructionResult::Fault(VmFault::StackUnderflow {
+                pc: self.program_counter,
+            }),
+        }
+    }
+
+    #[inline]
+    fn scr(&mut self) -> InstructionResult {
+        // 00FB - SCR
+        // Scroll display 4 pixels right.
+        self.display.scroll_right(4);
+        InstructionResult::Next
+    }
+
+    #[inline]
+    fn scl(&mut self) -> InstructionResult {
+        // 00FC - SCL
+        // Scroll display 4 pixels left.
+        self.display.scroll_left(4);
+        InstructionResult::Next
+    }
+
+    #[inline]
+    fn exit(&self) -> InstructionResult {
+        // 00FD - EXIT
+        // Exit the interpreter, which we do by never advancing past this instruction.
+        InstructionResult::Nop
+    }
+
+    #[inline]
+    fn low(&mut self) -> InstructionResult {
+        // 00FE - LOW
+        // Disable extended screen mode.
+        self.display.set_high_res(false);
+        InstructionResult::Next
+    }
+
+    #[inline]
+    fn high(&mut self) -> InstructionResult {
+        // 00FF - HIGH
+        // Enable extended screen mode for full-screen graphics.
+        self.display.set_high_res(true);
         InstructionResult::Next
     }
 
@@ -181,8 +728,12 @@ impl VM {
     #[inline]
     fn call(&mut self, addr: u16) -> InstructionResult {
         // 2nnn - CALL addr
-        // self.cpu.call(arg);
-        self.stack.push(self.program_counter);
+        // Call subroutine at nnn.
+        if !self.stack.push(self.program_counter) {
+            return InstructionResult::Fault(VmFault::StackOverflow {
+                pc: self.program_counter,
+            });
+        }
         InstructionResult::Jump(addr)
     }
 
@@ -219,6 +770,39 @@ impl VM {
         }
     }
 
+    #[inline]
+    fn ldivv(&mut self, vx_idx: usize, vy_idx: usize) -> InstructionResult {
+        // 5xy2 - LD [I], Vx-Vy
+        // Store registers Vx through Vy (in either direction) in memory starting at location I.
+        if let Err(fault) =
+            self.check_memory_range(self.index_register.into(), vx_idx.abs_diff(vy_idx) + 1)
+        {
+            return InstructionResult::Fault(fault);
+        }
+        for (offset, reg_idx) in register_range(vx_idx, vy_idx).enumerate() {
+            self.memory.set8(
+                self.index_register as usize + offset,
+                self.registers[reg_idx],
+            );
+        }
+        InstructionResult::Next
+    }
+
+    #[inline]
+    fn ldvvi(&mut self, vx_idx: usize, vy_idx: usize) -> InstructionResult {
+        // 5xy3 - LD Vx-Vy, [I]
+        // Read registers Vx through Vy (in either direction) from memory starting at location I.
+        if let Err(fault) =
+            self.check_memory_range(self.index_register.into(), vx_idx.abs_diff(vy_idx) + 1)
+        {
+            return InstructionResult::Fault(fault);
+        }
+        for (offset, reg_idx) in register_range(vx_idx, vy_idx).enumerate() {
+            self.registers[reg_idx] = self.memory.get8(self.index_register as usize + offset);
+        }
+        InstructionResult::Next
+    }
+
     #[inline]
     fn ldvb(&mut self, vx_idx: usize, byte: u8) -> InstructionResult {
         // 6xkk - LD Vx, byte
@@ -248,6 +832,7 @@ impl VM {
         // 8xy1 - OR Vx, Vy
         // Set Vx = Vx OR Vy.
         self.registers[vx_idx] |= self.registers[vy_idx];
+        self.reset_vf_after_logic();
         InstructionResult::Next
     }
 
@@ -256,6 +841,7 @@ impl VM {
         // 8xy2 - AND Vx, Vy
         // Set Vx = Vx AND Vy.
         self.registers[vx_idx] &= self.registers[vy_idx];
+        self.reset_vf_after_logic();
         InstructionResult::Next
     }
 
@@ -264,6 +850,7 @@ impl VM {
         // 8xy3 - XOR Vx, Vy
         // Set Vx = Vx XOR Vy.
         self.registers[vx_idx] ^= self.registers[vy_idx];
+        self.reset_vf_after_logic();
         InstructionResult::Next
     }
 
@@ -288,17 +875,12 @@ impl VM {
     }
 
     #[inline]
-    fn shrvv(&mut self, vx_idx: usize, _vy_idx: usize) -> InstructionResult {
+    fn shrvv(&mut self, vx_idx: usize, vy_idx: usize) -> InstructionResult {
         // 8xy6 - SHR Vx {, Vy}
-        // Set Vx = Vx SHR 1.
-
-        if self.registers[vx_idx] & 1 != 0 {
-            self.registers[0xF] = 1
-        } else {
-            self.registers[0xF] = 0
-        }
-
-        self.registers[vx_idx] /= 2;
+        // Set Vx = Vx SHR 1 (or Vx = Vy SHR 1, depending on quirks).
+        let value = self.registers[self.shift_source(vx_idx, vy_idx)];
+        self.registers[vx_idx] = value / 2;
+        self.registers[0xF] = value & 1;
         InstructionResult::Next
     }
 
@@ -313,17 +895,12 @@ impl VM {
     }
 
     #[inline]
-    fn shlvv(&mut self, vx_idx: usize, _vy_idx: usize) -> InstructionResult {
+    fn shlvv(&mut self, vx_idx: usize, vy_idx: usize) -> InstructionResult {
         // 8xyE - SHL Vx {, Vy}
-        // Set Vx = Vx SHL 1.
-
-        if self.registers[vx_idx] & (1 << 7) != 0 {
-            self.registers[0xF] = 1
-        } else {
-            self.registers[0xF] = 0
-        }
-
-        self.registers[vx_idx] = self.registers[vx_idx].overflowing_mul(2).0;
+        // Set Vx = Vx SHL 1 (or Vx = Vy SHL 1, depending on quirks).
+        let value = self.registers[self.shift_source(vx_idx, vy_idx)];
+        self.registers[vx_idx] = value.overflowing_mul(2).0;
+        self.registers[0xF] = value >> 7;
         InstructionResult::Next
     }
 
@@ -349,36 +926,81 @@ impl VM {
     #[inline]
     fn jpva(&self, addr: u16) -> InstructionResult {
         // Bnnn - JP V0, addr
-        // Jump to location nnn + V0.
-        InstructionResult::Jump(addr + self.registers[0x0] as u16)
+        // Jump to location nnn + V0 (or xnn + Vx, depending on quirks).
+        let offset_idx = if self.quirks.jump_uses_vx {
+            (addr >> 8) as usize
+        } else {
+            0x0
+        };
+        InstructionResult::Jump(addr + self.registers[offset_idx] as u16)
     }
 
     #[inline]
     fn rndvb(&mut self, vx_idx: usize, byte: u8) -> InstructionResult {
         // Cxkk - RND Vx, byte
         // Set Vx = random byte AND kk.
-        let num: u8 = self.rng.gen_range(0..255);
-        self.registers[vx_idx] = num & byte;
+        self.registers[vx_idx] = self.rng.next_u8() & byte;
         InstructionResult::Next
     }
 
     #[inline]
     fn drwvvn(&mut self, vx_idx: usize, vy_idx: usize, nibble: u8) -> InstructionResult {
+        // Dxyn - DRW Vx, Vy, nibble
+        // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
+        // Dxy0 - DRW Vx, Vy, 0
+        // Draw a 16x16 sprite (two bytes per row) instead.
+        // With both XO-CHIP planes selected, the second plane's sprite follows the first one.
+        // Sprites wrap around the screen edges, unless clipped by quirks.
+        let (rows, row_bytes) = if nibble == 0 {
+            (16, 2)
+        } else {
+            (nibble as usize, 1)
+        };
+
+        let selected_planes = (0..PLANE_NUM)
+            .filter(|plane| self.display.is_plane_selected(*plane))
+            .count();
+        if let Err(fault) = self.check_memory_range(
+            self.index_register.into(),
+            rows * row_bytes * selected_planes,
+        ) {
+            return InstructionResult::Fault(fault);
+        }
+
         self.registers[0xF] = 0;
         let (gfx_width, gfx_height) = self.display.get_current_mode();
+        // The starting position always wraps
+        let origin_x = self.registers[vx_idx] as usize % gfx_width;
+        let origin_y = self.registers[vy_idx] as usize % gfx_height;
+        let clip = self.quirks.clip_sprites;
+        let mut sprite_addr = self.index_register as usize;
+
+        for plane in 0..PLANE_NUM {
+            if !self.display.is_plane_selected(plane) {
+                continue;
+            }
 
-        for byte in 0..nibble {
-            let y = (self.registers[vy_idx].overflowing_add(byte).0) % gfx_height as u8;
-            for bit in 0..8 {
-                let x = (self.registers[vx_idx].overflowing_add(bit).0) % gfx_width as u8;
-                let color =
-                    (self.memory.get8((self.index_register + byte as u16).into()) >> (7 - bit)) & 1;
-
-                let current_pixel_state = self.display.get(x.into(), y.into()) as u8;
-                self.registers[0x0f] |= color & current_pixel_state;
-                self.display
-                    .set(x.into(), y.into(), (current_pixel_state ^ color) != 0);
+            for row in 0..rows {
+                if clip && origin_y + row >= gfx_height {
+                    break;
+                }
+                let y = (origin_y + row) % gfx_height;
+                for bit in 0..row_bytes * 8 {
+                    if clip && origin_x + bit >= gfx_width {
+                        break;
+                    }
+                    let x = (origin_x + bit) % gfx_width;
+                    let address = sprite_addr + row * row_bytes + bit / 8;
+                    let color = (self.memory.get8(address) >> (7 - bit % 8)) & 1;
+
+                    let current_pixel_state = self.display.get(plane, x, y) as u8;
+                    self.registers[0x0f] |= color & current_pixel_state;
+                    self.display
+                        .set(plane, x, y, (current_pixel_state ^ color) != 0);
+                }
             }
+
+            sprite_addr += rows * row_bytes;
         }
 
         InstructionResult::Next
@@ -388,7 +1010,7 @@ impl VM {
     fn skpv(&self, vx_idx: usize, keypad: &KeypadState) -> InstructionResult {
         // Ex9E - SKP Vx
         // Skip next instruction if key with the value of Vx is pressed.
-        if keypad.state[self.registers[vx_idx] as usize] {
+        if keypad.state[self.registers[vx_idx] as usize & KEY_MASK] {
             return InstructionResult::Skip;
         }
         InstructionResult::Next
@@ -398,12 +1020,48 @@ impl VM {
     fn sknpv(&self, vx_idx: usize, keypad: &KeypadState) -> InstructionResult {
         // ExA1 - SKNP Vx
         // Skip next instruction if key with the value of Vx is not pressed.
-        if !keypad.state[self.registers[vx_idx] as usize] {
+        if !keypad.state[self.registers[vx_idx] as usize & KEY_MASK] {
             return InstructionResult::Skip;
         }
         InstructionResult::Next
     }
 
+    #[inline]
+    fn ldil(&mut self) -> InstructionResult {
+        // F000 nnnn - LD I, long addr
+        // Set I = the 16-bit address stored in the following word.
+        let operand_addr = self.program_counter as usize + INSTRUCTION_SIZE as usize;
+        if let Err(fault) = self.check_memory_range(operand_addr, INSTRUCTION_SIZE.into()) {
+            return InstructionResult::Fault(fault);
+        }
+        self.index_register = self.memory.get16(operand_addr);
+        InstructionResult::NextLong
+    }
+
+    #[inline]
+    fn planen(&mut self, nibble: u8) -> InstructionResult {
+        // Fn01 - PLANE nibble
+        // Select drawing planes by bitmask (0 - 3).
+        self.display.select_planes(nibble);
+        InstructionResult::Next
+    }
+
+    #[inline]
+    fn audio(&mut self) -> InstructionResult {
+        // F002 - AUDIO
+        // Load the 16-byte audio pattern buffer from memory starting at location I.
+        if let Err(fault) = self.check_memory_range(self.index_register.into(), AUDIO_PATTERN_SIZE)
+        {
+            return InstructionResult::Fault(fault);
+        }
+        let mut pattern = [0; AUDIO_PATTERN_SIZE];
+        for (i, sample) in pattern.iter_mut().enumerate() {
+            *sample = self.memory.get8(self.index_register as usize + i);
+        }
+        self.audio_pattern = Some(pattern);
+        InstructionResult::Next
+    }
+
     #[inline]
     fn ldvdt(&mut self, vx_idx: usize) -> InstructionResult {
         // Fx07 - LD Vx, DT
@@ -445,7 +1103,9 @@ impl VM {
     fn addiv(&mut self, vx_idx: usize) -> InstructionResult {
         // Fx1E - ADD I, Vx
         // Set I = I + Vx.
-        self.index_register += self.registers[vx_idx] as u16;
+        self.index_register = self
+            .index_register
+            .wrapping_add(self.registers[vx_idx] as u16);
         InstructionResult::Next
     }
 
@@ -459,10 +1119,23 @@ impl VM {
         InstructionResult::Next
     }
 
+    #[inline]
+    fn ldhfv(&mut self, vx_idx: usize) -> InstructionResult {
+        // Fx30 - LD HF, Vx
+        // Set I = location of the 10-byte large sprite for digit Vx.
+        self.index_register = self
+            .memory
+            .get_large_font_sprite_location(self.registers[vx_idx].into());
+        InstructionResult::Next
+    }
+
     #[inline]
     fn ldbv(&mut self, vx_idx: usize) -> InstructionResult {
         // Fx33 - LD B, Vx
         // Store BCD representation of Vx in memory locations I, I+1, and I+2.
+        if let Err(fault) = self.check_memory_range(self.index_register.into(), 3) {
+            return InstructionResult::Fault(fault);
+        }
         self.memory
             .set8(self.index_register.into(), self.registers[vx_idx] / 100);
         self.memory.set8(
@@ -477,16 +1150,26 @@ impl VM {
         InstructionResult::Next
     }
 
+    #[inline]
+    fn ldpv(&mut self, vx_idx: usize) -> InstructionResult {
+        // Fx3A - LD PITCH, Vx
+        // Set audio pitch register = Vx.
+        self.pitch = self.registers[vx_idx];
+        InstructionResult::Next
+    }
+
     #[inline]
     fn ldiv(&mut self, vx_idx: usize) -> InstructionResult {
         // Fx55 - LD [I], Vx
         // Store registers V0 through Vx in memory starting at location I.
+        if let Err(fault) = self.check_memory_range(self.index_register.into(), vx_idx + 1) {
+            return InstructionResult::Fault(fault);
+        }
         for i in 0..=vx_idx {
-            self.memory.set8(
-                (self.index_register + i as u16).into(),
-                self.registers[i as usize],
-            )
+            self.memory
+                .set8((self.index_register + i as u16).into(), self.registers[i])
         }
+        self.increment_index_after_load_store(vx_idx);
         InstructionResult::Next
     }
 
@@ -494,17 +1177,59 @@ impl VM {
     fn ldvi(&mut self, vx_idx: usize) -> InstructionResult {
         // Fx65 - LD Vx, [I]
         // Read registers V0 through Vx from memory starting at location I.
+        if let Err(fault) = self.check_memory_range(self.index_register.into(), vx_idx + 1) {
+            return InstructionResult::Fault(fault);
+        }
         for i in 0..=vx_idx {
             self.registers[i] = self.memory.get8((self.index_register + i as u16).into());
         }
+        self.increment_index_after_load_store(vx_idx);
+        InstructionResult::Next
+    }
+
+    #[inline]
+    fn ldrv(&mut self, vx_idx: usize) -> InstructionResult {
+        // Fx75 - LD R, Vx
+        // Store V0 through Vx in RPL user flags (x <= 7).
+        let last = vx_idx.min(RPL_FLAGS_NUM - 1);
+        self.rpl_flags[..=last].copy_from_slice(&self.registers[..=last]);
+        InstructionResult::Next
+    }
+
+    #[inline]
+    fn ldvr(&mut self, vx_idx: usize) -> InstructionResult {
+        // Fx85 - LD Vx, R
+        // Read V0 through Vx from RPL user flags (x <= 7).
+        let last = vx_idx.min(RPL_FLAGS_NUM - 1);
+        self.registers[..=last].copy_from_slice(&self.rpl_flags[..=last]);
         InstructionResult::Next
     }
 }
 
+#[inline]
+fn register_range(from: usize, to: usize) -> impl Iterator<Item = usize> {
+    // Ranges may go either direction, the first register is always stored first
+    let count = from.abs_diff(to) + 1;
+    (0..count).map(move |offset| {
+        if from <= to {
+            from + offset
+        } else {
+            from - offset
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::{InstructionResult, INSTRUCTION_SIZE, VM};
-    use crate::{keypad::KeypadState, opcode::OpCode};
+    use crate::{
+        cycles::CycleBudget,
+        fault::VmFault,
+        keypad::KeypadState,
+        opcode::OpCode,
+        quirks::{IndexIncrement, Quirks},
+        savestate::SaveStateError,
+    };
 
     // Test helper
     fn execute_opcode(vm: &mut VM, opcode: OpCode) {
@@ -513,15 +1238,17 @@ mod tests {
 
     #[test]
     fn nop_test() {
-        let vm = VM::new(&[]);
+        let vm = VM::new(&[], Quirks::default());
         // Since vm is not mut it can not change
         assert!(vm.nop() == InstructionResult::Next);
     }
 
     #[test]
     fn cls_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
+        // Flush initial clear()
+        vm.display.pop_modifications();
 
         execute_opcode(&mut vm, OpCode::CLS());
 
@@ -538,7 +1265,7 @@ mod tests {
 
     #[test]
     fn call_ret_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let call_addr = 0x0;
         let init_addr = vm.program_counter;
         assert!(call_addr != init_addr);
@@ -551,7 +1278,7 @@ mod tests {
 
     #[test]
     fn jp_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let jp_addr = 0x0;
 
         execute_opcode(&mut vm, OpCode::JP(jp_addr));
@@ -560,7 +1287,7 @@ mod tests {
 
     #[test]
     fn sevb_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0x10;
 
@@ -575,7 +1302,7 @@ mod tests {
 
     #[test]
     fn snevb_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0x10;
 
@@ -590,7 +1317,7 @@ mod tests {
 
     #[test]
     fn sevv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0x10;
         vm.registers[0x1] = 0x10;
@@ -606,7 +1333,7 @@ mod tests {
 
     #[test]
     fn ldvb_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let new_value = 0x11;
         vm.registers[0x0] = 0x10;
@@ -617,7 +1344,7 @@ mod tests {
 
     #[test]
     fn addvb_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let add_value = 0x1;
         let init_value = 0x2;
@@ -629,7 +1356,7 @@ mod tests {
 
     #[test]
     fn ldvv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0x10;
         vm.registers[0x1] = 0x11;
@@ -640,7 +1367,7 @@ mod tests {
 
     #[test]
     fn orvv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let init_v0 = 0xA;
         vm.registers[0x0] = init_v0;
@@ -652,7 +1379,7 @@ mod tests {
 
     #[test]
     fn andvv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let init_v0 = 0xA;
         vm.registers[0x0] = init_v0;
@@ -664,7 +1391,7 @@ mod tests {
 
     #[test]
     fn xorvv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let init_v0 = 0xA;
         vm.registers[0x0] = init_v0;
@@ -676,7 +1403,7 @@ mod tests {
 
     #[test]
     fn addvv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0xA;
         vm.registers[0x1] = 0xB;
@@ -684,14 +1411,14 @@ mod tests {
         let (result, carry) = vm.registers[0x0].overflowing_add(vm.registers[0x1]);
 
         execute_opcode(&mut vm, OpCode::ADDVV(0x0, 0x1));
-        assert_eq!(vm.registers[0x0], result as u8);
+        assert_eq!(vm.registers[0x0], result);
         assert_eq!(vm.registers[0xF], carry as u8);
         assert_eq!(init_addr + INSTRUCTION_SIZE, vm.program_counter);
     }
 
     #[test]
     fn subvv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0xA;
         vm.registers[0x1] = 0xB;
@@ -699,14 +1426,14 @@ mod tests {
         let (result, carry) = vm.registers[0x0].overflowing_sub(vm.registers[0x1]);
 
         execute_opcode(&mut vm, OpCode::SUBVV(0x0, 0x1));
-        assert_eq!(vm.registers[0x0], result as u8);
+        assert_eq!(vm.registers[0x0], result);
         assert_eq!(vm.registers[0xF], !carry as u8);
         assert_eq!(init_addr + INSTRUCTION_SIZE, vm.program_counter);
     }
 
     #[test]
     fn shrvv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let init_value = 0xA;
         vm.registers[0x2] = init_value;
@@ -726,7 +1453,7 @@ mod tests {
 
     #[test]
     fn subnvv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0xA;
         vm.registers[0x1] = 0xB;
@@ -734,14 +1461,14 @@ mod tests {
         let (result, carry) = vm.registers[0x1].overflowing_sub(vm.registers[0x0]);
 
         execute_opcode(&mut vm, OpCode::SUBNVV(0x0, 0x1));
-        assert_eq!(vm.registers[0x0], result as u8);
+        assert_eq!(vm.registers[0x0], result);
         assert_eq!(vm.registers[0xF], !carry as u8);
         assert_eq!(init_addr + INSTRUCTION_SIZE, vm.program_counter);
     }
 
     #[test]
     fn shlvv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let init_value = 0xA;
         vm.registers[0x2] = init_value;
@@ -761,7 +1488,7 @@ mod tests {
 
     #[test]
     fn snevv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0xA;
         vm.registers[0x1] = 0xA;
@@ -777,7 +1504,7 @@ mod tests {
 
     #[test]
     fn ldia_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let new_value = 0x11;
         vm.index_register = 0x10;
@@ -788,7 +1515,7 @@ mod tests {
 
     #[test]
     fn jpva_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         vm.registers[0x0] = 0xA;
         let jp_addr = 0x1;
 
@@ -798,19 +1525,26 @@ mod tests {
 
     #[test]
     fn rndvb_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::with_seed(&[], Quirks::default(), 0);
+        let mut other_vm = VM::with_seed(&[], Quirks::default(), 0);
         let init_addr = vm.program_counter;
-        let init_val = 0xF;
-        vm.registers[0x0] = init_val;
 
-        execute_opcode(&mut vm, OpCode::RNDVB(0x0, 0xA));
-        assert_ne!(vm.registers[0x0], init_val);
-        assert_eq!(init_addr + INSTRUCTION_SIZE, vm.program_counter);
+        // First two bytes of the seed 0 sequence are 0xE2 and 0x6E
+        execute_opcode(&mut vm, OpCode::RNDVB(0x0, 0xFF));
+        assert_eq!(vm.registers[0x0], 0xE2);
+        execute_opcode(&mut vm, OpCode::RNDVB(0x1, 0x0F));
+        assert_eq!(vm.registers[0x1], 0x0E);
+        assert_eq!(init_addr + INSTRUCTION_SIZE * 2, vm.program_counter);
+
+        // Same seed, same results
+        execute_opcode(&mut other_vm, OpCode::RNDVB(0x0, 0xFF));
+        execute_opcode(&mut other_vm, OpCode::RNDVB(0x1, 0x0F));
+        assert_eq!(vm.registers, other_vm.registers);
     }
 
     #[test]
     fn drwvvn_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         // Flush initial clear()
         vm.display.pop_modifications();
@@ -827,7 +1561,7 @@ mod tests {
         let mut keypad_state = KeypadState::new();
         keypad_state.state[0xA] = true;
 
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0xA;
 
@@ -845,7 +1579,7 @@ mod tests {
         let mut keypad_state = KeypadState::new();
         keypad_state.state[0xA] = true;
 
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0xA;
 
@@ -860,7 +1594,7 @@ mod tests {
 
     #[test]
     fn lddtv_ldvdt_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let timer_val = 0xA;
 
@@ -880,7 +1614,7 @@ mod tests {
         let mut keypad_state = KeypadState::new();
         keypad_state.state[0xA] = true;
 
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0x0] = 0xA;
 
@@ -895,7 +1629,7 @@ mod tests {
 
     #[test]
     fn ldstv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let timer_val = 0xA;
 
@@ -908,7 +1642,7 @@ mod tests {
 
     #[test]
     fn addiv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         let start_value = 0x1;
         let add_value = 0x3;
@@ -921,7 +1655,7 @@ mod tests {
 
     #[test]
     fn ldfv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
         vm.registers[0xA] = 0x3;
 
@@ -932,7 +1666,7 @@ mod tests {
 
     #[test]
     fn ldbv_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
 
         vm.registers[0xA] = 0x10;
@@ -956,35 +1690,498 @@ mod tests {
 
     #[test]
     fn ldiv_ldvi_test() {
-        let mut vm = VM::new(&[]);
+        let mut vm = VM::new(&[], Quirks::default());
         let init_addr = vm.program_counter;
-        let max_reg = 0x4 as u8;
+        let max_reg = 0x4_u8;
         vm.index_register = 0xAA;
 
         for i in 0x0..=max_reg {
-            vm.registers[0x0 + i as usize] = i as u8 + 0x10;
+            vm.registers[i as usize] = i + 0x10;
         }
 
         execute_opcode(&mut vm, OpCode::LDIV(max_reg));
         for i in 0x0..=max_reg {
             assert_eq!(
                 vm.memory.get8((vm.index_register + i as u16) as usize),
-                vm.registers[0x0 + i as usize]
+                vm.registers[i as usize]
             );
         }
         assert_eq!(init_addr + INSTRUCTION_SIZE, vm.program_counter);
 
         for i in 0x0..=max_reg {
-            vm.registers[0x0 + i as usize] = 0x0;
+            vm.registers[i as usize] = 0x0;
         }
 
         execute_opcode(&mut vm, OpCode::LDVI(max_reg));
         for i in 0x0..=max_reg {
             assert_eq!(
                 vm.memory.get8((vm.index_register + i as u16) as usize),
-                vm.registers[0x0 + i as usize]
+                vm.registers[i as usize]
             );
         }
         assert_eq!(init_addr + INSTRUCTION_SIZE * 2, vm.program_counter);
     }
+
+    #[test]
+    fn low_high_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        let init_addr = vm.program_counter;
+
+        execute_opcode(&mut vm, OpCode::HIGH());
+        assert_eq!(vm.display.get_current_mode(), (128, 64));
+        execute_opcode(&mut vm, OpCode::LOW());
+        assert_eq!(vm.display.get_current_mode(), (64, 32));
+        assert_eq!(init_addr + INSTRUCTION_SIZE * 2, vm.program_counter);
+    }
+
+    #[test]
+    fn scdn_scr_scl_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        let init_addr = vm.program_counter;
+        vm.display.set(0, 8, 8, true);
+
+        execute_opcode(&mut vm, OpCode::SCDN(0x3));
+        assert!(vm.display.get(0, 8, 11));
+        execute_opcode(&mut vm, OpCode::SCR());
+        assert!(vm.display.get(0, 12, 11));
+        execute_opcode(&mut vm, OpCode::SCL());
+        assert!(vm.display.get(0, 8, 11));
+        execute_opcode(&mut vm, OpCode::SCUN(0x3));
+        assert!(vm.display.get(0, 8, 8));
+        assert_eq!(init_addr + INSTRUCTION_SIZE * 4, vm.program_counter);
+    }
+
+    #[test]
+    fn exit_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        let init_addr = vm.program_counter;
+
+        execute_opcode(&mut vm, OpCode::EXIT());
+        assert_eq!(init_addr, vm.program_counter);
+    }
+
+    #[test]
+    fn drwvv0_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        execute_opcode(&mut vm, OpCode::HIGH());
+        vm.index_register = 0x300;
+        for i in 0..32 {
+            vm.memory.set8(0x300 + i, 0xFF);
+        }
+
+        execute_opcode(&mut vm, OpCode::DRWVVN(0x0, 0x0, 0x0));
+        assert_eq!(vm.registers[0xF], 0);
+        for x in 0..16 {
+            for y in 0..16 {
+                assert!(vm.display.get(0, x, y));
+            }
+        }
+        assert!(!vm.display.get(0, 16, 0));
+        assert!(!vm.display.get(0, 0, 16));
+
+        execute_opcode(&mut vm, OpCode::DRWVVN(0x0, 0x0, 0x0));
+        assert_eq!(vm.registers[0xF], 1);
+        assert!(!vm.display.get(0, 15, 15));
+    }
+
+    #[test]
+    fn ldhfv_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        let init_addr = vm.program_counter;
+        vm.registers[0xA] = 0x3;
+
+        execute_opcode(&mut vm, OpCode::LDHFV(0xA));
+        assert_eq!(vm.index_register, 0x0A0 + (10 * vm.registers[0xA]) as u16);
+        assert_eq!(vm.memory.get8(vm.index_register as usize), 0x3C);
+        assert_eq!(init_addr + INSTRUCTION_SIZE, vm.program_counter);
+    }
+
+    #[test]
+    fn ldrv_ldvr_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        let init_addr = vm.program_counter;
+        let max_reg = 0x7_u8;
+
+        for i in 0x0..=max_reg {
+            vm.registers[i as usize] = i + 0x10;
+        }
+
+        execute_opcode(&mut vm, OpCode::LDRV(max_reg));
+        for i in 0x0..=max_reg {
+            vm.registers[i as usize] = 0x0;
+        }
+
+        execute_opcode(&mut vm, OpCode::LDVR(max_reg));
+        for i in 0x0..=max_reg {
+            assert_eq!(vm.registers[i as usize], i + 0x10);
+        }
+        assert_eq!(init_addr + INSTRUCTION_SIZE * 2, vm.program_counter);
+    }
+
+    #[test]
+    fn ldivv_ldvvi_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        let init_addr = vm.program_counter;
+        vm.index_register = 0xAA;
+        vm.registers[0x2] = 0x12;
+        vm.registers[0x3] = 0x13;
+        vm.registers[0x4] = 0x14;
+
+        execute_opcode(&mut vm, OpCode::LDIVV(0x2, 0x4));
+        assert_eq!(vm.memory.get8(0xAA), 0x12);
+        assert_eq!(vm.memory.get8(0xAC), 0x14);
+        // I is left untouched
+        assert_eq!(vm.index_register, 0xAA);
+        assert_eq!(init_addr + INSTRUCTION_SIZE, vm.program_counter);
+
+        // Reversed range loads backwards
+        execute_opcode(&mut vm, OpCode::LDVVI(0x7, 0x5));
+        assert_eq!(vm.registers[0x7], 0x12);
+        assert_eq!(vm.registers[0x6], 0x13);
+        assert_eq!(vm.registers[0x5], 0x14);
+        assert_eq!(init_addr + INSTRUCTION_SIZE * 2, vm.program_counter);
+    }
+
+    #[test]
+    fn ldil_test() {
+        let mut vm = VM::new(&[0xF0, 0x00, 0xBE, 0xEF], Quirks::default());
+        let init_addr = vm.program_counter;
+
+        execute_opcode(&mut vm, OpCode::LDIL());
+        assert_eq!(vm.index_register, 0xBEEF);
+        assert_eq!(init_addr + INSTRUCTION_SIZE * 2, vm.program_counter);
+
+        // The whole 64KiB address space is available
+        vm.memory.set8(0xBEEF, 0xAB);
+        execute_opcode(&mut vm, OpCode::LDVI(0x0));
+        assert_eq!(vm.registers[0x0], 0xAB);
+    }
+
+    #[test]
+    fn skip_long_test() {
+        let mut vm = VM::new(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34], Quirks::default());
+        let init_addr = vm.program_counter;
+
+        // Skips both words of F000 nnnn
+        execute_opcode(&mut vm, OpCode::SEVB(0x0, 0x0));
+        assert_eq!(init_addr + INSTRUCTION_SIZE * 3, vm.program_counter);
+    }
+
+    #[test]
+    fn ldpv_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        let init_addr = vm.program_counter;
+        assert_eq!(vm.pitch, 64);
+        vm.registers[0x1] = 0x70;
+
+        execute_opcode(&mut vm, OpCode::LDPV(0x1));
+        assert_eq!(vm.pitch, 0x70);
+        assert_eq!(init_addr + INSTRUCTION_SIZE, vm.program_counter);
+    }
+
+    #[test]
+    fn planen_drwvvn_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        let init_addr = vm.program_counter;
+        vm.index_register = 0x300;
+        // Plane 0 sprite row, followed by the plane 1 sprite row
+        vm.memory.set8(0x300, 0xF0);
+        vm.memory.set8(0x301, 0x0F);
+
+        execute_opcode(&mut vm, OpCode::PLANEN(0x3));
+        assert_eq!(vm.display.get_selected_planes(), 0x3);
+        execute_opcode(&mut vm, OpCode::DRWVVN(0x0, 0x0, 0x1));
+        assert_eq!(vm.display.get_pixel(0, 0), 0b01);
+        assert_eq!(vm.display.get_pixel(7, 0), 0b10);
+        assert_eq!(vm.registers[0xF], 0);
+
+        // Only plane 1 is drawn (from I), erasing its own pixels
+        execute_opcode(&mut vm, OpCode::PLANEN(0x2));
+        vm.index_register = 0x301;
+        execute_opcode(&mut vm, OpCode::DRWVVN(0x0, 0x0, 0x1));
+        assert_eq!(vm.display.get_pixel(0, 0), 0b01);
+        assert_eq!(vm.display.get_pixel(7, 0), 0b00);
+        assert_eq!(vm.registers[0xF], 1);
+
+        // Clearing leaves unselected planes untouched
+        execute_opcode(&mut vm, OpCode::CLS());
+        assert_eq!(vm.display.get_pixel(0, 0), 0b01);
+        assert_eq!(init_addr + INSTRUCTION_SIZE * 5, vm.program_counter);
+    }
+
+    #[test]
+    fn audio_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        let init_addr = vm.program_counter;
+        assert!(vm.get_audio_pattern().is_none());
+
+        vm.index_register = 0x300;
+        for i in 0..16 {
+            vm.memory.set8(0x300 + i, i as u8);
+        }
+
+        execute_opcode(&mut vm, OpCode::AUDIO());
+        let pattern = vm.get_audio_pattern().expect("No audio pattern");
+        assert_eq!(pattern[0], 0x0);
+        assert_eq!(pattern[15], 0xF);
+        assert_eq!(init_addr + INSTRUCTION_SIZE, vm.program_counter);
+    }
+
+    #[test]
+    fn shift_quirk_test() {
+        let mut vm = VM::new(&[], Quirks::cosmac_vip());
+        vm.registers[0x1] = 0x81;
+
+        execute_opcode(&mut vm, OpCode::SHRVV(0x0, 0x1));
+        assert_eq!(vm.registers[0x0], 0x40);
+        assert_eq!(vm.registers[0xF], 1);
+
+        execute_opcode(&mut vm, OpCode::SHLVV(0x0, 0x1));
+        assert_eq!(vm.registers[0x0], 0x02);
+        assert_eq!(vm.registers[0xF], 1);
+    }
+
+    #[test]
+    fn logic_quirk_test() {
+        let mut vm = VM::new(&[], Quirks::cosmac_vip());
+        vm.registers[0xF] = 0x1;
+        execute_opcode(&mut vm, OpCode::ORVV(0x0, 0x1));
+        assert_eq!(vm.registers[0xF], 0);
+
+        let mut vm = VM::new(&[], Quirks::schip());
+        vm.registers[0xF] = 0x1;
+        execute_opcode(&mut vm, OpCode::XORVV(0x0, 0x1));
+        assert_eq!(vm.registers[0xF], 1);
+    }
+
+    #[test]
+    fn load_store_quirk_test() {
+        for (quirks, expected) in [
+            (Quirks::schip(), 0x300),
+            (Quirks::chip48(), 0x303),
+            (Quirks::xochip(), 0x304),
+        ] {
+            let mut vm = VM::new(&[], quirks);
+            vm.index_register = 0x300;
+            execute_opcode(&mut vm, OpCode::LDIV(0x3));
+            assert_eq!(vm.index_register, expected);
+        }
+
+        let mut vm = VM::new(&[], Quirks::cosmac_vip());
+        assert_eq!(vm.quirks.load_store_index, IndexIncrement::XPlusOne);
+        vm.index_register = 0x300;
+        execute_opcode(&mut vm, OpCode::LDVI(0x0));
+        assert_eq!(vm.index_register, 0x301);
+    }
+
+    #[test]
+    fn jump_quirk_test() {
+        let mut vm = VM::new(&[], Quirks::schip());
+        vm.registers[0x0] = 0x1;
+        vm.registers[0x3] = 0x2;
+        execute_opcode(&mut vm, OpCode::JPVA(0x300));
+        assert_eq!(vm.program_counter, 0x302);
+
+        let mut vm = VM::new(&[], Quirks::cosmac_vip());
+        vm.registers[0x0] = 0x1;
+        vm.registers[0x3] = 0x2;
+        execute_opcode(&mut vm, OpCode::JPVA(0x300));
+        assert_eq!(vm.program_counter, 0x301);
+    }
+
+    #[test]
+    fn clip_quirk_test() {
+        for (quirks, wraps) in [(Quirks::schip(), false), (Quirks::xochip(), true)] {
+            let mut vm = VM::new(&[], quirks);
+            vm.index_register = 0x300;
+            vm.memory.set8(0x300, 0xFF);
+            vm.memory.set8(0x301, 0xFF);
+            vm.registers[0x0] = 60;
+            vm.registers[0x1] = 31;
+
+            execute_opcode(&mut vm, OpCode::DRWVVN(0x0, 0x1, 0x2));
+            assert!(vm.display.get(0, 63, 31));
+            assert_eq!(vm.display.get(0, 0, 31), wraps);
+            assert_eq!(vm.display.get(0, 60, 0), wraps);
+        }
+
+        // The starting position wraps regardless
+        let mut vm = VM::new(&[], Quirks::schip());
+        vm.index_register = 0x300;
+        vm.memory.set8(0x300, 0x80);
+        vm.registers[0x0] = 65;
+        vm.registers[0x1] = 33;
+        execute_opcode(&mut vm, OpCode::DRWVVN(0x0, 0x1, 0x1));
+        assert!(vm.display.get(0, 1, 1));
+    }
+
+    #[test]
+    fn unknown_opcode_fault_test() {
+        let mut vm = VM::new(&[0xFF, 0xFF], Quirks::default());
+        let fault = VmFault::UnknownOpcode {
+            pc: 0x200,
+            opcode: 0xFFFF,
+        };
+
+        assert_eq!(vm.tick(&KeypadState::new()), Err(fault));
+        assert!(vm.is_halted());
+        // Stays halted
+        assert_eq!(vm.tick(&KeypadState::new()), Err(fault));
+        assert_eq!(vm.program_counter, 0x200);
+    }
+
+    #[test]
+    fn stack_fault_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        execute_opcode(&mut vm, OpCode::RET());
+        assert_eq!(vm.get_fault(), Some(VmFault::StackUnderflow { pc: 0x200 }));
+
+        let mut vm = VM::new(&[], Quirks::default());
+        for _ in 0..16 {
+            execute_opcode(&mut vm, OpCode::CALL(0x200));
+        }
+        assert!(!vm.is_halted());
+        execute_opcode(&mut vm, OpCode::CALL(0x200));
+        assert_eq!(vm.get_fault(), Some(VmFault::StackOverflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn address_fault_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        vm.index_register = 0xFFFE;
+        execute_opcode(&mut vm, OpCode::LDIV(0x2));
+        assert_eq!(
+            vm.get_fault(),
+            Some(VmFault::AddressOutOfRange {
+                pc: 0x200,
+                address: 0x10000
+            })
+        );
+        // Nothing was written
+        assert_eq!(vm.memory.get8(0xFFFE), 0);
+
+        // Running off the end of memory
+        let mut vm = VM::new(&[], Quirks::default());
+        vm.memory.set8(0xFFFF, 0xE0);
+        vm.program_counter = 0xFFFE;
+        assert_eq!(
+            vm.tick(&KeypadState::new()),
+            Err(VmFault::AddressOutOfRange {
+                pc: 0xFFFE,
+                address: 0x10000
+            })
+        );
+    }
+
+    #[test]
+    fn pc_misaligned_fault_test() {
+        let mut vm = VM::new(&[], Quirks::default());
+        execute_opcode(&mut vm, OpCode::JP(0x201));
+        assert_eq!(
+            vm.tick(&KeypadState::new()),
+            Err(VmFault::PcMisaligned { pc: 0x201 })
+        );
+    }
+
+    #[test]
+    fn tick_timers_test() {
+        // 6000 - LD V0, 0x00 in a loop
+        let mut vm = VM::new(&[0x60, 0x00, 0x12, 0x00], Quirks::default());
+        vm.delay_timer = 0x2;
+        vm.sound_timer = 0x1;
+
+        for _ in 0..10 {
+            assert!(vm.tick(&KeypadState::new()).is_ok());
+        }
+        // Instructions don't affect the timers
+        assert_eq!(vm.delay_timer, 0x2);
+        assert!(vm.is_beeper_active());
+
+        vm.tick_timers();
+        assert_eq!(vm.delay_timer, 0x1);
+        assert!(!vm.is_beeper_active());
+
+        vm.tick_timers();
+        vm.tick_timers();
+        assert_eq!(vm.delay_timer, 0x0);
+    }
+
+    #[test]
+    fn self_modifying_code_test() {
+        let rom = [
+            0xA2, 0x05, // 0x200 LD I, 0x205
+            0x60, 0x05, // 0x202 LD V0, 0x05
+            0x71, 0x01, // 0x204 ADD V1, 0x01
+            0xF0, 0x55, // 0x206 LD [I], V0, which turns 0x204 into ADD V1, 0x05
+            0x12, 0x04, // 0x208 JP 0x204
+        ];
+        for is_cached in [true, false].iter() {
+            let mut vm = VM::new(&rom, Quirks::default());
+            vm.set_instruction_cache(*is_cached);
+            for _ in 0..6 {
+                assert!(vm.tick(&KeypadState::new()).is_ok());
+            }
+            assert_eq!(vm.registers[0x1], 0x06);
+
+            // Writes from outside of the VM count as well
+            vm.write_memory(0x204, 0x72);
+            vm.program_counter = 0x204;
+            assert!(vm.tick(&KeypadState::new()).is_ok());
+            assert_eq!(vm.registers[0x2], 0x05);
+        }
+    }
+
+    #[test]
+    fn save_state_test() {
+        // C0FF - RND V0, 0xFF, D015 - DRW V0, V1, 5, 2202 - CALL 0x202
+        let rom = [0xC0, 0xFF, 0xD0, 0x15, 0x22, 0x02];
+        let mut vm = VM::with_seed(&rom, Quirks::xochip(), 0x1234);
+        for _ in 0..6 {
+            assert!(vm.tick(&KeypadState::new()).is_ok());
+        }
+        vm.delay_timer = 0x10;
+        vm.pitch = 0x20;
+        vm.audio_pattern = Some([0xAA; super::AUDIO_PATTERN_SIZE]);
+        vm.display.set_high_res(true);
+        vm.display.set(1, 127, 63, true);
+        vm.pop_display_modifications();
+
+        let state = vm.save_state();
+        let mut restored = VM::from_state(&state).expect("Valid state");
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.get_quirks(), Quirks::xochip());
+        assert_eq!(restored.get_audio_pattern(), vm.get_audio_pattern());
+        assert!(restored.display.get(1, 127, 63));
+
+        // The whole screen is sent to the renderer
+        let modification = restored
+            .pop_display_modifications()
+            .expect("No modifications");
+        assert_eq!(modification.offset, 0);
+        assert_eq!(modification.data.len(), 512);
+        assert_eq!(modification.mode, (128, 64));
+
+        // Both machines carry on identically, random numbers included
+        for _ in 0..4 {
+            assert!(vm.tick(&KeypadState::new()).is_ok());
+            assert!(restored.tick(&KeypadState::new()).is_ok());
+        }
+        assert_eq!(restored.save_state(), vm.save_state());
+
+        let mut corrupted = state;
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xFF;
+        assert_eq!(
+            VM::from_state(&corrupted).err(),
+            Some(SaveStateError::ChecksumMismatch)
+        );
+
+        // Cycles owed to the next VIP frame are saved as well
+        // 7001 - ADD V0, 0x01, 1200 - JP 0x200, the last ADD runs over the frame
+        let mut vm = VM::with_seed(&[0x70, 0x01, 0x12, 0x00], Quirks::cosmac_vip(), 0);
+        vm.run_frame_cycles(|vm| vm.tick(&KeypadState::new()).is_ok());
+        assert_ne!(vm.cycles, CycleBudget::new());
+        let restored = VM::from_state(&vm.save_state()).expect("Valid state");
+        assert_eq!(restored.cycles, vm.cycles);
+    }
 }
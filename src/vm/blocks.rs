@@ -0,0 +1,281 @@
+// Engine::Blocks compiles the straight run of instructions from an address up to the next
+// jump, call, return or wait into one block of closures, with the operands already decoded.
+// Every step calls the same handler VM::execute would, and its result is applied the same
+// way, so both engines end up in the same state after the same number of instructions.
+
+use std::mem;
+
+use super::{InstructionResult, VM};
+use crate::keypad::KeypadState;
+use crate::memory::MEM_SIZE;
+use crate::opcode::{OpCode, INSTRUCTION_SIZE};
+
+// Longer runs are split into several blocks
+const MAX_BLOCK_LEN: usize = 64;
+
+type Step = Box<dyn Fn(&mut VM, &KeypadState) -> InstructionResult + Send>;
+
+// By start address, one slot per word, allocated when the engine first runs
+pub(super) type Blocks = Vec<Option<Block>>;
+
+pub(super) struct Block {
+    // Memory's code generation when the block was compiled, newer writes may have changed it
+    generation: u64,
+    // Each instruction with the address it falls through to
+    steps: Vec<(Step, u16)>,
+}
+
+impl VM {
+    pub(super) fn run_blocks(&mut self, keypad: &KeypadState, ticks: u64) -> u64 {
+        // Taken out so steps can borrow the VM mutably
+        let mut blocks = mem::take(&mut self.blocks);
+        if blocks.is_empty() {
+            blocks.resize_with(MEM_SIZE / INSTRUCTION_SIZE as usize, || None);
+        }
+        let mut executed = 0;
+
+        while executed < ticks && self.fault.is_none() {
+            let pc = self.program_counter;
+            let slot = pc as usize / INSTRUCTION_SIZE as usize;
+            let generation = self.memory.code_generation();
+            // Misaligned addresses share a slot, but never compile
+            let is_stale = match &blocks[slot] {
+                Some(block) => pc & 1 != 0 || block.generation != generation,
+                None => true,
+            };
+            if is_stale {
+                match self.compile(pc, generation) {
+                    Some(block) => blocks[slot] = Some(block),
+                    None => {
+                        // The instruction can't be fetched, tick faults on it like the interpreter
+                        let _ = self.tick(keypad);
+                        continue;
+                    }
+                }
+            }
+
+            let steps = match &blocks[slot] {
+                Some(block) => &block.steps,
+                None => continue,
+            };
+            for (step, next_pc) in steps.iter() {
+                let result = step(self, keypad);
+                self.apply(result);
+                if self.fault.is_some() {
+                    break;
+                }
+                executed += 1;
+                // Taken skips and waits leave the block, so do writes to code
+                if executed == ticks
+                    || self.program_counter != *next_pc
+                    || self.memory.code_generation() != generation
+                {
+                    break;
+                }
+            }
+        }
+
+        self.blocks = blocks;
+        executed
+    }
+
+    // None if the first instruction doesn't decode
+    fn compile(&mut self, start: u16, generation: u64) -> Option<Block> {
+        let mut steps = Vec::new();
+        let mut pc = start;
+        while steps.len() < MAX_BLOCK_LEN {
+            if self.check_instruction_address(pc).is_err() {
+                break;
+            }
+            let opcode = match self.memory.decode(pc.into()) {
+                Some(opcode) => opcode,
+                None => break,
+            };
+            // Running off the end of memory faults in apply, the block ends either way
+            let next_pc = pc.checked_add(opcode.size());
+            steps.push((compile_step(opcode), next_pc.unwrap_or(pc)));
+            match next_pc {
+                Some(next_pc) if !ends_block(opcode) => pc = next_pc,
+                _ => break,
+            }
+        }
+
+        if steps.is_empty() {
+            None
+        } else {
+            Some(Block { generation, steps })
+        }
+    }
+}
+
+// Instructions that never fall through, or wait on the same address
+fn ends_block(opcode: OpCode) -> bool {
+    use OpCode::*;
+    matches!(opcode, RET() | EXIT() | JP(_) | CALL(_) | JPVA(_) | LDVK(_))
+}
+
+// Gives the closures their parameter types
+fn step<F>(f: F) -> Step
+where
+    F: Fn(&mut VM, &KeypadState) -> InstructionResult + Send + 'static,
+{
+    Box::new(f)
+}
+
+// Mirrors the match in VM::execute
+fn compile_step(opcode: OpCode) -> Step {
+    use OpCode::*;
+    match opcode {
+        NOP() => step(|vm, _| vm.nop()),
+        SCDN(nibble) => step(move |vm, _| vm.scdn(nibble)),
+        SCUN(nibble) => step(move |vm, _| vm.scun(nibble)),
+        CLS() => step(|vm, _| vm.cls()),
+        RET() => step(|vm, _| vm.ret()),
+        SCR() => step(|vm, _| vm.scr()),
+        SCL() => step(|vm, _| vm.scl()),
+        EXIT() => step(|vm, _| vm.exit()),
+        LOW() => step(|vm, _| vm.low()),
+        HIGH() => step(|vm, _| vm.high()),
+        JP(addr) => step(move |vm, _| vm.jp(addr)),
+        CALL(addr) => step(move |vm, _| vm.call(addr)),
+        SEVB(x, byte) => step(move |vm, _| vm.sevb(x.into(), byte)),
+        SNEVB(x, byte) => step(move |vm, _| vm.snevb(x.into(), byte)),
+        SEVV(x, y) => step(move |vm, _| vm.sevv(x.into(), y.into())),
+        LDIVV(x, y) => step(move |vm, _| vm.ldivv(x.into(), y.into())),
+        LDVVI(x, y) => step(move |vm, _| vm.ldvvi(x.into(), y.into())),
+        LDVB(x, byte) => step(move |vm, _| vm.ldvb(x.into(), byte)),
+        ADDVB(x, byte) => step(move |vm, _| vm.addvb(x.into(), byte)),
+        LDVV(x, y) => step(move |vm, _| vm.ldvv(x.into(), y.into())),
+        ORVV(x, y) => step(move |vm, _| vm.orvv(x.into(), y.into())),
+        ANDVV(x, y) => step(move |vm, _| vm.andvv(x.into(), y.into())),
+        XORVV(x, y) => step(move |vm, _| vm.xorvv(x.into(), y.into())),
+        ADDVV(x, y) => step(move |vm, _| vm.addvv(x.into(), y.into())),
+        SUBVV(x, y) => step(move |vm, _| vm.subvv(x.into(), y.into())),
+        SHRVV(x, y) => step(move |vm, _| vm.shrvv(x.into(), y.into())),
+        SUBNVV(x, y) => step(move |vm, _| vm.subnvv(x.into(), y.into())),
+        SHLVV(x, y) => step(move |vm, _| vm.shlvv(x.into(), y.into())),
+        SNEVV(x, y) => step(move |vm, _| vm.snevv(x.into(), y.into())),
+        LDIA(addr) => step(move |vm, _| vm.ldia(addr)),
+        JPVA(addr) => step(move |vm, _| vm.jpva(addr)),
+        RNDVB(x, byte) => step(move |vm, _| vm.rndvb(x.into(), byte)),
+        DRWVVN(x, y, nibble) => step(move |vm, _| vm.drwvvn(x.into(), y.into(), nibble)),
+        SKPV(x) => step(move |vm, keypad| vm.skpv(x.into(), keypad)),
+        SKNPV(x) => step(move |vm, keypad| vm.sknpv(x.into(), keypad)),
+        // The address is the following word, read when the step runs
+        LDIL() => step(|vm, _| vm.ldil()),
+        PLANEN(nibble) => step(move |vm, _| vm.planen(nibble)),
+        AUDIO() => step(|vm, _| vm.audio()),
+        LDVDT(x) => step(move |vm, _| vm.ldvdt(x.into())),
+        LDVK(x) => step(move |vm, keypad| vm.ldvk(x.into(), keypad)),
+        LDDTV(x) => step(move |vm, _| vm.lddtv(x.into())),
+        LDSTV(x) => step(move |vm, _| vm.ldstv(x.into())),
+        ADDIV(x) => step(move |vm, _| vm.addiv(x.into())),
+        LDFV(x) => step(move |vm, _| vm.ldfv(x.into())),
+        LDHFV(x) => step(move |vm, _| vm.ldhfv(x.into())),
+        LDBV(x) => step(move |vm, _| vm.ldbv(x.into())),
+        LDPV(x) => step(move |vm, _| vm.ldpv(x.into())),
+        LDIV(x) => step(move |vm, _| vm.ldiv(x.into())),
+        LDVI(x) => step(move |vm, _| vm.ldvi(x.into())),
+        LDRV(x) => step(move |vm, _| vm.ldrv(x.into())),
+        LDVR(x) => step(move |vm, _| vm.ldvr(x.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        keypad::KeypadState,
+        opcode::OpCode,
+        quirks::Quirks,
+        vm::{Engine, VM},
+    };
+    use proptest::prelude::*;
+
+    #[test]
+    fn self_modifying_block_test() {
+        let rom = [
+            0x60, 0x07, // 0x200 LD V0, 0x07
+            0xA2, 0x09, // 0x202 LD I, 0x209
+            0xF0, 0x55, // 0x204 LD [I], V0
+            0x63, 0x01, // 0x206 LD V3, 0x01
+            0x62, 0x01, // 0x208 LD V2, 0x01, rewritten to LD V2, 0x07 by 0x204
+            0x12, 0x0A, // 0x20A JP 0x20A
+        ];
+        let keypad = KeypadState::new();
+        for is_cached in [false, true].iter() {
+            let mut vm = VM::with_seed(&rom, Quirks::default(), 0);
+            vm.set_instruction_cache(*is_cached);
+            // Compiles the whole run up to the jump, then has to drop it after the store
+            assert_eq!(vm.run(Engine::Blocks, &keypad, 8), 8);
+            assert_eq!(vm.get_registers()[0x2], 0x07);
+            assert_eq!(vm.get_registers()[0x3], 0x01);
+            assert_eq!(vm.get_program_counter(), 0x20A);
+        }
+    }
+
+    // Random words with the jump, call and index targets kept inside the program
+    // Most unknown words become ADDs or stores, so programs run for a while before they fault
+    // and often write over their own code
+    fn program() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(any::<u16>(), 1..48).prop_map(|words| {
+            let len = words.len() as u16 * 2;
+            words
+                .iter()
+                .flat_map(|word| {
+                    let word = match word >> 12 {
+                        0x1 | 0x2 | 0xA | 0xB => word & 0xF000 | (0x200 + (word & 0x0FFF) % len),
+                        _ if OpCode::from_bytes(*word).is_none() && word % 16 != 0 => {
+                            match word % 4 {
+                                0 => 0x7000 | word & 0x0FFF,
+                                1 => 0xF033 | word & 0x0F00,
+                                _ => 0xF055 | word & 0x0F00,
+                            }
+                        }
+                        _ => *word,
+                    };
+                    word.to_be_bytes().to_vec()
+                })
+                .collect()
+        })
+    }
+
+    fn quirks() -> impl Strategy<Value = Quirks> {
+        prop_oneof![
+            Just(Quirks::cosmac_vip()),
+            Just(Quirks::chip48()),
+            Just(Quirks::schip()),
+            Just(Quirks::xochip()),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn engine_proptest(
+            rom in program(),
+            quirks in quirks(),
+            seed in any::<u64>(),
+            is_cached in any::<bool>(),
+            chunks in prop::collection::vec((1u64..200, any::<u16>()), 1..20),
+        ) {
+            let mut interpreter = VM::with_seed(&rom, quirks, seed);
+            let mut compiled = VM::with_seed(&rom, quirks, seed);
+            interpreter.set_instruction_cache(is_cached);
+            compiled.set_instruction_cache(is_cached);
+
+            for (ticks, keys) in chunks {
+                let mut keypad = KeypadState::new();
+                for (key, state) in keypad.state.iter_mut().enumerate() {
+                    *state = keys & 1 << key != 0;
+                }
+                prop_assert_eq!(
+                    interpreter.run(Engine::Interpreter, &keypad, ticks),
+                    compiled.run(Engine::Blocks, &keypad, ticks)
+                );
+                prop_assert_eq!(interpreter.get_fault(), compiled.get_fault());
+                prop_assert_eq!(interpreter.save_state(), compiled.save_state());
+                interpreter.tick_timers();
+                compiled.tick_timers();
+            }
+        }
+    }
+}
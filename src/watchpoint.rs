@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchAction {
+    Pause,
+    Log,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    // Both ends are watched
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    // Only accesses of this value count, executes compare the first opcode byte
+    pub value: Option<u8>,
+    pub action: WatchAction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    // The instruction that made the access
+    pub pc: u16,
+    pub access: Access,
+    pub address: u16,
+    pub value: u8,
+    pub action: WatchAction,
+}
+
+// Checked by Memory on every guest access, hits are collected until a tool takes them
+pub struct Watchpoints {
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_id: usize,
+    pc: u16,
+    hits: Vec<WatchHit>,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::Read, Access::Read)
+                | (WatchKind::Write, Access::Write)
+                | (WatchKind::ReadWrite, Access::Read)
+                | (WatchKind::ReadWrite, Access::Write)
+                | (WatchKind::Execute, Access::Execute)
+        )
+    }
+}
+
+impl Watchpoint {
+    fn matches(&self, access: Access, address: u16, value: u8) -> bool {
+        (self.start..=self.end).contains(&address)
+            && self.kind.matches(access)
+            && (self.value.is_none() || self.value == Some(value))
+    }
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Self {
+            watchpoints: BTreeMap::new(),
+            next_id: 1,
+            pc: 0,
+            hits: Vec::new(),
+        }
+    }
+
+    // Ids count up from 1 and aren't reused
+    pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoints.insert(id, watchpoint);
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Watchpoint> {
+        self.watchpoints.remove(&id)
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints
+            .iter()
+            .map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+
+    // Set by the VM before each instruction, so hits can name it
+    pub(crate) fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub(crate) fn check(&mut self, access: Access, address: usize, value: u8) {
+        let address = address as u16;
+        for (id, watchpoint) in self.watchpoints.iter() {
+            if watchpoint.matches(access, address, value) {
+                self.hits.push(WatchHit {
+                    id: *id,
+                    pc: self.pc,
+                    access,
+                    address,
+                    value,
+                    action: watchpoint.action,
+                });
+            }
+        }
+    }
+}
+
+impl Default for Watchpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::ReadWrite => "rw",
+            WatchKind::Execute => "exec",
+        };
+        write!(f, "{:#06X}-{:#06X} {}", self.start, self.end, kind)?;
+        if let Some(value) = self.value {
+            write!(f, " if {:#04X}", value)?;
+        }
+        if self.action == WatchAction::Log {
+            write!(f, " log")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let access = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "exec",
+        };
+        write!(
+            f,
+            "Watchpoint {}: {} {:#06X} = {:#04X} by PC {:#06X}",
+            self.id, access, self.address, self.value, self.pc
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, WatchAction, WatchHit, WatchKind, Watchpoint};
+    use crate::{keypad::KeypadState, quirks::Quirks, vm::VM};
+
+    fn watchpoint(start: u16, end: u16, kind: WatchKind, value: Option<u8>) -> Watchpoint {
+        Watchpoint {
+            start,
+            end,
+            kind,
+            value,
+            action: WatchAction::Log,
+        }
+    }
+
+    #[test]
+    fn access_test() {
+        let rom = [
+            0xA3, 0x00, // 0x200 LD I, 0x300
+            0x60, 0x05, // 0x202 LD V0, 0x05
+            0xF0, 0x55, // 0x204 LD [I], V0
+            0xF1, 0x65, // 0x206 LD V1, [I]
+            0x12, 0x08, // 0x208 JP 0x208
+        ];
+        let mut vm = VM::with_seed(&rom, Quirks::default(), 0);
+        let keypad = KeypadState::new();
+        let write = vm
+            .watchpoints_mut()
+            .add(watchpoint(0x300, 0x300, WatchKind::Write, None));
+        let read = vm
+            .watchpoints_mut()
+            .add(watchpoint(0x300, 0x301, WatchKind::Read, Some(0x05)));
+        let exec = vm
+            .watchpoints_mut()
+            .add(watchpoint(0x208, 0x208, WatchKind::Execute, None));
+
+        let mut hits = Vec::new();
+        for _ in 0..5 {
+            vm.tick(&keypad).expect("Valid rom");
+            hits.extend(vm.watchpoints_mut().take_hits());
+        }
+        let hit = |id, pc, access, address, value| WatchHit {
+            id,
+            pc,
+            access,
+            address,
+            value,
+            action: WatchAction::Log,
+        };
+        // V1 reads back 0x301 as well, which doesn't match the value
+        assert_eq!(
+            hits,
+            vec![
+                hit(write, 0x204, Access::Write, 0x300, 0x05),
+                hit(read, 0x206, Access::Read, 0x300, 0x05),
+                hit(exec, 0x208, Access::Execute, 0x208, 0x12),
+            ]
+        );
+
+        // Tools aren't watched, removed watchpoints stop matching
+        vm.write_memory(0x300, 0x05);
+        assert_eq!(vm.read_memory(0x300), 0x05);
+        assert!(vm.watchpoints_mut().take_hits().is_empty());
+        assert!(vm.watchpoints_mut().remove(exec).is_some());
+        vm.tick(&keypad).expect("Valid rom");
+        assert!(vm.watchpoints_mut().take_hits().is_empty());
+    }
+}